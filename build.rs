@@ -0,0 +1,9 @@
+// compiles the protos of the services which are served by the state keeper only.
+// They use the shared messages of orchestra, imported from the orchestra submodule
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tonic_build::configure()
+        .extern_path(".rollup", "::orchestra::rpc::rollup")
+        .protoc_arg("--experimental_allow_proto3_optional")
+        .compile(&["proto/prover.proto", "proto/explorer.proto"], &["proto", "orchestra/proto"])?;
+    Ok(())
+}
@@ -9,12 +9,22 @@ const load = {
   oneofs: true
 };
 
+// the services of the state keeper only, using the messages of rollup.proto
+const explorerFile = "../../proto/explorer.proto";
+const explorerLoad = { ...load, includeDirs: ["../../orchestra/proto"] };
+
 class Client {
   client: any;
+  explorer: any;
 
   constructor(server = process.env.GRPC_ADDR || "127.0.0.1:50061") {
     console.log("using GRPC", server);
     this.client = caller(`${server}`, { file, load }, "RollupState");
+    this.explorer = caller(
+      `${server}`,
+      { file: explorerFile, load: explorerLoad },
+      "Explorer"
+    );
   }
 
   async l2BlockQuery(block_id): Promise<Map<string, any>> {
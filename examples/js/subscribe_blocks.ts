@@ -0,0 +1,18 @@
+import { grpcClient } from "./grpc_client";
+
+// prints every block from `FROM_BLOCK_ID` (or the next saved one) on, as it is saved
+async function main() {
+  const request = { with_txs: false };
+  if (process.env.FROM_BLOCK_ID) {
+    request["from_block_id"] = process.env.FROM_BLOCK_ID;
+  }
+  const call = grpcClient.explorer.SubscribeBlocks(request);
+  call.on("data", update => console.log(update["block"]));
+  call.on("error", error => {
+    console.error("Caught error:", error);
+    process.exit(1);
+  });
+  call.on("end", () => console.log("subscription ended"));
+}
+
+main();
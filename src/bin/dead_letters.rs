@@ -0,0 +1,85 @@
+// Inspect and re-inject dead letters, see `rollup_state_manager::msg::dead_letter`.
+// usage:
+//   dead_letters list <dead_letters.jsonl>
+//   dead_letters reinject <dead_letters.jsonl> <topic> [index...]
+// `list` prints every dead letter with its index. `reinject` produces the chosen dead letters (all by default)
+// to a kafka topic with their original key and payload, which may have been fixed in the file by hand.
+// The topic must be one of `kafka.topics` in settings to be consumed again.
+use anyhow::{anyhow, bail, Result};
+use fluidex_common::rdkafka;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rollup_state_manager::config::Settings;
+use rollup_state_manager::msg::dead_letter::{DeadLetter, DeadLetterStore};
+use rollup_state_manager::msg::msg_loader::parse_keyed_msg;
+use rollup_state_manager::types::matchengine::messages::{MsgOffset, TopicPartition};
+use std::env;
+use std::time::Duration;
+
+const USAGE: &str = "usage: dead_letters list <file> | dead_letters reinject <file> <topic> [index...]";
+
+fn list(letters: &[DeadLetter]) {
+    for (idx, letter) in letters.iter().enumerate() {
+        let offset = letter
+            .offset
+            .as_ref()
+            .map_or_else(|| "-".to_string(), |o| format!("{}:{}:{}", o.tp.topic, o.tp.partition, o.offset));
+        println!("#{} {:?} {} {}: {}", idx, letter.stage, offset, letter.key, letter.error);
+        println!("    {}", letter.payload);
+    }
+}
+
+async fn reinject(letters: Vec<DeadLetter>, topic: &str, indexes: Vec<usize>) -> Result<()> {
+    let chosen: Vec<(usize, DeadLetter)> = if indexes.is_empty() {
+        letters.into_iter().enumerate().collect()
+    } else {
+        let mut chosen = Vec::new();
+        for idx in indexes {
+            let letter = letters.get(idx).ok_or_else(|| anyhow!("no dead letter #{}", idx))?;
+            chosen.push((idx, letter.clone()));
+        }
+        chosen
+    };
+    // refuse to send anything if a msg is still broken
+    let any_offset = MsgOffset {
+        tp: TopicPartition {
+            topic: topic.to_string(),
+            partition: 0,
+        },
+        offset: 0,
+    };
+    for (idx, letter) in &chosen {
+        if let Err(e) = parse_keyed_msg(&letter.key, &letter.payload, any_offset.clone()) {
+            bail!("dead letter #{} is still invalid: {:#}", idx, e);
+        }
+    }
+
+    let producer: FutureProducer = rdkafka::config::ClientConfig::new()
+        .set("bootstrap.servers", Settings::brokers())
+        .set("message.timeout.ms", "5000")
+        .create()?;
+    for (idx, letter) in &chosen {
+        let record = FutureRecord::to(topic).key(&letter.key).payload(&letter.payload);
+        producer
+            .send(record, Duration::from_secs(0))
+            .await
+            .map_err(|(e, _)| anyhow!("failed to reinject #{}: {}", idx, e))?;
+        println!("reinjected #{}", idx);
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["list", path] => list(&DeadLetterStore::read(path)?),
+        ["reinject", path, topic, indexes @ ..] => {
+            Settings::init_default();
+            let indexes = indexes.iter().map(|idx| idx.parse()).collect::<Result<Vec<usize>, _>>()?;
+            reinject(DeadLetterStore::read(path)?, topic, indexes).await?;
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
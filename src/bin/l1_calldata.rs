@@ -0,0 +1,60 @@
+// Print the ABI-encoded L1 calldata of a block saved in db.
+// usage: l1_calldata <block_id> [proof.json]
+// where proof.json is a json array of the serialized proof in decimal strings.
+// verifyBlock calldata is only printed when the proof is given.
+use anyhow::{anyhow, Context, Result};
+use ethers::core::types::U256;
+use fluidex_common::db::models::tablenames;
+use rollup_state_manager::config::Settings;
+use rollup_state_manager::l1::calldata::{CommitBlockCall, VerifyBlockCall};
+use rollup_state_manager::storage;
+use rollup_state_manager::types::l2::PubDataAux;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::{env, fs};
+
+fn read_proof(path: &str) -> Result<Vec<U256>> {
+    let content = fs::read_to_string(path).with_context(|| format!("failed to read proof {}", path))?;
+    let proof: Vec<String> = serde_json::from_str(&content)?;
+    proof
+        .iter()
+        .map(|p| U256::from_dec_str(p).map_err(|e| anyhow!("invalid proof element {}: {:?}", p, e)))
+        .collect()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    Settings::init_default();
+
+    let mut args = env::args().skip(1);
+    let block_id: u32 = args
+        .next()
+        .ok_or_else(|| anyhow!("usage: l1_calldata <block_id> [proof.json]"))?
+        .parse()?;
+    let proof = args.next().map(|path| read_proof(&path)).transpose()?;
+
+    let db_pool = PgPool::connect(Settings::db()).await?;
+    let row = sqlx::query(&format!(
+        "select raw_public_data, public_data_aux from {} where block_id = $1",
+        tablenames::L2_BLOCK
+    ))
+    .bind(block_id as i64)
+    .fetch_one(&db_pool)
+    .await
+    .with_context(|| format!("block {} not found", block_id))?;
+
+    let detail = storage::load_block_detail(&db_pool, block_id as i64)
+        .await?
+        .ok_or_else(|| anyhow!("block {} not found", block_id))?;
+    let public_data: Vec<u8> = row.try_get("raw_public_data")?;
+    let aux: Option<sqlx::types::Json<PubDataAux>> = row.try_get("public_data_aux")?;
+    let aux = aux.ok_or_else(|| anyhow!("block {} is saved without public_data_aux", block_id))?;
+
+    let commit = CommitBlockCall::from_stored(block_id, &detail, public_data, &aux.0);
+    println!("commitBlock: 0x{}", hex::encode(commit.encode()));
+    if let Some(proof) = proof {
+        println!("verifyBlock: 0x{}", hex::encode(VerifyBlockCall::new(&commit, proof).encode()));
+    }
+    Ok(())
+}
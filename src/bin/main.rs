@@ -3,25 +3,41 @@
 
 use crossbeam_channel::RecvTimeoutError;
 use fluidex_common::db::models::tablenames;
-use fluidex_common::db::models::task::TaskStatus;
 use fluidex_common::db::MIGRATOR;
 use fluidex_common::non_blocking_tracing;
 use fluidex_common::types::FrExt;
-use rollup_state_manager::config::Settings;
-use rollup_state_manager::grpc::run_grpc_server;
-use rollup_state_manager::msg::{msg_loader, msg_processor};
+use rollup_state_manager::config::{L1DepositSettings, L1WatcherSettings, MsgSourceSettings, PendingBlockPolicy, Settings};
+use rollup_state_manager::grpc::{run_grpc_server, run_rest_server, BlockNotifier};
+use rollup_state_manager::l1::block_status::{self, BlockStatusWatcher};
+use rollup_state_manager::l1::deposit::{self, source_from_settings, DepositEvent, DepositScheduler, DepositWatcher};
+use rollup_state_manager::metrics::{self, run_metrics_server};
+use rollup_state_manager::msg::dead_letter::{DeadLetterGuard, DeadLetterStore};
+use rollup_state_manager::msg::pipeline::PipelineStats;
+use rollup_state_manager::msg::{msg_processor, msg_source};
 use rollup_state_manager::params;
 #[cfg(feature = "persist_sled")]
 use rollup_state_manager::r#const::sled_db::*;
+use rollup_state_manager::shutdown::{Shutdown, ShutdownCoordinator};
+use rollup_state_manager::state::manager_wrapper::SealedBlock;
+use rollup_state_manager::state::sealing::{self, BlockSealer};
 use rollup_state_manager::state::{GlobalState, ManagerWrapper};
+use rollup_state_manager::storage;
 use rollup_state_manager::test_utils::messages::WrappedMessage;
 use rollup_state_manager::types::l2::{L2Block, L2BlockSerde};
+use rollup_state_manager::types::matchengine::messages::PartitionOffsets;
 use sqlx::postgres::PgPool;
 use sqlx::Row;
+use std::collections::BTreeSet;
 use std::option::Option::None;
 use std::sync::{Arc, RwLock};
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
-use std::{fs, io};
+use std::time::{Duration, Instant};
+use std::{env, fs, io};
+
+// how long to wait for new msgs when no sealing policy has a deadline
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// how long received L1 deposits may wait for the next msg
+const DEPOSIT_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_L1_POLL_SECS: u64 = 5;
 
 #[tokio::main]
 async fn main() {
@@ -35,28 +51,72 @@ async fn main() {
     run().await;
 }
 
-fn grpc_run(state: Arc<RwLock<GlobalState>>) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+fn grpc_run(
+    state: Arc<RwLock<GlobalState>>,
+    notifier: BlockNotifier,
+    shutdown: Shutdown,
+) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     Some(std::thread::spawn(move || {
         let addr = Settings::grpc_addr().parse()?;
-        run_grpc_server(addr, state)
+        run_grpc_server(addr, state, notifier, shutdown)
     }))
 }
 
+fn rest_run(state: Arc<RwLock<GlobalState>>, shutdown: Shutdown) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    Settings::rest_addr().map(|addr| {
+        std::thread::spawn(move || {
+            let addr = addr.parse()?;
+            run_rest_server(addr, state, shutdown)
+        })
+    })
+}
+
 fn process_msgs(
     msg_receiver: crossbeam_channel::Receiver<WrappedMessage>,
-    block_sender: crossbeam_channel::Sender<L2Block>,
+    block_sender: crossbeam_channel::Sender<SealedBlock>,
     state: Arc<RwLock<GlobalState>>,
     block_offset: Option<usize>,
+    kafka_offsets: PartitionOffsets,
+    quarantined: BTreeSet<u32>,
+    saved_block_num: usize,
+    dead_letters: DeadLetterStore,
+    deposits: Option<DepositScheduler>,
 ) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
     Some(std::thread::spawn(move || {
-        let manager = ManagerWrapper::new(state, *params::NTXS, block_offset, *params::VERBOSE);
+        let mut manager = ManagerWrapper::new(state, *params::NTXS, block_offset, *params::VERBOSE);
+        manager.set_consumed_offsets(kafka_offsets);
+        manager.set_quarantined(quarantined);
         // TODO: change to to_hex_string, remove 'Fr(' and ')'
         log::info!("genesis root {}", manager.root().to_string());
 
-        run_msg_processor(msg_receiver, block_sender, manager)
+        let guard = DeadLetterGuard::new(Settings::dead_letter().policy, dead_letters);
+        run_msg_processor(msg_receiver, block_sender, manager, saved_block_num, guard, deposits)
+    }))
+}
+
+// builds the witnesses of sealed blocks off the processor thread, ends after the processor has sent its last block
+fn witness_run(
+    sealed_receiver: crossbeam_channel::Receiver<SealedBlock>,
+    block_sender: crossbeam_channel::Sender<L2Block>,
+) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    Some(std::thread::spawn(move || {
+        for block in sealed_receiver.iter() {
+            block_sender.send(block.build()).expect("block receiver dropped");
+        }
+        Ok(())
     }))
 }
 
+fn metrics_run(shutdown: Shutdown) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
+    let settings = Settings::metrics();
+    settings.addr.as_ref().map(|addr| {
+        std::thread::spawn(move || {
+            let addr = addr.parse()?;
+            run_metrics_server(addr, settings, shutdown)
+        })
+    })
+}
+
 async fn run() {
     let state = Arc::new(RwLock::new(GlobalState::new(
         *params::BALANCELEVELS,
@@ -65,32 +125,196 @@ async fn run() {
         *params::VERBOSE,
     )));
 
-    let (block_offset, kafka_offset) = get_persistent_offsets(Arc::clone(&state));
-
-    let (msg_sender, msg_receiver) = crossbeam_channel::unbounded();
-    let (blk_sender, blk_receiver) = crossbeam_channel::unbounded();
-
-    let loader_thread = msg_loader::load_msgs_from_mq(Settings::brokers(), kafka_offset, msg_sender);
-    let replay_thread = process_msgs(msg_receiver, blk_sender, Arc::clone(&state), block_offset);
-    let server_thread = grpc_run(state);
-
     let db_pool = PgPool::connect(Settings::db()).await.unwrap();
     MIGRATOR.run(&db_pool).await.ok();
+    storage::migrate(&db_pool).await.unwrap();
+    // in the background, the history of an account is partial until it is done
+    tokio::spawn({
+        let db_pool = db_pool.clone();
+        async move {
+            match storage::l2_tx::backfill(&db_pool).await {
+                Ok(0) => {}
+                Ok(indexed) => log::info!("l2_tx backfill is done, {} blocks indexed", indexed),
+                Err(e) => log::error!("l2_tx backfill failed: {:?}", e),
+            }
+        }
+    });
+
+    let progress = storage::load_progress(&db_pool).await.unwrap();
+    log::info!(
+        "saved blocks end at #{}, offsets {:?}",
+        progress.next_block_id,
+        progress.kafka_offsets
+    );
+    // a snapshot newer than the saved blocks would skip the unsaved ones
+    let (block_offset, mut kafka_offsets, quarantined) = get_persistent_offsets(Arc::clone(&state), progress.next_block_id);
+    if block_offset == Some(progress.next_block_id) {
+        // same point, but the offsets in db are committed with the blocks
+        kafka_offsets = progress.kafka_offsets;
+    }
 
+    let coordinator = ShutdownCoordinator::default();
+    tokio::spawn(coordinator.clone().watch_signal(Settings::shutdown()));
+
+    // bounded, so a slow stage holds back the ones before it
+    let (msg_sender, msg_receiver) = crossbeam_channel::bounded(Settings::pipeline().msg_capacity);
+    let (sealed_sender, sealed_receiver) = crossbeam_channel::bounded(Settings::pipeline().block_capacity);
+    let (blk_sender, blk_receiver) = crossbeam_channel::bounded(Settings::pipeline().block_capacity);
+
+    // L1 deposits resume after the consumed position, like kafka msgs
+    let deposit_cursor = kafka_offsets.get(&deposit::topic_partition()).copied();
+    let (deposit_sender, deposit_receiver) = crossbeam_channel::bounded(Settings::pipeline().msg_capacity);
+    let deposits = match Settings::l1_deposit() {
+        Some(_) => {
+            let saved_cursors = storage::load_l1_deposit_cursors(&db_pool, block_offset.unwrap_or(0)).await.unwrap();
+            Some(DepositScheduler::new(
+                deposit_receiver,
+                saved_cursors,
+                progress.next_block_id,
+                deposit_cursor,
+            ))
+        }
+        None => None,
+    };
+    let deposit_thread =
+        Settings::l1_deposit().map(|settings| watch_l1_deposits(settings, deposit_sender, deposit_cursor, coordinator.intake.clone()));
+    // `--source` overrides `msg_source` in settings
+    let source_settings = env::args()
+        .skip_while(|arg| arg != "--source")
+        .nth(1)
+        .map(|arg| MsgSourceSettings::from_arg(&arg))
+        .unwrap_or_else(|| Settings::msg_source().clone());
+    log::info!("msg source {:?}", source_settings);
+    let dead_letters = DeadLetterStore::from_settings(Settings::dead_letter()).unwrap();
+    let source = msg_source::from_settings(&source_settings, kafka_offsets.clone(), dead_letters.clone()).expect("invalid msg source");
+    let loader_thread = Some(msg_source::spawn(source, msg_sender, coordinator.intake.clone()));
+    let replay_thread = process_msgs(
+        msg_receiver,
+        sealed_sender,
+        Arc::clone(&state),
+        block_offset,
+        kafka_offsets,
+        quarantined,
+        progress.next_block_id,
+        dead_letters,
+        deposits,
+    );
+    let witness_thread = witness_run(sealed_receiver, blk_sender);
+    // pushes saved blocks to `SubscribeBlocks` clients
+    let notifier = BlockNotifier::default();
+    let rest_thread = rest_run(Arc::clone(&state), coordinator.server.clone());
+    let server_thread = grpc_run(state, notifier.clone(), coordinator.server.clone());
+    let metrics_thread = metrics_run(coordinator.server.clone());
+    let l1_watcher_thread = Settings::l1_watcher().map(|settings| watch_l1_blocks(settings, coordinator.server.clone()));
+
+    // ends after the processor has sent its last block
     for block in blk_receiver.iter() {
-        save_block_to_db(&db_pool, &block).await.unwrap();
-        save_task_to_db(&db_pool, block).await.unwrap();
+        storage::save_block(&db_pool, &block).await.unwrap();
+        PipelineStats::get().add_saved_block();
+        notifier.notify(block.block_id);
+    }
+    log::info!("all blocks are saved");
+    if !coordinator.intake.is_triggered() {
+        log::info!("msg source is exhausted, serving grpc until Ctrl-C");
+        coordinator.intake.wait().await;
     }
+    coordinator.server.trigger();
 
     loader_thread.map(|h| h.join().expect("loader thread failed"));
     replay_thread.map(|h| h.join().expect("loader thread failed"));
+    witness_thread.map(|h| h.join().expect("witness thread failed"));
     server_thread.map(|h| h.join().expect("loader thread failed"));
+    rest_thread.map(|h| h.join().expect("rest thread failed"));
+    deposit_thread.map(|h| h.join().expect("deposit thread failed"));
+    metrics_thread.map(|h| h.join().expect("metrics thread failed"));
+    l1_watcher_thread.map(|h| h.join().expect("l1 watcher thread failed"));
+}
+
+fn watch_l1_deposits(
+    settings: &'static L1DepositSettings,
+    deposit_sender: crossbeam_channel::Sender<DepositEvent>,
+    cursor: Option<i64>,
+    shutdown: Shutdown,
+) -> std::thread::JoinHandle<anyhow::Result<()>> {
+    std::thread::spawn(move || {
+        let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Build runtime");
+        rt.block_on(async {
+            let mut watcher = DepositWatcher::new(source_from_settings(settings)?, settings.confirmations, settings.from_block, cursor);
+            let interval = Duration::from_secs(settings.poll_interval_secs.unwrap_or(DEFAULT_L1_POLL_SECS));
+            loop {
+                match watcher.poll().await {
+                    Ok(deposits) => {
+                        for deposit in deposits {
+                            if deposit_sender.send(deposit).is_err() {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Err(e) => log::error!("poll l1 deposits failed: {:?}", e),
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    })
+}
+
+// keeps the status of saved blocks in sync with L1, until the grpc server stops
+fn watch_l1_blocks(settings: &'static L1WatcherSettings, shutdown: Shutdown) -> std::thread::JoinHandle<anyhow::Result<()>> {
+    std::thread::spawn(move || {
+        let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Build runtime");
+        rt.block_on(async {
+            let db_pool = PgPool::connect(Settings::db()).await?;
+            let latest_verified = block_status::load_latest_verified(&db_pool).await?;
+            let scanned = block_status::load_scanned(&db_pool).await?;
+            let mut watcher = BlockStatusWatcher::new(block_status::source_from_settings(settings)?, settings, latest_verified, scanned);
+            let interval = Duration::from_secs(settings.poll_interval_secs.unwrap_or(DEFAULT_L1_POLL_SECS));
+            // updates failed to save are saved with the next ones, before the scanned blocks move past them
+            let mut pending = Vec::new();
+            loop {
+                let mut saved = false;
+                match watcher.poll().await {
+                    Ok(updates) => {
+                        pending.extend(updates);
+                        match block_status::apply_updates(&db_pool, &pending, watcher.scanned()).await {
+                            Ok(()) => {
+                                pending.clear();
+                                saved = true;
+                            }
+                            Err(e) => log::error!("save l1 block status failed: {:?}", e),
+                        }
+                        metrics::L1_VERIFIED_BLOCK.set(watcher.latest_verified().unwrap_or(-1));
+                    }
+                    Err(e) => log::error!("poll l1 blocks failed: {:?}", e),
+                }
+                // scan the next chunk right away, but not on errors
+                if saved && watcher.is_behind() && !shutdown.is_triggered() {
+                    continue;
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = shutdown.wait() => return Ok(()),
+                }
+            }
+        })
+    })
 }
 
 fn run_msg_processor(
     msg_receiver: crossbeam_channel::Receiver<WrappedMessage>,
-    block_sender: crossbeam_channel::Sender<L2Block>,
+    block_sender: crossbeam_channel::Sender<SealedBlock>,
     mut manager: ManagerWrapper,
+    saved_block_num: usize,
+    mut guard: DeadLetterGuard,
+    mut deposits: Option<DepositScheduler>,
 ) -> anyhow::Result<()> {
     let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -99,61 +323,44 @@ fn run_msg_processor(
 
     rt.block_on(async {
         let mut processor = msg_processor::Processor::default();
+        processor.enable_place_order = Settings::place_order_tx();
+        processor.enable_full_exit = Settings::full_exit_tx();
+        processor.enable_l1_deposit = Settings::l1_deposit().is_some();
+        processor.l1_token_decimals = Settings::l1_deposit().map(|s| s.token_decimals.clone()).unwrap_or_default();
 
         let timing = Instant::now();
         let db_pool = PgPool::connect(Settings::db()).await.unwrap();
-        let mut old_block_check = true;
+        let mut sealer = BlockSealer::new(
+            sealing::AnyOf::from_settings(Settings::sealing()).expect("invalid sealing settings"),
+            Instant::now(),
+        );
         let mut old_block_num = 0;
+        // until the msg sources are stopped and all sent msgs are handled
         loop {
-            // In the worst case we wait for about 119 seconds timeout until we try to
-            // generate a block, if there's any tx.
-            // TODO: dynamic timeout
-            match msg_receiver.recv_timeout(Duration::from_secs(120)) {
-                Ok(msg) => {
-                    log::debug!("recv new msg {:?}", msg);
-                    match msg {
-                        WrappedMessage::DEPOSIT(deposit) => {
-                            processor.handle_deposit_msg(&mut manager, deposit);
-                        }
-                        WrappedMessage::ORDER(order) => {
-                            processor.handle_order_msg(&mut manager, order);
-                        }
-                        WrappedMessage::TRADE(trade) => {
-                            processor.handle_trade_msg(&mut manager, trade);
-                        }
-                        WrappedMessage::TRANSFER(transfer) => {
-                            processor.handle_transfer_msg(&mut manager, transfer);
-                        }
-                        WrappedMessage::USER(user) => {
-                            processor.handle_user_msg(&mut manager, user);
-                        }
-                        WrappedMessage::WITHDRAW(withdraw) => {
-                            processor.handle_withdraw_msg(&mut manager, withdraw);
-                        }
-                    }
-                }
+            // wait for new msgs until the sealing policy may want to seal the pending block
+            let mut timeout = sealer.timeout(&manager, Instant::now()).unwrap_or(IDLE_TIMEOUT);
+            if deposits.is_some() {
+                timeout = timeout.min(DEPOSIT_TIMEOUT);
+            }
+            let msg = match msg_receiver.recv_timeout(timeout) {
+                Ok(msg) => Some(msg),
                 Err(err) => match err {
-                    RecvTimeoutError::Timeout => {
-                        if manager.has_raw_tx() {
-                            manager.flush_with_nop();
-                        }
-                    }
+                    RecvTimeoutError::Timeout => None,
                     RecvTimeoutError::Disconnected => break,
                 },
             };
-
-            for block in manager.pop_all_blocks() {
-                if old_block_check && is_present_block(&db_pool, &block).await.unwrap() {
-                    // Skips this old block.
-                    old_block_num += 1;
-                    continue;
-                }
-
-                // Once the block is a new one, no need to check if old.
-                old_block_check = false;
-
-                block_sender.try_send(block).unwrap();
+            if let Some(deposits) = deposits.as_mut() {
+                apply_l1_deposits(deposits, &mut processor, &mut manager, &mut guard)?;
+            }
+            if let Some(msg) = msg {
+                log::debug!("recv new msg {:?}", msg);
+                guard.handle(&mut processor, &mut manager, msg);
             }
+            sealer.poll(&mut manager, Instant::now());
+
+            old_block_num += send_blocks(&mut manager, &db_pool, saved_block_num, &block_sender).await;
+            PipelineStats::get().set_msg_queue(msg_receiver.len());
+            PipelineStats::get().set_consumed(manager.consumed_offsets());
 
             let block_num = manager.get_block_generate_num() - old_block_num;
             let secs = timing.elapsed().as_secs_f32();
@@ -166,12 +373,72 @@ fn run_msg_processor(
             );
         }
 
+        let pending_tx_num = manager.pending_tx_types().len();
+        match Settings::shutdown().pending_block {
+            PendingBlockPolicy::Seal if pending_tx_num > 0 => {
+                log::info!("seal the pending block with {} txs", pending_tx_num);
+                manager.seal_block();
+                send_blocks(&mut manager, &db_pool, saved_block_num, &block_sender).await;
+            }
+            PendingBlockPolicy::Seal => {}
+            PendingBlockPolicy::Discard => log::info!("discard the pending block with {} txs", pending_tx_num),
+        }
+        #[cfg(feature = "persist_sled")]
+        if Settings::shutdown().final_snapshot && manager.pending_tx_types().is_empty() {
+            manager.snapshot();
+        }
+        log::info!("msg processor stopped at block {}", manager.get_block_generate_num());
+
         Ok(())
     })
 }
 
+// L1 deposits only go at the start of a block, see `DepositScheduler`
+fn apply_l1_deposits(
+    deposits: &mut DepositScheduler,
+    processor: &mut msg_processor::Processor,
+    manager: &mut ManagerWrapper,
+    guard: &mut DeadLetterGuard,
+) -> anyhow::Result<()> {
+    // a saved block may be filled up by deposits, and the next one start with more
+    while manager.pending_tx_types().is_empty() {
+        let events = deposits.take(manager.sealed_block_num())?;
+        if events.is_empty() {
+            break;
+        }
+        for event in events {
+            guard.handle(processor, manager, WrappedMessage::L1DEPOSIT(event));
+        }
+    }
+    Ok(())
+}
+
+// sends the sealed blocks to the witness builder, returns the number of blocks skipped since they are saved already
+async fn send_blocks(
+    manager: &mut ManagerWrapper,
+    db_pool: &PgPool,
+    saved_block_num: usize,
+    block_sender: &crossbeam_channel::Sender<SealedBlock>,
+) -> usize {
+    let mut old_block_num = 0;
+    for block in manager.pop_sealed_blocks() {
+        // blocks replayed from an older snapshot are saved already, only check they are the same
+        if block.block_id < saved_block_num {
+            let block_id = block.block_id;
+            assert!(is_present_block(db_pool, block).await.unwrap(), "missing saved block {}", block_id);
+            old_block_num += 1;
+            continue;
+        }
+
+        // blocks when the db writer falls behind, and so the msg channel fills up
+        block_sender.send(block).expect("block receiver dropped");
+        PipelineStats::get().add_sent_block();
+    }
+    old_block_num
+}
+
 // Returns true if already present in DB, otherwise false.
-async fn is_present_block(pool: &PgPool, block: &L2Block) -> anyhow::Result<bool> {
+async fn is_present_block(pool: &PgPool, block: SealedBlock) -> anyhow::Result<bool> {
     match sqlx::query(&format!("select new_root from {} where block_id = $1", tablenames::L2_BLOCK))
         .bind(block.block_id as u32)
         .fetch_one(pool)
@@ -179,18 +446,19 @@ async fn is_present_block(pool: &PgPool, block: &L2Block) -> anyhow::Result<bool
     {
         Ok(row) => {
             let new_root: String = row.get(0);
-            let old_root: String = block.detail.new_root.to_hex_string();
+            let old_root: String = block.new_root().to_hex_string();
             if new_root == old_root {
                 log::debug!("skip same l2 block {} {}", block.block_id, new_root);
             } else {
+                let block_id = block.block_id;
                 log::error!(
                     "new block {}",
-                    serde_json::to_string_pretty(&L2BlockSerde::from(block.detail.clone())).unwrap()
+                    serde_json::to_string_pretty(&L2BlockSerde::from(block.build().detail)).unwrap()
                 );
                 assert_eq!(
                     new_root, old_root,
                     "l2 block generation must be deterministic! Error for block {}",
-                    block.block_id
+                    block_id
                 );
             }
 
@@ -201,49 +469,8 @@ async fn is_present_block(pool: &PgPool, block: &L2Block) -> anyhow::Result<bool
     }
 }
 
-async fn save_block_to_db(pool: &PgPool, block: &L2Block) -> anyhow::Result<()> {
-    let new_root = block.detail.new_root.to_hex_string();
-    let detail = L2BlockSerde::from(block.detail.clone());
-    sqlx::query(&format!(
-        "insert into {} (block_id, new_root, detail, raw_public_data) values ($1, $2, $3, $4)",
-        tablenames::L2_BLOCK
-    ))
-    .bind(block.block_id as u32)
-    .bind(new_root)
-    .bind(sqlx::types::Json(detail))
-    .bind(&block.public_data)
-    .execute(pool)
-    .await?;
-
-    Ok(())
-}
-
-async fn save_task_to_db(pool: &PgPool, block: L2Block) -> anyhow::Result<()> {
-    let tx_num = block.detail.encoded_txs.len();
-    let input = L2BlockSerde::from(block.detail);
-    let task_id = unique_task_id();
-
-    sqlx::query(&format!(
-        "insert into {} (task_id, circuit, block_id, input, status) values ($1, $2, $3, $4, $5)",
-        tablenames::TASK
-    ))
-    .bind(task_id)
-    .bind(format!("block_{}", tx_num))
-    .bind(block.block_id as i64) // TODO: will it overflow?
-    .bind(sqlx::types::Json(input))
-    .bind(TaskStatus::Inited)
-    .execute(pool)
-    .await?;
-
-    Ok(())
-}
-
-fn unique_task_id() -> String {
-    let current_millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-    format!("task_{}", current_millis)
-}
-
-fn get_latest_dump() -> anyhow::Result<Option<usize>> {
+// dumps are named after the number of blocks generated before them, take the latest one within `max_block_num`
+fn get_latest_dump(max_block_num: usize) -> anyhow::Result<Option<usize>> {
     let mut dumps = std::fs::read_dir(Settings::persist_dir())?
         .map(|entry| entry.and_then(|e| e.metadata().map(|meta| (meta, e.file_name().into_string().unwrap()))))
         .collect::<io::Result<Vec<(fs::Metadata, String)>>>()?
@@ -251,6 +478,7 @@ fn get_latest_dump() -> anyhow::Result<Option<usize>> {
         .filter_map(|(meta, name)| if meta.is_dir() && name.ends_with(".db") { Some(name) } else { None })
         .map(|path| path.strip_suffix(".db").unwrap().parse::<usize>())
         .collect::<Result<Vec<usize>, _>>()?;
+    dumps.retain(|&id| id <= max_block_num);
     dumps.sort_unstable();
     Ok(dumps.last().copied())
 }
@@ -264,25 +492,51 @@ fn get_block_offset(db: &Option<sled::Db>, state: Arc<RwLock<GlobalState>>) -> O
 }
 
 #[cfg(feature = "persist_sled")]
-fn get_kafka_offset(db: &Option<sled::Db>) -> Option<i64> {
+fn get_kafka_offsets(db: &Option<sled::Db>) -> PartitionOffsets {
+    let db = match db {
+        Some(db) => db,
+        None => return PartitionOffsets::new(),
+    };
+    if let Some(offsets) = db.get(KAFKA_OFFSETS_KEY).ok().flatten().and_then(|v| bincode::deserialize(&v).ok()) {
+        return offsets;
+    }
+    // old dumps only have the offset of the first partition
+    let old_offset: Option<i64> = db.get(KAFKA_OFFSET_KEY).ok().flatten().and_then(|v| bincode::deserialize(&v).ok());
+    old_offset
+        .map(|offset| {
+            Settings::kafka()
+                .topic_partitions()
+                .into_iter()
+                .take(1)
+                .map(|tp| (tp, offset))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// dumps before quarantined accounts were persisted have none
+#[cfg(feature = "persist_sled")]
+fn get_quarantined(db: &Option<sled::Db>) -> BTreeSet<u32> {
     db.as_ref()
-        .and_then(|db| db.get(KAFKA_OFFSET_KEY).ok().flatten().and_then(|v| bincode::deserialize(&v).ok()))
+        .and_then(|db| db.get(QUARANTINED_KEY).ok().flatten())
+        .and_then(|v| bincode::deserialize(&v).ok())
+        .unwrap_or_default()
 }
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "persist_sled")] {
-        fn get_persistent_offsets(state: Arc<RwLock<GlobalState>>) -> (Option<usize>, Option<i64>) {
-            get_latest_dump().unwrap().map_or_else(
-                || (None, None),
+        fn get_persistent_offsets(state: Arc<RwLock<GlobalState>>, max_block_num: usize) -> (Option<usize>, PartitionOffsets, BTreeSet<u32>) {
+            get_latest_dump(max_block_num).unwrap().map_or_else(
+                || (None, PartitionOffsets::new(), BTreeSet::new()),
                 |id| {
                 log::info!("found dump #{}", id);
                 let db = sled::open(Settings::persist_dir().join(format!("{}.db", id))).ok();
-                (get_block_offset(&db, state), get_kafka_offset(&db))
+                (get_block_offset(&db, state), get_kafka_offsets(&db), get_quarantined(&db))
                 })
         }
     } else {
-        fn get_persistent_offsets(_state: Arc<RwLock<GlobalState>>) -> (Option<usize>, Option<i64>) {
-            (None, None)
+        fn get_persistent_offsets(_state: Arc<RwLock<GlobalState>>, _max_block_num: usize) -> (Option<usize>, PartitionOffsets, BTreeSet<u32>) {
+            (None, PartitionOffsets::new(), BTreeSet::new())
         }
     }
 }
@@ -0,0 +1,130 @@
+// Rebuild the global state from block public data only, see `rollup_state_manager::state::recovery`.
+// usage: recover_state <db | blocks.jsonl> [sled_dump_path]
+// `db` reads `raw_public_data` and `new_root` of all blocks in the l2_block table.
+// blocks.jsonl has one block per line, e.g. public data taken from L1 calldata:
+//   {"block_id": 0, "public_data": "0x...", "new_root": "0x..."}
+// where new_root is optional. The recovered state is dumped to sled only when it is complete.
+use anyhow::{anyhow, bail, Context, Result};
+use fluidex_common::db::models::tablenames;
+use rollup_state_manager::config::Settings;
+use rollup_state_manager::params;
+use rollup_state_manager::state::recovery::{RootCheck, StateRecovery};
+use rollup_state_manager::state::GlobalState;
+use serde::Deserialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::io::{BufRead, BufReader};
+use std::{env, fs};
+
+#[derive(Deserialize)]
+struct BlockPubData {
+    block_id: u64,
+    public_data: String,
+    new_root: Option<String>,
+}
+
+fn read_blocks_file(path: &str) -> Result<Vec<BlockPubData>> {
+    let file = fs::File::open(path).with_context(|| format!("failed to open {}", path))?;
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+async fn read_blocks_db() -> Result<Vec<BlockPubData>> {
+    Settings::init_default();
+    let db_pool = PgPool::connect(Settings::db()).await?;
+    let rows = sqlx::query(&format!(
+        "select block_id, raw_public_data, new_root from {} order by block_id",
+        tablenames::L2_BLOCK
+    ))
+    .fetch_all(&db_pool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            let block_id: i64 = row.try_get("block_id")?;
+            let public_data: Vec<u8> = row.try_get("raw_public_data")?;
+            Ok(BlockPubData {
+                block_id: block_id as u64,
+                public_data: hex::encode(public_data),
+                new_root: Some(row.try_get("new_root")?),
+            })
+        })
+        .collect()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+
+    let mut args = env::args().skip(1);
+    let source = args
+        .next()
+        .ok_or_else(|| anyhow!("usage: recover_state <db | blocks.jsonl> [sled_dump_path]"))?;
+    let dump_path = args.next();
+    let mut blocks = if source == "db" {
+        read_blocks_db().await?
+    } else {
+        read_blocks_file(&source)?
+    };
+    blocks.sort_by_key(|b| b.block_id);
+
+    let state = GlobalState::new(
+        *params::BALANCELEVELS,
+        *params::ORDERLEVELS,
+        *params::ACCOUNTLEVELS,
+        *params::VERBOSE,
+    );
+    let mut recovery = StateRecovery::new(state);
+    for block in &blocks {
+        let public_data = hex::decode(block.public_data.trim_start_matches("0x"))?;
+        let result = recovery
+            .apply_block(block.block_id, &public_data, block.new_root.as_deref())
+            .with_context(|| format!("invalid public data of block {}", block.block_id))?;
+        println!(
+            "block {}: {} txs, root {} {:?}",
+            result.block_id, result.tx_num, result.root, result.check
+        );
+    }
+
+    let next_block_id = recovery.next_block_id();
+    let (state, report) = recovery.finish();
+    for insufficiency in &report.insufficiencies {
+        println!("insufficient: {:?}", insufficiency);
+    }
+    if !report.unrecoverable_accounts.is_empty() {
+        println!("unrecoverable accounts: {:?}", report.unrecoverable_accounts);
+    }
+    let matched = report.blocks.iter().filter(|b| b.check == RootCheck::Matched).count();
+    println!(
+        "recovered {} blocks, {} roots verified, complete: {}",
+        report.blocks.len(),
+        matched,
+        report.is_complete()
+    );
+
+    if let Some(dump_path) = dump_path {
+        if !report.is_complete() {
+            bail!("the recovered state is incomplete, refuse to dump it");
+        }
+        dump(&state, &dump_path, next_block_id)?;
+        println!("dumped to {}", dump_path);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "persist_sled")]
+fn dump(state: &GlobalState, path: &str, next_block_id: u64) -> Result<()> {
+    use rollup_state_manager::r#const::sled_db::BLOCK_OFFSET_KEY;
+    let db = sled::open(path)?;
+    // same as ManagerWrapper::persist, but the kafka offset is unknown
+    db.insert(BLOCK_OFFSET_KEY, bincode::serialize(&(next_block_id as usize))?)?;
+    state.persist(&db)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "persist_sled"))]
+fn dump(_state: &GlobalState, _path: &str, _next_block_id: u64) -> Result<()> {
+    bail!("dumping requires the persist_sled feature")
+}
@@ -1,6 +1,8 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::path::Path;
 
+use crate::types::matchengine::messages::TopicPartition;
 use once_cell::sync::OnceCell;
 use serde::Deserialize;
 
@@ -11,9 +13,331 @@ static SETTINGS: OnceCell<Settings> = OnceCell::new();
 pub struct Settings {
     pub brokers: String,
     pub grpc_addr: String,
+    /// serves the query RPCs as REST/JSON under `/api/explorer`, disabled if unset
+    #[serde(default)]
+    pub rest_addr: Option<String>,
     pub db: String,
     pub persist_dir: Box<Path>,
     pub persist_every_n_block: usize,
+    #[serde(default)]
+    pub sealing: SealingSettings,
+    /// put orders into the order tree by PlaceOrder txs when they are PUT, instead of when they first trade.
+    /// Keep it off in production: the circuit and the orchestra proto have no PlaceOrder (pubdata heading 5) yet,
+    /// so such blocks can neither be proven nor shown by the explorer
+    #[serde(default)]
+    pub place_order_tx: bool,
+    /// apply the full exit msgs of L1 as FullExit txs, otherwise they are dead lettered, to be re-injected later.
+    /// Keep it off in production: the circuit has no FullExit (pubdata heading 3) yet,
+    /// so a block with one can not be proven, nor can any block after it
+    #[serde(default)]
+    pub full_exit_tx: bool,
+    /// read deposits from L1 events instead of the deposit messages of the exchange
+    #[serde(default)]
+    pub l1_deposit: Option<L1DepositSettings>,
+    /// update the status of blocks by the L1 events of the rollup contract
+    #[serde(default)]
+    pub l1_watcher: Option<L1WatcherSettings>,
+    #[serde(default)]
+    pub kafka: KafkaSettings,
+    #[serde(default)]
+    pub msg_source: MsgSourceSettings,
+    #[serde(default)]
+    pub dead_letter: DeadLetterSettings,
+    #[serde(default)]
+    pub pipeline: PipelineSettings,
+    #[serde(default)]
+    pub shutdown: ShutdownSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub prover: ProverSettings,
+}
+
+/// Task leasing of the prover coordinator, see [`crate::grpc`].
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ProverSettings {
+    /// a leased task is re-queued if the prover sends no heartbeat for this long
+    pub lease_secs: u64,
+    /// a task is no longer handed out after being leased this many times
+    pub max_attempts: u32,
+    /// only hand out tasks whose witness is generated
+    pub require_witness: bool,
+}
+
+impl Default for ProverSettings {
+    fn default() -> Self {
+        Self {
+            lease_secs: 300,
+            max_attempts: 3,
+            require_witness: false,
+        }
+    }
+}
+
+/// The http server of metrics and probes, see [`crate::metrics`].
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// serves `/metrics`, `/health` and `/ready`, disabled if unset
+    pub addr: Option<String>,
+    /// `/ready` fails while more msgs than this are in kafka but not consumed by the processor
+    pub ready_max_lag: i64,
+    /// `/ready` fails while more blocks than this are sealed but not saved
+    pub ready_max_block_queue: usize,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            addr: None,
+            ready_max_lag: 100,
+            ready_max_block_queue: 1,
+        }
+    }
+}
+
+/// What to do with the pending (not full) block on shutdown.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingBlockPolicy {
+    /// pad it with nop and save it, so all consumed msgs are in blocks
+    Seal,
+    /// leave it unsealed, its msgs are consumed again on the next start.
+    /// No final snapshot is taken, since the state includes its txs.
+    Discard,
+}
+
+/// Graceful shutdown, see [`crate::shutdown`].
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// exit anyway if the shutdown takes longer
+    pub timeout_secs: u64,
+    pub pending_block: PendingBlockPolicy,
+    /// take a snapshot of the state after the last block
+    pub final_snapshot: bool,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 60,
+            pending_block: PendingBlockPolicy::Seal,
+            final_snapshot: true,
+        }
+    }
+}
+
+/// Capacities of the channels between the msg source, the processor and the db writer,
+/// see [`crate::msg::pipeline`].
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct PipelineSettings {
+    /// msgs waiting to be processed; kafka consumption is paused while it is full
+    pub msg_capacity: usize,
+    /// blocks waiting to be saved; the processor waits while it is full
+    pub block_capacity: usize,
+    /// how often to fetch kafka watermarks to report the lags
+    pub lag_report_secs: u64,
+}
+
+impl Default for PipelineSettings {
+    fn default() -> Self {
+        Self {
+            msg_capacity: 10000,
+            block_capacity: 16,
+            lag_report_secs: 10,
+        }
+    }
+}
+
+/// What to do with msgs which fail parsing or processing, see [`crate::msg::dead_letter`].
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterPolicy {
+    /// record the msg and stop
+    Halt,
+    /// record the msg and go on
+    Skip,
+    /// record the msg, and reject all later msgs of the accounts it touches, also after a restart
+    QuarantineAccount,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct DeadLetterSettings {
+    pub policy: DeadLetterPolicy,
+    /// json lines file to append dead letters to, they are only logged if unset
+    pub path: Option<String>,
+}
+
+impl Default for DeadLetterSettings {
+    fn default() -> Self {
+        Self {
+            policy: DeadLetterPolicy::Halt,
+            path: None,
+        }
+    }
+}
+
+/// Where msgs are read from, see [`crate::msg::msg_source`].
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MsgSourceSettings {
+    Kafka,
+    /// msgs in json lines
+    File {
+        path: String,
+    },
+    Stdin,
+    /// kafka msgs archived in a postgres table
+    Replay {
+        #[serde(default = "default_archive_table")]
+        table: String,
+    },
+}
+
+fn default_archive_table() -> String {
+    "archived_message".to_string()
+}
+
+impl Default for MsgSourceSettings {
+    fn default() -> Self {
+        Self::Kafka
+    }
+}
+
+impl MsgSourceSettings {
+    /// Parses the `--source` cli arg: `kafka`, `stdin`, `replay`, `replay:<table>` or a file path.
+    pub fn from_arg(arg: &str) -> Self {
+        match arg {
+            "kafka" => Self::Kafka,
+            "stdin" | "-" => Self::Stdin,
+            "replay" => Self::Replay {
+                table: default_archive_table(),
+            },
+            _ => match arg.strip_prefix("replay:") {
+                Some(table) => Self::Replay { table: table.to_string() },
+                None => Self::File { path: arg.to_string() },
+            },
+        }
+    }
+}
+
+/// Topic-partitions to consume, see [`crate::msg::msg_loader`].
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct KafkaSettings {
+    pub group_id: String,
+    pub topics: Vec<TopicSettings>,
+    /// how often to check whether a partition without buffered msgs is read up to its high watermark,
+    /// only then it no longer holds back the msgs of other partitions, see `msg::msg_merge`
+    pub watermark_check_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct TopicSettings {
+    pub name: String,
+    pub partitions: Vec<i32>,
+}
+
+impl Default for KafkaSettings {
+    fn default() -> Self {
+        Self {
+            group_id: "rollup_msg_consumer".to_string(),
+            topics: vec![TopicSettings {
+                name: "unifyevents".to_string(),
+                partitions: vec![0],
+            }],
+            watermark_check_ms: 500,
+        }
+    }
+}
+
+impl KafkaSettings {
+    // all topic-partitions, in the order used to break ties when merging
+    pub fn topic_partitions(&self) -> Vec<TopicPartition> {
+        self.topics
+            .iter()
+            .flat_map(|topic| {
+                topic.partitions.iter().map(move |&partition| TopicPartition {
+                    topic: topic.name.clone(),
+                    partition,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Source of L1 deposits, see [`crate::l1::deposit`].
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct L1DepositSettings {
+    /// JSON-RPC endpoint of the L1 node
+    pub rpc_url: Option<String>,
+    pub contract_address: Option<String>,
+    /// read deposit events from this jsonl file instead of a node, for testing
+    pub mock_file: Option<String>,
+    /// a deposit is applied once this many blocks are mined on top of it
+    pub confirmations: u64,
+    /// the L1 block to start scanning from, unless the state is restored with a later position
+    pub from_block: u64,
+    pub poll_interval_secs: Option<u64>,
+    /// decimals of the L1 token by token id, 18 if unset. Amounts are converted to the L2 precision of the token
+    pub token_decimals: BTreeMap<u32, u32>,
+}
+
+/// Source of block commit and verification events, see [`crate::l1::block_status`].
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct L1WatcherSettings {
+    /// JSON-RPC endpoint of the L1 node
+    pub rpc_url: String,
+    pub contract_address: String,
+    /// the L1 block to start scanning from
+    #[serde(default)]
+    pub from_block: u64,
+    /// reorgs of at most this many blocks are reverted
+    #[serde(default = "default_reorg_depth")]
+    pub reorg_depth: usize,
+    /// at most this many L1 blocks are scanned by one `eth_getLogs` call
+    #[serde(default = "default_max_scan_blocks")]
+    pub max_scan_blocks: u64,
+    pub poll_interval_secs: Option<u64>,
+}
+
+fn default_reorg_depth() -> usize {
+    12
+}
+
+fn default_max_scan_blocks() -> u64 {
+    1000
+}
+
+/// Block sealing rules, see [`crate::state::sealing`].
+/// A rule set to null is disabled; a partial block is sealed as soon as any enabled rule fires.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct SealingSettings {
+    /// seal once the first buffered tx has waited this long, 120 by default like the old idle flush
+    pub max_wait_secs: Option<u64>,
+    /// seal once the pending block is at least this full (0.0 ~ 1.0)
+    pub min_fill_ratio: Option<f64>,
+    /// seal an empty block if no block was sealed during this period
+    pub heartbeat_secs: Option<u64>,
+    /// seal as soon as one of these tx types is buffered, e.g. `withdraw`
+    pub seal_on_tx_types: Vec<String>,
+}
+
+impl Default for SealingSettings {
+    fn default() -> Self {
+        Self {
+            max_wait_secs: Some(120),
+            min_fill_ratio: None,
+            heartbeat_secs: None,
+            seal_on_tx_types: Vec::new(),
+        }
+    }
 }
 
 impl Default for Settings {
@@ -44,9 +368,22 @@ impl Settings {
         Settings {
             brokers: String::new(),
             grpc_addr: String::new(),
+            rest_addr: None,
             db: String::new(),
             persist_dir: Box::from(Path::new(".")),
             persist_every_n_block: 0,
+            sealing: SealingSettings::default(),
+            place_order_tx: false,
+            full_exit_tx: false,
+            l1_deposit: None,
+            l1_watcher: None,
+            kafka: KafkaSettings::default(),
+            msg_source: MsgSourceSettings::default(),
+            dead_letter: DeadLetterSettings::default(),
+            pipeline: PipelineSettings::default(),
+            shutdown: ShutdownSettings::default(),
+            metrics: MetricsSettings::default(),
+            prover: ProverSettings::default(),
         }
     }
 
@@ -80,6 +417,12 @@ impl Settings {
         Self::get().grpc_addr.as_str()
     }
 
+    /// Shortcut of `Self::get().rest_addr.as_deref()`
+    #[inline(always)]
+    pub fn rest_addr() -> Option<&'static str> {
+        Self::get().rest_addr.as_deref()
+    }
+
     /// Shortcut of `Self::get().rollup_state_manager_db.as_str()`
     #[inline(always)]
     pub fn db() -> &'static str {
@@ -95,4 +438,76 @@ impl Settings {
     pub fn persist_every_n_block() -> usize {
         Self::get().persist_every_n_block
     }
+
+    /// Shortcut of `&Self::get().sealing`
+    #[inline(always)]
+    pub fn sealing() -> &'static SealingSettings {
+        &Self::get().sealing
+    }
+
+    /// Shortcut of `Self::get().place_order_tx`
+    #[inline(always)]
+    pub fn place_order_tx() -> bool {
+        Self::get().place_order_tx
+    }
+
+    /// Shortcut of `Self::get().full_exit_tx`
+    #[inline(always)]
+    pub fn full_exit_tx() -> bool {
+        Self::get().full_exit_tx
+    }
+
+    /// Shortcut of `Self::get().l1_deposit.as_ref()`
+    #[inline(always)]
+    pub fn l1_deposit() -> Option<&'static L1DepositSettings> {
+        Self::get().l1_deposit.as_ref()
+    }
+
+    /// Shortcut of `Self::get().l1_watcher.as_ref()`
+    #[inline(always)]
+    pub fn l1_watcher() -> Option<&'static L1WatcherSettings> {
+        Self::get().l1_watcher.as_ref()
+    }
+
+    /// Shortcut of `&Self::get().kafka`
+    #[inline(always)]
+    pub fn kafka() -> &'static KafkaSettings {
+        &Self::get().kafka
+    }
+
+    /// Shortcut of `&Self::get().msg_source`
+    #[inline(always)]
+    pub fn msg_source() -> &'static MsgSourceSettings {
+        &Self::get().msg_source
+    }
+
+    /// Shortcut of `&Self::get().dead_letter`
+    #[inline(always)]
+    pub fn dead_letter() -> &'static DeadLetterSettings {
+        &Self::get().dead_letter
+    }
+
+    /// Shortcut of `&Self::get().pipeline`
+    #[inline(always)]
+    pub fn pipeline() -> &'static PipelineSettings {
+        &Self::get().pipeline
+    }
+
+    /// Shortcut of `&Self::get().shutdown`
+    #[inline(always)]
+    pub fn shutdown() -> &'static ShutdownSettings {
+        &Self::get().shutdown
+    }
+
+    /// Shortcut of `&Self::get().metrics`
+    #[inline(always)]
+    pub fn metrics() -> &'static MetricsSettings {
+        &Self::get().metrics
+    }
+
+    /// Shortcut of `&Self::get().prover`
+    #[inline(always)]
+    pub fn prover() -> &'static ProverSettings {
+        &Self::get().prover
+    }
 }
@@ -1,7 +1,11 @@
 #[cfg(feature = "persist_sled")]
 pub mod sled_db {
     pub const BLOCK_OFFSET_KEY: &str = "block_offset";
+    // single offset of partition 0 of the unify topic, only in old dumps
     pub const KAFKA_OFFSET_KEY: &str = "kafka_offset";
+    pub const KAFKA_OFFSETS_KEY: &str = "kafka_offsets";
+    // accounts quarantined by dead letters, see `DeadLetterPolicy::QuarantineAccount`
+    pub const QUARANTINED_KEY: &str = "quarantined";
     pub const ACCOUNTTREE_KEY: &str = "account_tree";
     pub const ACCOUNTSTATES_KEY: &str = "account_states";
     pub const BALANCETREES_KEY: &str = "balance_trees";
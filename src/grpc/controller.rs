@@ -1,5 +1,11 @@
 use crate::config::Settings;
+use crate::grpc::proto::explorer::{
+    account_info_query_response, account_tx_history_query_response, tx_status_query_response, AccountInfoQueryRequest,
+    AccountInfoQueryResponse, AccountTxHistoryQueryRequest, AccountTxHistoryQueryResponse, BlockUpdate, TxStatusQueryRequest,
+    TxStatusQueryResponse,
+};
 use crate::state::global::GlobalState;
+use crate::storage;
 use crate::test_utils::types::{get_token_id_by_name, prec_token_id};
 use crate::types::l2::{tx_detail_idx, L2BlockSerde, TxType};
 use core::cmp::min;
@@ -8,9 +14,13 @@ use fluidex_common::db::DbType;
 use fluidex_common::types::FrExt;
 use fluidex_common::utils::timeutil::FTimestamp;
 use orchestra::rpc::rollup::*;
+use sqlx::postgres::PgRow;
+use sqlx::Row;
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use tonic::{Code, Status};
 
+#[derive(Clone)]
 pub struct Controller {
     db_pool: sqlx::Pool<DbType>,
     state: Arc<RwLock<GlobalState>>,
@@ -31,29 +41,47 @@ impl Controller {
 
         Ok(L2BlocksQueryResponse {
             total,
-            blocks: blocks
-                .iter()
-                .map(|b| l2_blocks_query_response::BlockSummary {
-                    block_height: b.block_id,
-                    merkle_root: b.new_root.clone(),
-                    block_time: FTimestamp::from(&b.created_time).as_milliseconds(),
-                })
-                .collect(),
+            blocks: blocks.iter().map(block_summary).collect(),
         })
     }
 
+    // updates of the blocks from `from_block_id` on, in order, along with their ids
+    pub async fn block_updates(&self, from_block_id: i64, limit: i64, with_txs: bool) -> Result<Vec<(i64, BlockUpdate)>, Status> {
+        let blocks = get_l2_blocks_from(&self.db_pool, from_block_id, limit).await.map_err(|e| {
+            log::error!("{:?}", e);
+            Status::new(Code::Internal, "db l2_blocks query error")
+        })?;
+
+        let mut updates = Vec::with_capacity(blocks.len());
+        for b in blocks {
+            let detail = if with_txs {
+                Some(self.l2_block_query(L2BlockQueryRequest { block_id: b.block_id }).await?)
+            } else {
+                None
+            };
+            let update = BlockUpdate {
+                block: Some(block_summary(&b)),
+                detail,
+            };
+            updates.push((b.block_id, update));
+        }
+        Ok(updates)
+    }
+
     // TODO: cache
     pub async fn l2_block_query(&self, request: L2BlockQueryRequest) -> Result<L2BlockQueryResponse, Status> {
         let block_id = request.block_id;
         let l2_block = get_l2_block_by_id(&self.db_pool, block_id).await?;
 
-        let status = match get_status_by_block_id(&self.db_pool, block_id).await? {
-            l2_block::BlockStatus::Uncommited => BlockStatus::Uncommited,
-            l2_block::BlockStatus::Commited => BlockStatus::Commited,
-            l2_block::BlockStatus::Verified => BlockStatus::Verified,
-        };
+        let status = block_status(get_status_by_block_id(&self.db_pool, block_id).await?);
 
-        let detail: L2BlockSerde = serde_json::from_value(l2_block.detail).unwrap();
+        let detail: L2BlockSerde = storage::load_block_detail(&self.db_pool, block_id)
+            .await
+            .map_err(|e| {
+                log::error!("{:?}", e);
+                Status::new(Code::Internal, "db l2_block detail error")
+            })?
+            .ok_or_else(|| Status::new(Code::NotFound, "db l2_block record not found"))?;
         let tx_num = detail.encoded_txs.len() as u64;
         let real_tx_num = detail.txs_type.clone().into_iter().filter(|t| *t != TxType::Nop).count();
 
@@ -88,7 +116,8 @@ impl Controller {
                         new_balance,
                     })
                 }
-                TxType::Withdraw => {
+                // full exit is shown as a withdraw of the whole balance
+                TxType::Withdraw | TxType::FullExit => {
                     let account_id = tx[tx_detail_idx::ACCOUNT_ID1].0.to_u32();
 
                     let token_id = tx[tx_detail_idx::TOKEN_ID1].0.to_u32();
@@ -209,8 +238,8 @@ impl Controller {
     pub fn token_balance_query(&self, request: TokenBalanceQueryRequest) -> Result<TokenBalanceQueryResponse, Status> {
         let token_id = if let Some(token_id) = request.token_id {
             token_id
-        } else if let Some(_token_address) = request.token_address {
-            unimplemented!()
+        } else if request.token_address.is_some() {
+            return Err(Status::new(Code::Unimplemented, "query by token_address is not supported yet"));
         } else if let Some(token_name) = request.token_name {
             get_token_id_by_name(&token_name)
         } else {
@@ -229,6 +258,229 @@ impl Controller {
             precision,
         })
     }
+
+    // the receipts of the txs from a msg, by its source id or its kafka offset.
+    // none means the msg is not included in any saved block yet
+    pub async fn tx_status_query(&self, request: TxStatusQueryRequest) -> Result<TxStatusQueryResponse, Status> {
+        let receipts = match (request.source_id, request.topic, request.partition, request.offset) {
+            (Some(source_id), _, _, _) => {
+                let stmt = tx_receipts_stmt("r.source_id = $1");
+                sqlx::query(&stmt).bind(source_id).fetch_all(&self.db_pool).await
+            }
+            (None, Some(topic), Some(partition), Some(offset)) => {
+                let stmt = tx_receipts_stmt("r.topic = $1 and r.partition_id = $2 and r.msg_offset = $3");
+                sqlx::query(&stmt)
+                    .bind(topic)
+                    .bind(partition)
+                    .bind(offset)
+                    .fetch_all(&self.db_pool)
+                    .await
+            }
+            _ => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "Must specify either source_id or topic, partition and offset",
+                ))
+            }
+        };
+        let receipts = receipts
+            .and_then(|rows| rows.iter().map(tx_receipt_from_row).collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                log::error!("{:?}", e);
+                Status::new(Code::Internal, "db tx_receipt query error")
+            })?;
+        Ok(TxStatusQueryResponse { receipts })
+    }
+
+    // the txs touching an account, the latest first
+    pub async fn account_tx_history_query(&self, request: AccountTxHistoryQueryRequest) -> Result<AccountTxHistoryQueryResponse, Status> {
+        let tx_type = match request.tx_type.as_deref() {
+            Some(tx_type) => Some(TxType::from_str(tx_type).map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))?),
+            None => None,
+        };
+        let limit = if request.limit.is_positive() { request.limit } else { 10 };
+        let limit = min(100, limit);
+        let offset = request.offset.max(0);
+
+        let (total, txs) = get_account_txs(
+            &self.db_pool,
+            request.account_id,
+            tx_type,
+            (request.start_time, request.end_time),
+            offset,
+            limit,
+        )
+        .await
+        .map_err(|e| {
+            log::error!("{:?}", e);
+            Status::new(Code::Internal, "db l2_tx query error")
+        })?;
+        Ok(AccountTxHistoryQueryResponse { total, txs })
+    }
+
+    pub fn account_info_query(&self, request: AccountInfoQueryRequest) -> Result<AccountInfoQueryResponse, Status> {
+        let account_id = request.account_id;
+        let (has_account, account, balances) = {
+            let state = self.state.read().unwrap();
+            (
+                state.has_account(account_id),
+                state.get_account(account_id),
+                state.get_token_balances(account_id),
+            )
+        };
+
+        Ok(AccountInfoQueryResponse {
+            has_account,
+            nonce: account.nonce.to_decimal_string(),
+            sign: account.sign.to_decimal_string(),
+            ay: account.ay.to_hex_string(),
+            balance_root: account.balance_root.to_hex_string(),
+            order_root: account.order_root.to_hex_string(),
+            balances: balances
+                .into_iter()
+                .map(|(token_id, balance)| {
+                    let precision = prec_token_id(token_id);
+                    account_info_query_response::TokenBalance {
+                        token_id,
+                        balance: balance.to_decimal(precision).to_string(),
+                        balance_raw: balance.to_decimal_string(),
+                        precision,
+                    }
+                })
+                .collect(),
+        })
+    }
+}
+
+fn block_status(status: l2_block::BlockStatus) -> BlockStatus {
+    match status {
+        l2_block::BlockStatus::Uncommited => BlockStatus::Uncommited,
+        l2_block::BlockStatus::Commited => BlockStatus::Commited,
+        l2_block::BlockStatus::Verified => BlockStatus::Verified,
+    }
+}
+
+fn block_summary(b: &l2_block::L2Block) -> l2_blocks_query_response::BlockSummary {
+    l2_blocks_query_response::BlockSummary {
+        block_height: b.block_id,
+        merkle_root: b.new_root.clone(),
+        block_time: FTimestamp::from(&b.created_time).as_milliseconds(),
+    }
+}
+
+fn tx_receipts_stmt(condition: &str) -> String {
+    format!(
+        "select r.block_id, r.tx_index, r.tx_type, r.topic, r.partition_id, r.msg_offset, r.source_id,
+            r.root_before, r.root_after, b.status, b.l1_tx_hash
+        from {} r join {} b on b.block_id = r.block_id
+        where {}
+        order by r.block_id, r.tx_index",
+        storage::TX_RECEIPT,
+        tablenames::L2_BLOCK,
+        condition
+    )
+}
+
+fn tx_receipt_from_row(row: &PgRow) -> Result<tx_status_query_response::TxReceipt, sqlx::Error> {
+    let tx_type: String = row.try_get("tx_type")?;
+    let tx_type = TxType::from_str(&tx_type).map_err(|e| sqlx::Error::Decode(e.into()))?;
+    Ok(tx_status_query_response::TxReceipt {
+        block_id: row.try_get("block_id")?,
+        tx_index: row.try_get::<i32, _>("tx_index")? as u32,
+        tx_type: tx_type as i32,
+        topic: row.try_get::<Option<String>, _>("topic")?.unwrap_or_default(),
+        partition: row.try_get::<Option<i32>, _>("partition_id")?.unwrap_or_default(),
+        offset: row.try_get::<Option<i64>, _>("msg_offset")?.unwrap_or_default(),
+        source_id: row.try_get::<Option<String>, _>("source_id")?.unwrap_or_default(),
+        root_before: row.try_get("root_before")?,
+        root_after: row.try_get("root_after")?,
+        block_status: block_status(row.try_get("status")?) as i32,
+        l1_tx_hash: row.try_get::<Option<String>, _>("l1_tx_hash")?.unwrap_or_default(),
+    })
+}
+
+fn optional_id(row: &PgRow, column: &str) -> Result<Option<u32>, sqlx::Error> {
+    Ok(row.try_get::<Option<i32>, _>(column)?.map(|id| id as u32))
+}
+
+// empty if null
+fn optional_decimal(row: &PgRow, column: &str) -> Result<String, sqlx::Error> {
+    Ok(row.try_get::<Option<String>, _>(column)?.unwrap_or_default())
+}
+
+// `time_range` is [start, end) in milliseconds
+async fn get_account_txs(
+    db_pool: &sqlx::Pool<DbType>,
+    account_id: u32,
+    tx_type: Option<TxType>,
+    time_range: (Option<i64>, Option<i64>),
+    offset: i64,
+    limit: i64,
+) -> Result<(i64, Vec<account_tx_history_query_response::L2Tx>), anyhow::Error> {
+    let condition = "(t.from_account = $1 or t.to_account = $1)
+        and ($2::varchar is null or t.tx_type = $2)
+        and ($3::bigint is null or extract(epoch from b.created_time) * 1000 >= $3)
+        and ($4::bigint is null or extract(epoch from b.created_time) * 1000 < $4)";
+    let mut tx = db_pool.begin().await?;
+
+    let total: i64 = sqlx::query_scalar(&format!(
+        "select count(*) from {} t join {} b on b.block_id = t.block_id where {}",
+        storage::l2_tx::L2_TX,
+        tablenames::L2_BLOCK,
+        condition
+    ))
+    .bind(account_id as i32)
+    .bind(tx_type.map(|t| t.as_str()))
+    .bind(time_range.0)
+    .bind(time_range.1)
+    .fetch_one(&mut tx)
+    .await?;
+
+    let rows = sqlx::query(&format!(
+        "select t.block_id, t.tx_index, t.tx_type, (extract(epoch from b.created_time) * 1000)::bigint as created_time,
+            t.from_account, t.to_account, t.token_id, t.amount::text, t.to_token_id, t.to_amount::text,
+            t.from_balance::text, t.from_balance_to_token::text, t.to_balance::text, t.to_balance_to_token::text
+        from {} t join {} b on b.block_id = t.block_id
+        where {}
+        order by t.block_id desc, t.tx_index desc
+        offset $5 limit $6",
+        storage::l2_tx::L2_TX,
+        tablenames::L2_BLOCK,
+        condition
+    ))
+    .bind(account_id as i32)
+    .bind(tx_type.map(|t| t.as_str()))
+    .bind(time_range.0)
+    .bind(time_range.1)
+    .bind(offset)
+    .bind(limit)
+    .fetch_all(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    let txs = rows
+        .iter()
+        .map(|row| -> Result<_, anyhow::Error> {
+            let tx_type: String = row.try_get("tx_type")?;
+            Ok(account_tx_history_query_response::L2Tx {
+                block_id: row.try_get("block_id")?,
+                tx_index: row.try_get::<i32, _>("tx_index")? as u32,
+                tx_type: TxType::from_str(&tx_type)? as i32,
+                created_time: row.try_get("created_time")?,
+                from_account: row.try_get::<i32, _>("from_account")? as u32,
+                to_account: optional_id(row, "to_account")?,
+                token_id: optional_id(row, "token_id")?,
+                amount: optional_decimal(row, "amount")?,
+                to_token_id: optional_id(row, "to_token_id")?,
+                to_amount: optional_decimal(row, "to_amount")?,
+                from_balance: optional_decimal(row, "from_balance")?,
+                from_balance_to_token: optional_decimal(row, "from_balance_to_token")?,
+                to_balance: optional_decimal(row, "to_balance")?,
+                to_balance_to_token: optional_decimal(row, "to_balance_to_token")?,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((total, txs))
 }
 
 async fn get_l2_blocks(
@@ -249,7 +501,7 @@ async fn get_l2_blocks(
 
     let limit = min(100, limit);
     let blocks_query = format!(
-        "select block_id, new_root, raw_public_data, status, l1_tx_hash, detail, created_time
+        "select block_id, new_root, raw_public_data, status, l1_tx_hash, 'null'::jsonb as detail, created_time
             from {}
             where block_id <= $1
             order by block_id desc limit {}",
@@ -266,9 +518,26 @@ async fn get_l2_blocks(
     Ok((total, blocks))
 }
 
+async fn get_l2_blocks_from(db_pool: &sqlx::Pool<DbType>, from_block_id: i64, limit: i64) -> Result<Vec<l2_block::L2Block>, anyhow::Error> {
+    let stmt = format!(
+        "select block_id, new_root, raw_public_data, status, l1_tx_hash, 'null'::jsonb as detail, created_time
+        from {}
+        where block_id >= $1
+        order by block_id limit $2",
+        tablenames::L2_BLOCK
+    );
+    let blocks = sqlx::query_as::<_, l2_block::L2Block>(&stmt)
+        .bind(from_block_id)
+        .bind(limit)
+        .fetch_all(db_pool)
+        .await?;
+    Ok(blocks)
+}
+
+// the detail is left out, it is decoded by `storage::load_block_detail` when needed
 async fn get_l2_block_by_id(db_pool: &sqlx::Pool<DbType>, block_id: i64) -> Result<l2_block::L2Block, Status> {
     let stmt = format!(
-        "select block_id, new_root, raw_public_data, status, l1_tx_hash, detail, created_time
+        "select block_id, new_root, raw_public_data, status, l1_tx_hash, 'null'::jsonb as detail, created_time
         from {}
         where block_id = $1
         order by created_time desc limit 1",
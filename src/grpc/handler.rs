@@ -1,17 +1,29 @@
 use crate::grpc::controller::Controller;
+use crate::grpc::proto::explorer::{
+    explorer_server, AccountInfoQueryRequest, AccountInfoQueryResponse, AccountTxHistoryQueryRequest, AccountTxHistoryQueryResponse,
+    SubscribeBlocksRequest, TxStatusQueryRequest, TxStatusQueryResponse,
+};
+use crate::grpc::subscription::{self, BlockNotifier, BlockStream};
+use crate::shutdown::Shutdown;
 use crate::state::global::GlobalState;
 use orchestra::rpc::rollup::*;
 use std::sync::{Arc, RwLock};
 use tonic::{Request, Response, Status};
 
+// serves both `RollupState` and `Explorer`
+#[derive(Clone)]
 pub struct Handler {
     controller: Controller,
+    notifier: BlockNotifier,
+    shutdown: Shutdown,
 }
 
 impl Handler {
-    pub async fn new(state: Arc<RwLock<GlobalState>>) -> Self {
+    pub async fn new(state: Arc<RwLock<GlobalState>>, notifier: BlockNotifier, shutdown: Shutdown) -> Self {
         Self {
             controller: Controller::new(state).await,
+            notifier,
+            shutdown,
         }
     }
 }
@@ -30,3 +42,32 @@ impl rollup_state_server::RollupState for Handler {
         Ok(Response::new(self.controller.token_balance_query(request.into_inner())?))
     }
 }
+
+#[tonic::async_trait]
+impl explorer_server::Explorer for Handler {
+    type SubscribeBlocksStream = BlockStream;
+
+    async fn subscribe_blocks(&self, request: Request<SubscribeBlocksRequest>) -> Result<Response<Self::SubscribeBlocksStream>, Status> {
+        Ok(Response::new(subscription::subscribe_blocks(
+            self.controller.clone(),
+            &self.notifier,
+            request.into_inner(),
+            self.shutdown.clone(),
+        )))
+    }
+
+    async fn account_info_query(&self, request: Request<AccountInfoQueryRequest>) -> Result<Response<AccountInfoQueryResponse>, Status> {
+        Ok(Response::new(self.controller.account_info_query(request.into_inner())?))
+    }
+
+    async fn tx_status_query(&self, request: Request<TxStatusQueryRequest>) -> Result<Response<TxStatusQueryResponse>, Status> {
+        Ok(Response::new(self.controller.tx_status_query(request.into_inner()).await?))
+    }
+
+    async fn account_tx_history_query(
+        &self,
+        request: Request<AccountTxHistoryQueryRequest>,
+    ) -> Result<Response<AccountTxHistoryQueryResponse>, Status> {
+        Ok(Response::new(self.controller.account_tx_history_query(request.into_inner()).await?))
+    }
+}
@@ -1,32 +1,62 @@
 mod controller;
 mod handler;
+mod prover;
+mod rest;
+mod subscription;
+mod view;
+
+// services which are not in orchestra, compiled from `proto/` by build.rs
+pub mod proto {
+    pub mod explorer {
+        tonic::include_proto!("explorer");
+    }
+    pub mod prover {
+        tonic::include_proto!("prover");
+    }
+}
 
 use crate::grpc::handler::Handler;
+use crate::grpc::proto::explorer::explorer_server::ExplorerServer;
+use crate::grpc::proto::prover::prover_coordinator_server::ProverCoordinatorServer;
+use crate::grpc::prover::ProverHandler;
+use crate::shutdown::Shutdown;
 use crate::state::GlobalState;
 use orchestra::rpc::rollup::rollup_state_server::RollupStateServer;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
-pub fn run_grpc_server(addr: SocketAddr, state: Arc<RwLock<GlobalState>>) -> anyhow::Result<()> {
+pub use rest::run_rest_server;
+pub use subscription::BlockNotifier;
+
+// serves until `shutdown` is triggered
+pub fn run_grpc_server(
+    addr: SocketAddr,
+    state: Arc<RwLock<GlobalState>>,
+    notifier: BlockNotifier,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
     let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .expect("Build runtime");
 
     rt.block_on(async {
-        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.ok();
-            log::info!("Ctrl-C received, shutting down");
-            tx.send(()).ok();
-        });
-
-        let handler = Handler::new(state).await;
+        let handler = Handler::new(state, notifier, shutdown.clone()).await;
+        let prover_handler = ProverHandler::new().await;
+        // liveness only, readiness is `/ready` of the metrics server
+        let (mut health_reporter, health_service) = tonic_health::server::health_reporter();
+        health_reporter.set_serving::<RollupStateServer<Handler>>().await;
+        health_reporter.set_serving::<ExplorerServer<Handler>>().await;
+        health_reporter.set_serving::<ProverCoordinatorServer<ProverHandler>>().await;
 
         tonic::transport::Server::builder()
-            .add_service(RollupStateServer::new(handler))
-            .serve_with_shutdown(addr, async {
-                rx.await.ok();
+            .add_service(health_service)
+            .add_service(RollupStateServer::new(handler.clone()))
+            .add_service(ExplorerServer::new(handler))
+            .add_service(ProverCoordinatorServer::new(prover_handler))
+            .serve_with_shutdown(addr, async move {
+                shutdown.wait().await;
+                log::info!("stopping grpc server");
             })
             .await?;
 
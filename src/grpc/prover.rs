@@ -0,0 +1,398 @@
+// The prover-facing service. A prover leases a task by `FetchTask`, keeps the lease by `Heartbeat`,
+// and ends it by `SubmitProof` or `ReportFailure`. A task whose lease expires is re-queued on the next fetch,
+// and a task which has been leased `max_attempts` times is no longer handed out.
+// A proof is kept on its task and marks its block as proved by `proved_time`, in the same transaction.
+// The status of the block follows L1 and is left to the L1 watcher.
+use super::proto::prover::prover_coordinator_server::ProverCoordinator;
+use super::proto::prover::*;
+use crate::config::{ProverSettings, Settings};
+use crate::storage;
+use fluidex_common::db::models::tablenames;
+use fluidex_common::db::models::task::TaskStatus;
+use fluidex_common::db::DbType;
+use sqlx::Row;
+use tonic::{Code, Request, Response, Status};
+
+// `$1` and `$2` must be bound to `requeue_status(false)` and `requeue_status(true)`
+const REQUEUE_STATUS: &str = "case when witness is null then $1 else $2 end";
+
+// a task goes back to the status it was leased from: `Ready` if the witness is generated, else `Inited`
+fn requeue_status(has_witness: bool) -> TaskStatus {
+    if has_witness {
+        TaskStatus::Ready
+    } else {
+        TaskStatus::Inited
+    }
+}
+
+// statuses a task can be leased from
+fn fetchable(require_witness: bool) -> (TaskStatus, TaskStatus) {
+    if require_witness {
+        (TaskStatus::Ready, TaskStatus::Ready)
+    } else {
+        (TaskStatus::Inited, TaskStatus::Ready)
+    }
+}
+
+// a task which has been leased `attempts` times is handed out again, the fetch query filters by the same bound
+fn has_attempts_left(attempts: u32, max_attempts: u32) -> bool {
+    attempts < max_attempts
+}
+
+fn db_error(e: sqlx::Error) -> Status {
+    log::error!("{:?}", e);
+    Status::new(Code::Internal, "db task error")
+}
+
+fn lease_lost(task_id: &str) -> Status {
+    Status::new(Code::FailedPrecondition, format!("task {} is not leased to this prover", task_id))
+}
+
+pub struct ProverHandler {
+    db_pool: sqlx::Pool<DbType>,
+    settings: &'static ProverSettings,
+}
+
+impl ProverHandler {
+    pub async fn new() -> Self {
+        let db_pool = sqlx::postgres::PgPool::connect(Settings::db()).await.unwrap();
+        Self::with_pool(db_pool, Settings::prover())
+    }
+
+    pub fn with_pool(db_pool: sqlx::Pool<DbType>, settings: &'static ProverSettings) -> Self {
+        Self { db_pool, settings }
+    }
+
+    async fn requeue_expired(&self) -> Result<(), Status> {
+        let requeued = sqlx::query(&format!(
+            "update {} set status = {}, prover_id = null, lease_deadline = null, last_error = 'lease expired'
+            where status = $3 and lease_deadline < now()",
+            tablenames::TASK,
+            REQUEUE_STATUS
+        ))
+        .bind(requeue_status(false))
+        .bind(requeue_status(true))
+        .bind(TaskStatus::Assigned)
+        .execute(&self.db_pool)
+        .await
+        .map_err(db_error)?
+        .rows_affected();
+        if requeued > 0 {
+            log::warn!("re-queue {} tasks with expired leases", requeued);
+        }
+        Ok(())
+    }
+
+    async fn fetch_task(&self, request: FetchTaskRequest) -> Result<FetchTaskResponse, Status> {
+        if request.prover_id.is_empty() {
+            return Err(Status::new(Code::InvalidArgument, "prover_id is required"));
+        }
+        self.requeue_expired().await?;
+
+        let fetchable = fetchable(self.settings.require_witness);
+        // the oldest block first, and concurrent fetches never lease the same task
+        let row = sqlx::query(&format!(
+            "update {table} set status = $1, prover_id = $2, attempts = attempts + 1, last_error = null,
+                lease_deadline = now() + make_interval(secs => $3), updated_time = now()
+            where task_id = (
+                select task_id from {table}
+                where circuit = $4 and (status = $5 or status = $6) and attempts < $7
+                order by block_id limit 1
+                for update skip locked)
+            returning task_id, block_id, circuit, input::text as input, witness, attempts,
+                (extract(epoch from lease_deadline) * 1000)::bigint as lease_deadline",
+            table = tablenames::TASK
+        ))
+        .bind(TaskStatus::Assigned)
+        .bind(&request.prover_id)
+        .bind(self.settings.lease_secs as f64)
+        .bind(&request.circuit)
+        .bind(fetchable.0)
+        .bind(fetchable.1)
+        .bind(self.settings.max_attempts as i32)
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(db_error)?;
+
+        let task = match row {
+            Some(row) => row,
+            None => return Ok(FetchTaskResponse { task: None }),
+        };
+        let task = (|| -> Result<ProverTask, sqlx::Error> {
+            Ok(ProverTask {
+                task_id: task.try_get("task_id")?,
+                block_id: task.try_get("block_id")?,
+                circuit: task.try_get("circuit")?,
+                input: task.try_get("input")?,
+                witness: task.try_get::<Option<Vec<u8>>, _>("witness")?.unwrap_or_default(),
+                attempt: task.try_get::<i32, _>("attempts")? as u32,
+                lease_deadline: task.try_get("lease_deadline")?,
+            })
+        })()
+        .map_err(db_error)?;
+        let task = ProverTask {
+            input: storage::materialize_task_input(&self.db_pool, task.input).await.map_err(|e| {
+                log::error!("{:?}", e);
+                Status::new(Code::Internal, "task input error")
+            })?,
+            ..task
+        };
+        log::info!(
+            "lease task {} to prover {}, attempt {}",
+            task.task_id,
+            request.prover_id,
+            task.attempt
+        );
+        Ok(FetchTaskResponse { task: Some(task) })
+    }
+
+    async fn heartbeat(&self, request: HeartbeatRequest) -> Result<HeartbeatResponse, Status> {
+        // a lease which has expired but is not re-queued yet can still be extended
+        let lease_deadline: Option<i64> = sqlx::query_scalar(&format!(
+            "update {} set lease_deadline = now() + make_interval(secs => $1), updated_time = now()
+            where task_id = $2 and prover_id = $3 and status = $4
+            returning (extract(epoch from lease_deadline) * 1000)::bigint",
+            tablenames::TASK
+        ))
+        .bind(self.settings.lease_secs as f64)
+        .bind(&request.task_id)
+        .bind(&request.prover_id)
+        .bind(TaskStatus::Assigned)
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(db_error)?;
+        match lease_deadline {
+            Some(lease_deadline) => Ok(HeartbeatResponse { lease_deadline }),
+            None => Err(lease_lost(&request.task_id)),
+        }
+    }
+
+    async fn submit_proof(&self, request: SubmitProofRequest) -> Result<SubmitProofResponse, Status> {
+        let mut tx = self.db_pool.begin().await.map_err(db_error)?;
+        let block_id: Option<i64> = sqlx::query_scalar(&format!(
+            "update {} set status = $1, proof = $2, public_input = $3, lease_deadline = null, updated_time = now()
+            where task_id = $4 and prover_id = $5 and status = $6
+            returning block_id",
+            tablenames::TASK
+        ))
+        .bind(TaskStatus::Proved)
+        .bind(&request.proof)
+        .bind(&request.public_input)
+        .bind(&request.task_id)
+        .bind(&request.prover_id)
+        .bind(TaskStatus::Assigned)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(db_error)?;
+        let block_id = block_id.ok_or_else(|| lease_lost(&request.task_id))?;
+        sqlx::query(&format!(
+            "update {} set proved_time = now() where block_id = $1",
+            tablenames::L2_BLOCK
+        ))
+        .bind(block_id)
+        .execute(&mut tx)
+        .await
+        .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+
+        log::info!("block {} is proved by prover {}", block_id, request.prover_id);
+        Ok(SubmitProofResponse {})
+    }
+
+    async fn report_failure(&self, request: ReportFailureRequest) -> Result<ReportFailureResponse, Status> {
+        let attempts: Option<i32> = sqlx::query_scalar(&format!(
+            "update {} set status = {}, prover_id = null, lease_deadline = null, last_error = $3, updated_time = now()
+            where task_id = $4 and prover_id = $5 and status = $6
+            returning attempts",
+            tablenames::TASK,
+            REQUEUE_STATUS
+        ))
+        .bind(requeue_status(false))
+        .bind(requeue_status(true))
+        .bind(&request.error)
+        .bind(&request.task_id)
+        .bind(&request.prover_id)
+        .bind(TaskStatus::Assigned)
+        .fetch_optional(&self.db_pool)
+        .await
+        .map_err(db_error)?;
+        let attempts = attempts.ok_or_else(|| lease_lost(&request.task_id))?;
+
+        let requeued = has_attempts_left(attempts as u32, self.settings.max_attempts);
+        if requeued {
+            log::warn!("task {} failed on prover {}: {}", request.task_id, request.prover_id, request.error);
+        } else {
+            log::error!("task {} failed {} times, give up: {}", request.task_id, attempts, request.error);
+        }
+        Ok(ReportFailureResponse { requeued })
+    }
+}
+
+#[tonic::async_trait]
+impl ProverCoordinator for ProverHandler {
+    async fn fetch_task(&self, request: Request<FetchTaskRequest>) -> Result<Response<FetchTaskResponse>, Status> {
+        Ok(Response::new(ProverHandler::fetch_task(self, request.into_inner()).await?))
+    }
+
+    async fn heartbeat(&self, request: Request<HeartbeatRequest>) -> Result<Response<HeartbeatResponse>, Status> {
+        Ok(Response::new(ProverHandler::heartbeat(self, request.into_inner()).await?))
+    }
+
+    async fn submit_proof(&self, request: Request<SubmitProofRequest>) -> Result<Response<SubmitProofResponse>, Status> {
+        Ok(Response::new(ProverHandler::submit_proof(self, request.into_inner()).await?))
+    }
+
+    async fn report_failure(&self, request: Request<ReportFailureRequest>) -> Result<Response<ReportFailureResponse>, Status> {
+        Ok(Response::new(ProverHandler::report_failure(self, request.into_inner()).await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_test_settings;
+    use fluidex_common::db::MIGRATOR;
+
+    fn fetch(prover_id: &str, circuit: &str) -> FetchTaskRequest {
+        FetchTaskRequest {
+            prover_id: prover_id.to_string(),
+            circuit: circuit.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_requeue_status() {
+        assert!(matches!(requeue_status(false), TaskStatus::Inited));
+        assert!(matches!(requeue_status(true), TaskStatus::Ready));
+        // a re-queued task can be leased again
+        assert!(matches!(fetchable(false), (TaskStatus::Inited, TaskStatus::Ready)));
+        assert!(matches!(fetchable(true), (TaskStatus::Ready, TaskStatus::Ready)));
+    }
+
+    #[test]
+    fn test_attempts_left() {
+        // with 2 attempts, a task failed on its first lease is re-queued and given up on its second
+        assert!(has_attempts_left(0, 2));
+        assert!(has_attempts_left(1, 2));
+        assert!(!has_attempts_left(2, 2));
+        assert!(!has_attempts_left(1, 0));
+    }
+
+    // needs the postgres db of `Settings::db()`, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn test_task_lease() {
+        init_test_settings();
+        let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let db_pool = sqlx::postgres::PgPool::connect(Settings::db()).await.unwrap();
+            MIGRATOR.run(&db_pool).await.ok();
+            storage::migrate(&db_pool).await.unwrap();
+            // tasks of a circuit no one else proves
+            let circuit = format!("test_lease_{}", std::process::id());
+            for block_id in 0..2i64 {
+                sqlx::query(&format!(
+                    "insert into {} (task_id, circuit, block_id, input, status) values ($1, $2, $3, $4, $5)",
+                    tablenames::TASK
+                ))
+                .bind(format!("{}_{}", circuit, block_id))
+                .bind(&circuit)
+                .bind(block_id)
+                .bind(sqlx::types::Json(serde_json::json!({})))
+                .bind(TaskStatus::Inited)
+                .execute(&db_pool)
+                .await
+                .unwrap();
+            }
+            let settings = Box::leak(Box::new(ProverSettings {
+                lease_secs: 60,
+                max_attempts: 2,
+                require_witness: false,
+            }));
+            let handler = ProverHandler::with_pool(db_pool.clone(), settings);
+
+            // concurrent fetches never lease the same task, the older block goes first
+            let (a, b) = futures::join!(handler.fetch_task(fetch("a", &circuit)), handler.fetch_task(fetch("b", &circuit)));
+            let (a, b) = (a.unwrap().task.unwrap(), b.unwrap().task.unwrap());
+            assert_ne!(a.task_id, b.task_id);
+            assert_eq!(a.block_id.min(b.block_id), 0);
+            assert_eq!((a.attempt, b.attempt), (1, 1));
+            assert!(handler.fetch_task(fetch("c", &circuit)).await.unwrap().task.is_none());
+
+            // only the prover holding the lease can keep or end it
+            let err = handler
+                .heartbeat(HeartbeatRequest {
+                    prover_id: "b".to_string(),
+                    task_id: a.task_id.clone(),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::FailedPrecondition);
+
+            // a failed task is re-queued while it has attempts left
+            let failure = handler
+                .report_failure(ReportFailureRequest {
+                    prover_id: "a".to_string(),
+                    task_id: a.task_id.clone(),
+                    error: "out of memory".to_string(),
+                })
+                .await
+                .unwrap();
+            assert!(failure.requeued);
+
+            // an expired lease is re-queued on the next fetch
+            sqlx::query(&format!(
+                "update {} set lease_deadline = now() - interval '1 second' where task_id = $1",
+                tablenames::TASK
+            ))
+            .bind(&b.task_id)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+            let a2 = handler.fetch_task(fetch("c", &circuit)).await.unwrap().task.unwrap();
+            let b2 = handler.fetch_task(fetch("d", &circuit)).await.unwrap().task.unwrap();
+            assert_eq!((a2.block_id, b2.block_id), (0, 1));
+            assert_eq!((a2.attempt, b2.attempt), (2, 2));
+            // the expired prover lost its lease
+            let err = handler
+                .submit_proof(SubmitProofRequest {
+                    prover_id: "b".to_string(),
+                    task_id: b.task_id.clone(),
+                    proof: vec![1],
+                    public_input: vec![2],
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(err.code(), Code::FailedPrecondition);
+
+            // no task is handed out after `max_attempts` leases
+            let failure = handler
+                .report_failure(ReportFailureRequest {
+                    prover_id: "c".to_string(),
+                    task_id: a2.task_id.clone(),
+                    error: "out of memory".to_string(),
+                })
+                .await
+                .unwrap();
+            assert!(!failure.requeued);
+            assert!(handler.fetch_task(fetch("e", &circuit)).await.unwrap().task.is_none());
+
+            // the prover holding the lease ends it with a proof
+            handler
+                .submit_proof(SubmitProofRequest {
+                    prover_id: "d".to_string(),
+                    task_id: b2.task_id.clone(),
+                    proof: vec![1],
+                    public_input: vec![2],
+                })
+                .await
+                .unwrap();
+
+            sqlx::query(&format!("delete from {} where circuit = $1", tablenames::TASK))
+                .bind(&circuit)
+                .execute(&db_pool)
+                .await
+                .unwrap();
+        });
+    }
+}
@@ -0,0 +1,216 @@
+// A REST/JSON mirror of the query RPCs under `/api/explorer`, for web frontends without a grpc-web proxy.
+// The handlers are the same `Controller` methods as the grpc service, and a grpc error code is mapped to
+// the closest http status, with `{"code", "message"}` in the body.
+use crate::grpc::controller::Controller;
+use crate::grpc::proto::explorer::{AccountInfoQueryRequest, AccountTxHistoryQueryRequest, TxStatusQueryRequest};
+use crate::grpc::view::{AccountInfoView, AccountTxHistoryView, L2BlockView, L2BlocksView, TokenBalanceView, TxStatusView};
+use crate::shutdown::Shutdown;
+use crate::state::GlobalState;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, StatusCode, Uri};
+use orchestra::rpc::rollup::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tonic::{Code, Status};
+
+const PREFIX: &str = "/api/explorer";
+
+#[derive(Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    offset: i64,
+    #[serde(default)]
+    limit: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token_id: Option<u32>,
+    token_address: Option<String>,
+    token_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AccountTxsQuery {
+    tx_type: Option<String>,
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+    #[serde(default)]
+    offset: i64,
+    #[serde(default)]
+    limit: i64,
+}
+
+#[derive(Deserialize)]
+struct TxStatusQuery {
+    source_id: Option<String>,
+    topic: Option<String>,
+    partition: Option<i32>,
+    offset: Option<i64>,
+}
+
+fn parse_query<T: DeserializeOwned>(query: &str) -> Result<T, Status> {
+    serde_urlencoded::from_str(query).map_err(|e| Status::new(Code::InvalidArgument, e.to_string()))
+}
+
+fn parse_param<T: FromStr>(param: &str) -> Result<T, Status> {
+    param
+        .parse()
+        .map_err(|_| Status::new(Code::InvalidArgument, format!("invalid path parameter {}", param)))
+}
+
+// see `grpc::view` for the JSON of each response
+fn to_json<V: Serialize>(view: V) -> Result<String, Status> {
+    serde_json::to_string(&view).map_err(|e| Status::new(Code::Internal, e.to_string()))
+}
+
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::InvalidArgument | Code::OutOfRange => StatusCode::BAD_REQUEST,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::FailedPrecondition => StatusCode::PRECONDITION_FAILED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn respond(result: Result<String, Status>) -> Response<Body> {
+    let (status, body) = match result {
+        Ok(body) => (StatusCode::OK, body),
+        Err(e) => (
+            http_status(e.code()),
+            serde_json::json!({"code": e.code() as i32, "message": e.message()}).to_string(),
+        ),
+    };
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn route(controller: &Controller, method: &Method, uri: &Uri) -> Result<String, Status> {
+    if method != Method::GET {
+        return Err(Status::new(Code::Unimplemented, "only GET is supported"));
+    }
+    let not_found = || Status::new(Code::NotFound, format!("no route for {}", uri.path()));
+    let path = uri.path().strip_prefix(PREFIX).ok_or_else(not_found)?;
+    let query = uri.query().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["l2_blocks"] => {
+            let q: PageQuery = parse_query(query)?;
+            let request = L2BlocksQueryRequest {
+                offset: q.offset,
+                limit: q.limit,
+            };
+            to_json(L2BlocksView::from(controller.l2_blocks_query(request).await?))
+        }
+        ["l2_block", block_id] => {
+            let request = L2BlockQueryRequest {
+                block_id: parse_param(block_id)?,
+            };
+            to_json(L2BlockView::from(controller.l2_block_query(request).await?))
+        }
+        ["token_balance", account_id] => {
+            let q: TokenQuery = parse_query(query)?;
+            let request = TokenBalanceQueryRequest {
+                account_id: parse_param(account_id)?,
+                token_id: q.token_id,
+                token_address: q.token_address,
+                token_name: q.token_name,
+            };
+            to_json(TokenBalanceView::from(controller.token_balance_query(request)?))
+        }
+        ["account", account_id] => {
+            let request = AccountInfoQueryRequest {
+                account_id: parse_param(account_id)?,
+            };
+            to_json(AccountInfoView::from(controller.account_info_query(request)?))
+        }
+        ["account_txs", account_id] => {
+            let q: AccountTxsQuery = parse_query(query)?;
+            let request = AccountTxHistoryQueryRequest {
+                account_id: parse_param(account_id)?,
+                tx_type: q.tx_type,
+                start_time: q.start_time,
+                end_time: q.end_time,
+                offset: q.offset,
+                limit: q.limit,
+            };
+            to_json(AccountTxHistoryView::from(controller.account_tx_history_query(request).await?))
+        }
+        ["tx_status"] => {
+            let q: TxStatusQuery = parse_query(query)?;
+            let request = TxStatusQueryRequest {
+                source_id: q.source_id,
+                topic: q.topic,
+                partition: q.partition,
+                offset: q.offset,
+            };
+            to_json(TxStatusView::from(controller.tx_status_query(request).await?))
+        }
+        _ => Err(not_found()),
+    }
+}
+
+// serves until `shutdown` is triggered
+pub fn run_rest_server(addr: SocketAddr, state: Arc<RwLock<GlobalState>>, shutdown: Shutdown) -> anyhow::Result<()> {
+    let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Build runtime");
+
+    rt.block_on(async {
+        let controller = Controller::new(state).await;
+        let make_svc = make_service_fn(move |_| {
+            let controller = controller.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let controller = controller.clone();
+                    async move { Ok::<_, Infallible>(respond(route(&controller, req.method(), req.uri()).await)) }
+                }))
+            }
+        });
+        log::info!("serving rest api on {}{}", addr, PREFIX);
+        hyper::Server::try_bind(&addr)?
+            .serve(make_svc)
+            .with_graceful_shutdown(async move {
+                shutdown.wait().await;
+                log::info!("stopping rest server");
+            })
+            .await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let q: TxStatusQuery = parse_query("source_id=trade%3A12&partition=0").unwrap();
+        assert_eq!(q.source_id.as_deref(), Some("trade:12"));
+        assert_eq!((q.topic, q.partition, q.offset), (None, Some(0), None));
+        let q: PageQuery = parse_query("").unwrap();
+        assert_eq!((q.offset, q.limit), (0, 0));
+        assert_eq!(parse_query::<PageQuery>("limit=ten").unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(parse_param::<u32>("-1").unwrap_err().code(), Code::InvalidArgument);
+
+        let response = respond(Err(Status::new(Code::NotFound, "db l2_block record not found")));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
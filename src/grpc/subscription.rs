@@ -0,0 +1,207 @@
+// Push updates of saved blocks for `SubscribeBlocks`. The db writer notifies the id of every block it saves,
+// and each subscriber reads the blocks from db in order. So a notification is only a hint to read the db,
+// and a subscriber which resumes from an old block, or lags behind the notifications, backfills from db.
+use crate::grpc::controller::Controller;
+use crate::grpc::proto::explorer::{BlockUpdate, SubscribeBlocksRequest};
+use crate::shutdown::Shutdown;
+use futures::Stream;
+use std::pin::Pin;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tonic::Status;
+
+const NOTIFY_CAPACITY: usize = 64;
+// blocks read from db at a time while backfilling
+const BACKFILL_BATCH: i64 = 100;
+// updates buffered for a slow client, the backfill waits for it beyond this
+const STREAM_CAPACITY: usize = 16;
+
+pub type BlockStream = Pin<Box<dyn Stream<Item = Result<BlockUpdate, Status>> + Send>>;
+
+#[derive(Clone)]
+pub struct BlockNotifier {
+    sender: broadcast::Sender<i64>,
+}
+
+impl Default for BlockNotifier {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(NOTIFY_CAPACITY);
+        Self { sender }
+    }
+}
+
+impl BlockNotifier {
+    // called after the block is committed to db
+    pub fn notify(&self, block_id: usize) {
+        // fails only if there is no subscriber
+        self.sender.send(block_id as i64).ok();
+    }
+}
+
+/// Where the saved blocks are read from, the db for the server.
+#[tonic::async_trait]
+pub trait SavedBlocks: Send + Sync + 'static {
+    // updates of the blocks from `from_block_id` on, in order, along with their ids
+    async fn block_updates(&self, from_block_id: i64, limit: i64, with_txs: bool) -> Result<Vec<(i64, BlockUpdate)>, Status>;
+}
+
+#[tonic::async_trait]
+impl SavedBlocks for Controller {
+    async fn block_updates(&self, from_block_id: i64, limit: i64, with_txs: bool) -> Result<Vec<(i64, BlockUpdate)>, Status> {
+        Controller::block_updates(self, from_block_id, limit, with_txs).await
+    }
+}
+
+// the stream ends when the client goes away or the server shuts down
+pub fn subscribe_blocks<S: SavedBlocks>(
+    blocks: S,
+    notifier: &BlockNotifier,
+    request: SubscribeBlocksRequest,
+    shutdown: Shutdown,
+) -> BlockStream {
+    let (sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+    // subscribed before backfilling, so no block saved in between is missed
+    let notifications = notifier.sender.subscribe();
+    tokio::spawn(async move {
+        tokio::select! {
+            result = push_blocks(&blocks, notifications, &request, &sender) => {
+                if let Err(status) = result {
+                    sender.send(Err(status)).await.ok();
+                }
+            }
+            _ = sender.closed() => {}
+            _ = shutdown.wait() => {}
+        }
+    });
+    Box::pin(futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    }))
+}
+
+// returns Ok when there is nothing more to push
+async fn push_blocks<S: SavedBlocks>(
+    blocks: &S,
+    mut notifications: broadcast::Receiver<i64>,
+    request: &SubscribeBlocksRequest,
+    sender: &mpsc::Sender<Result<BlockUpdate, Status>>,
+) -> Result<(), Status> {
+    // without `from_block_id`, starts from the first block saved after subscribing
+    let mut next_block_id = request.from_block_id;
+    loop {
+        while let Some(from) = next_block_id {
+            let updates = blocks.block_updates(from, BACKFILL_BATCH, request.with_txs).await?;
+            let num = updates.len() as i64;
+            for (block_id, update) in updates {
+                if sender.send(Ok(update)).await.is_err() {
+                    return Ok(());
+                }
+                next_block_id = Some(block_id + 1);
+            }
+            if num < BACKFILL_BATCH {
+                break;
+            }
+        }
+
+        loop {
+            match notifications.recv().await {
+                Ok(block_id) => match next_block_id {
+                    Some(next) if block_id < next => continue,
+                    Some(_) => break,
+                    None => {
+                        next_block_id = Some(block_id);
+                        break;
+                    }
+                },
+                // the missed blocks are read from db anyway
+                Err(RecvError::Lagged(missed)) => {
+                    log::debug!("block subscriber lags behind {} notifications", missed);
+                    if next_block_id.is_some() {
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use orchestra::rpc::rollup::l2_blocks_query_response::BlockSummary;
+    use std::sync::{Arc, Mutex};
+
+    // saved block ids, from 0
+    #[derive(Clone, Default)]
+    struct MemBlocks(Arc<Mutex<i64>>);
+
+    impl MemBlocks {
+        fn save(&self, notifier: Option<&BlockNotifier>) {
+            let block_id = {
+                let mut saved = self.0.lock().unwrap();
+                *saved += 1;
+                *saved - 1
+            };
+            if let Some(notifier) = notifier {
+                notifier.notify(block_id as usize);
+            }
+        }
+    }
+
+    #[tonic::async_trait]
+    impl SavedBlocks for MemBlocks {
+        async fn block_updates(&self, from_block_id: i64, limit: i64, _with_txs: bool) -> Result<Vec<(i64, BlockUpdate)>, Status> {
+            let saved = *self.0.lock().unwrap();
+            Ok((from_block_id..saved.min(from_block_id + limit))
+                .map(|block_id| {
+                    let block = BlockSummary {
+                        block_height: block_id,
+                        ..Default::default()
+                    };
+                    (
+                        block_id,
+                        BlockUpdate {
+                            block: Some(block),
+                            detail: None,
+                        },
+                    )
+                })
+                .collect())
+        }
+    }
+
+    fn next_block_id(stream: &mut BlockStream) -> Option<i64> {
+        let update = futures::executor::block_on(stream.next())?.unwrap();
+        Some(update.block.unwrap().block_height)
+    }
+
+    #[test]
+    fn test_backfill_then_live() {
+        let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        let _guard = rt.enter();
+        let (blocks, notifier, shutdown) = (MemBlocks::default(), BlockNotifier::default(), Shutdown::default());
+        for _ in 0..3 {
+            blocks.save(None);
+        }
+        let request = SubscribeBlocksRequest {
+            from_block_id: Some(1),
+            with_txs: false,
+        };
+        let mut stream = subscribe_blocks(blocks.clone(), &notifier, request, shutdown.clone());
+        // backfilled from the saved blocks
+        assert_eq!(next_block_id(&mut stream), Some(1));
+        assert_eq!(next_block_id(&mut stream), Some(2));
+        // then pushed as they are saved
+        blocks.save(Some(&notifier));
+        assert_eq!(next_block_id(&mut stream), Some(3));
+        // a missed notification is read from the saved blocks too
+        blocks.save(None);
+        blocks.save(Some(&notifier));
+        assert_eq!(next_block_id(&mut stream), Some(4));
+        assert_eq!(next_block_id(&mut stream), Some(5));
+
+        shutdown.trigger();
+        assert_eq!(next_block_id(&mut stream), None);
+    }
+}
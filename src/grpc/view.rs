@@ -0,0 +1,392 @@
+// The JSON bodies of the REST api, built from the grpc responses. Like the proto3 JSON mapping,
+// 64-bit integers are strings and unset fields are omitted, while fields keep their proto names.
+// Unlike it, block statuses and tx types are sent by the lowercase names they have in the db,
+// which are also what the `tx_type` query takes, e.g. "verified" and "spot_trade".
+// A value without a name is sent as its number.
+use crate::grpc::proto::explorer::{AccountInfoQueryResponse, AccountTxHistoryQueryResponse, TxStatusQueryResponse};
+use crate::types::l2::TxType;
+use orchestra::rpc::rollup::*;
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum EnumView {
+    Name(&'static str),
+    Number(i32),
+}
+
+impl EnumView {
+    fn block_status(status: i32) -> Self {
+        match BlockStatus::from_i32(status) {
+            Some(BlockStatus::Uncommited) => EnumView::Name("uncommited"),
+            Some(BlockStatus::Commited) => EnumView::Name("commited"),
+            Some(BlockStatus::Verified) => EnumView::Name("verified"),
+            None => EnumView::Number(status),
+        }
+    }
+
+    fn tx_type(tx_type: i32) -> Self {
+        match TxType::ALL.iter().find(|t| **t as i32 == tx_type) {
+            Some(t) => EnumView::Name(t.as_str()),
+            None => EnumView::Number(tx_type),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct L2BlocksView {
+    total: String,
+    blocks: Vec<BlockSummaryView>,
+}
+
+#[derive(Serialize)]
+struct BlockSummaryView {
+    block_height: String,
+    merkle_root: String,
+    block_time: String,
+}
+
+impl From<L2BlocksQueryResponse> for L2BlocksView {
+    fn from(response: L2BlocksQueryResponse) -> Self {
+        Self {
+            total: response.total.to_string(),
+            blocks: response
+                .blocks
+                .into_iter()
+                .map(|b| BlockSummaryView {
+                    block_height: b.block_height.to_string(),
+                    merkle_root: b.merkle_root,
+                    block_time: b.block_time.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct L2BlockView {
+    tx_num: String,
+    real_tx_num: String,
+    created_time: String,
+    status: EnumView,
+    new_root: String,
+    l1_tx_hash: String,
+    txs: Vec<EncodedTxView>,
+    decoded_txs: Vec<DecodedTxView>,
+    txs_type: Vec<EnumView>,
+}
+
+#[derive(Serialize)]
+struct EncodedTxView {
+    detail: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DecodedTxView {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deposit_tx: Option<BalanceTxView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    withdraw_tx: Option<BalanceTxView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transfer_tx: Option<TransferTxView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spot_trade_tx: Option<SpotTradeTxView>,
+}
+
+// a deposit or a withdraw
+#[derive(Serialize)]
+struct BalanceTxView {
+    account_id: u32,
+    token_id: u32,
+    amount: String,
+    old_balance: String,
+    new_balance: String,
+}
+
+#[derive(Serialize)]
+struct TransferTxView {
+    from: u32,
+    to: u32,
+    token_id: u32,
+    amount: String,
+    from_old_balance: String,
+    from_new_balance: String,
+    to_old_balance: String,
+    to_new_balance: String,
+}
+
+#[derive(Serialize)]
+struct SpotTradeTxView {
+    order1_account_id: u32,
+    order2_account_id: u32,
+    token_id_1to2: u32,
+    token_id_2to1: u32,
+    amount_1to2: String,
+    amount_2to1: String,
+    account1_token_buy_new_balance: String,
+    account1_token_buy_old_balance: String,
+    account1_token_sell_new_balance: String,
+    account1_token_sell_old_balance: String,
+    account2_token_buy_new_balance: String,
+    account2_token_buy_old_balance: String,
+    account2_token_sell_new_balance: String,
+    account2_token_sell_old_balance: String,
+}
+
+impl From<L2BlockQueryResponse> for L2BlockView {
+    fn from(response: L2BlockQueryResponse) -> Self {
+        Self {
+            tx_num: response.tx_num.to_string(),
+            real_tx_num: response.real_tx_num.to_string(),
+            created_time: response.created_time.to_string(),
+            status: EnumView::block_status(response.status),
+            new_root: response.new_root,
+            l1_tx_hash: response.l1_tx_hash,
+            txs: response.txs.into_iter().map(|tx| EncodedTxView { detail: tx.detail }).collect(),
+            decoded_txs: response.decoded_txs.into_iter().map(DecodedTxView::from).collect(),
+            txs_type: response.txs_type.into_iter().map(EnumView::tx_type).collect(),
+        }
+    }
+}
+
+impl From<l2_block_query_response::DecodedTx> for DecodedTxView {
+    fn from(tx: l2_block_query_response::DecodedTx) -> Self {
+        Self {
+            deposit_tx: tx.deposit_tx.map(|tx| BalanceTxView {
+                account_id: tx.account_id,
+                token_id: tx.token_id,
+                amount: tx.amount,
+                old_balance: tx.old_balance,
+                new_balance: tx.new_balance,
+            }),
+            withdraw_tx: tx.withdraw_tx.map(|tx| BalanceTxView {
+                account_id: tx.account_id,
+                token_id: tx.token_id,
+                amount: tx.amount,
+                old_balance: tx.old_balance,
+                new_balance: tx.new_balance,
+            }),
+            transfer_tx: tx.transfer_tx.map(|tx| TransferTxView {
+                from: tx.from,
+                to: tx.to,
+                token_id: tx.token_id,
+                amount: tx.amount,
+                from_old_balance: tx.from_old_balance,
+                from_new_balance: tx.from_new_balance,
+                to_old_balance: tx.to_old_balance,
+                to_new_balance: tx.to_new_balance,
+            }),
+            spot_trade_tx: tx.spot_trade_tx.map(|tx| SpotTradeTxView {
+                order1_account_id: tx.order1_account_id,
+                order2_account_id: tx.order2_account_id,
+                token_id_1to2: tx.token_id_1to2,
+                token_id_2to1: tx.token_id_2to1,
+                amount_1to2: tx.amount_1to2,
+                amount_2to1: tx.amount_2to1,
+                account1_token_buy_new_balance: tx.account1_token_buy_new_balance,
+                account1_token_buy_old_balance: tx.account1_token_buy_old_balance,
+                account1_token_sell_new_balance: tx.account1_token_sell_new_balance,
+                account1_token_sell_old_balance: tx.account1_token_sell_old_balance,
+                account2_token_buy_new_balance: tx.account2_token_buy_new_balance,
+                account2_token_buy_old_balance: tx.account2_token_buy_old_balance,
+                account2_token_sell_new_balance: tx.account2_token_sell_new_balance,
+                account2_token_sell_old_balance: tx.account2_token_sell_old_balance,
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TokenBalanceView {
+    balance: String,
+    balance_raw: String,
+    precision: u32,
+}
+
+impl From<TokenBalanceQueryResponse> for TokenBalanceView {
+    fn from(response: TokenBalanceQueryResponse) -> Self {
+        Self {
+            balance: response.balance,
+            balance_raw: response.balance_raw,
+            precision: response.precision,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountInfoView {
+    has_account: bool,
+    nonce: String,
+    sign: String,
+    ay: String,
+    balance_root: String,
+    order_root: String,
+    balances: Vec<AccountBalanceView>,
+}
+
+#[derive(Serialize)]
+struct AccountBalanceView {
+    token_id: u32,
+    balance: String,
+    balance_raw: String,
+    precision: u32,
+}
+
+impl From<AccountInfoQueryResponse> for AccountInfoView {
+    fn from(response: AccountInfoQueryResponse) -> Self {
+        Self {
+            has_account: response.has_account,
+            nonce: response.nonce,
+            sign: response.sign,
+            ay: response.ay,
+            balance_root: response.balance_root,
+            order_root: response.order_root,
+            balances: response
+                .balances
+                .into_iter()
+                .map(|b| AccountBalanceView {
+                    token_id: b.token_id,
+                    balance: b.balance,
+                    balance_raw: b.balance_raw,
+                    precision: b.precision,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TxStatusView {
+    receipts: Vec<TxReceiptView>,
+}
+
+#[derive(Serialize)]
+struct TxReceiptView {
+    block_id: String,
+    tx_index: u32,
+    tx_type: EnumView,
+    topic: String,
+    partition: i32,
+    offset: String,
+    source_id: String,
+    root_before: String,
+    root_after: String,
+    block_status: EnumView,
+    l1_tx_hash: String,
+}
+
+impl From<TxStatusQueryResponse> for TxStatusView {
+    fn from(response: TxStatusQueryResponse) -> Self {
+        Self {
+            receipts: response
+                .receipts
+                .into_iter()
+                .map(|r| TxReceiptView {
+                    block_id: r.block_id.to_string(),
+                    tx_index: r.tx_index,
+                    tx_type: EnumView::tx_type(r.tx_type),
+                    topic: r.topic,
+                    partition: r.partition,
+                    offset: r.offset.to_string(),
+                    source_id: r.source_id,
+                    root_before: r.root_before,
+                    root_after: r.root_after,
+                    block_status: EnumView::block_status(r.block_status),
+                    l1_tx_hash: r.l1_tx_hash,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct AccountTxHistoryView {
+    total: String,
+    txs: Vec<L2TxView>,
+}
+
+#[derive(Serialize)]
+struct L2TxView {
+    block_id: String,
+    tx_index: u32,
+    tx_type: EnumView,
+    created_time: String,
+    from_account: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_account: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_id: Option<u32>,
+    amount: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_token_id: Option<u32>,
+    to_amount: String,
+    from_balance: String,
+    from_balance_to_token: String,
+    to_balance: String,
+    to_balance_to_token: String,
+}
+
+impl From<AccountTxHistoryQueryResponse> for AccountTxHistoryView {
+    fn from(response: AccountTxHistoryQueryResponse) -> Self {
+        Self {
+            total: response.total.to_string(),
+            txs: response
+                .txs
+                .into_iter()
+                .map(|tx| L2TxView {
+                    block_id: tx.block_id.to_string(),
+                    tx_index: tx.tx_index,
+                    tx_type: EnumView::tx_type(tx.tx_type),
+                    created_time: tx.created_time.to_string(),
+                    from_account: tx.from_account,
+                    to_account: tx.to_account,
+                    token_id: tx.token_id,
+                    amount: tx.amount,
+                    to_token_id: tx.to_token_id,
+                    to_amount: tx.to_amount,
+                    from_balance: tx.from_balance,
+                    from_balance_to_token: tx.from_balance_to_token,
+                    to_balance: tx.to_balance,
+                    to_balance_to_token: tx.to_balance_to_token,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::proto::explorer::account_tx_history_query_response;
+
+    #[test]
+    fn test_int64_as_string() {
+        let response = AccountTxHistoryQueryResponse {
+            total: 1,
+            txs: vec![account_tx_history_query_response::L2Tx {
+                block_id: 2,
+                tx_type: 2,
+                created_time: 1_600_000_000_000,
+                from_account: 1,
+                to_account: Some(3),
+                amount: "0.0030".to_owned(),
+                ..Default::default()
+            }],
+        };
+        let json = serde_json::to_value(AccountTxHistoryView::from(response)).unwrap();
+        assert_eq!(json["total"], "1");
+        assert_eq!(json["txs"][0]["block_id"], "2");
+        assert_eq!(json["txs"][0]["created_time"], "1600000000000");
+        assert_eq!(json["txs"][0]["to_account"], 3);
+        assert!(json["txs"][0].get("token_id").is_none());
+        assert_eq!(json["txs"][0]["tx_type"], "transfer");
+    }
+
+    #[test]
+    fn test_enum_names() {
+        assert_eq!(EnumView::block_status(BlockStatus::Verified as i32), EnumView::Name("verified"));
+        assert_eq!(EnumView::tx_type(TxType::SpotTrade as i32), EnumView::Name("spot_trade"));
+        // unknown values keep their numbers
+        assert_eq!(serde_json::to_value(EnumView::tx_type(100)).unwrap(), 100);
+    }
+}
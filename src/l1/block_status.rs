@@ -0,0 +1,458 @@
+// Tracks the L2 blocks committed and verified on L1, by the events of the rollup contract.
+// keep in sync with the rollup contract:
+//   event BlockCommit(uint32 blockId)
+//   event BlockVerification(uint32 blockId)
+// L1 blocks scanned within `reorg_depth` are remembered with their hashes. When one of them is replaced by a reorg,
+// the events in it are reverted, and the blocks after it are scanned again.
+// The remembered blocks are saved with the status updates, so a restart resumes after them and still reverts
+// a reorg which happens while stopped.
+use crate::config::L1WatcherSettings;
+use crate::storage::L1_SCANNED_BLOCK;
+use anyhow::{anyhow, Context, Result};
+use ethers::abi::{self, ParamType};
+use ethers::core::types::{Address, BlockNumber, Filter, Log, H256};
+use ethers::core::utils::keccak256;
+use ethers::providers::{Http, Middleware, Provider};
+use fluidex_common::db::models::{l2_block, tablenames};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+pub const BLOCK_COMMIT_EVENT: &str = "BlockCommit(uint32)";
+pub const BLOCK_VERIFICATION_EVENT: &str = "BlockVerification(uint32)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlockEventKind {
+    Commit,
+    Verify,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockEvent {
+    pub kind: BlockEventKind,
+    pub block_id: i64,
+    pub tx_hash: H256,
+    pub block_number: u64,
+    pub log_index: u64,
+}
+
+impl BlockEvent {
+    fn from_log(log: &Log, commit_topic: H256) -> Result<Self> {
+        let kind = if log.topics.first() == Some(&commit_topic) {
+            BlockEventKind::Commit
+        } else {
+            BlockEventKind::Verify
+        };
+        let tokens = abi::decode(&[ParamType::Uint(32)], &log.data)?;
+        let block_id = tokens[0]
+            .clone()
+            .into_uint()
+            .ok_or_else(|| anyhow!("invalid block log {:?}", log))?;
+        Ok(Self {
+            kind,
+            block_id: block_id.as_u64() as i64,
+            tx_hash: log.transaction_hash.ok_or_else(|| anyhow!("pending block log"))?,
+            block_number: log.block_number.ok_or_else(|| anyhow!("pending block log"))?.as_u64(),
+            log_index: log.log_index.ok_or_else(|| anyhow!("pending block log"))?.as_u64(),
+        })
+    }
+}
+
+/// The status of an L2 block to be saved, after an event or after the revert of one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusUpdate {
+    pub block_id: i64,
+    pub status: l2_block::BlockStatus,
+    // `None` keeps the saved hash, unless the status is reverted to `Uncommited`
+    pub l1_tx_hash: Option<H256>,
+}
+
+#[tonic::async_trait]
+pub trait L1BlockSource: Send + Sync {
+    async fn latest_block(&self) -> Result<u64>;
+    // `None` if the chain is not that long (any more)
+    async fn block_hash(&self, number: u64) -> Result<Option<H256>>;
+    // events in blocks `from..=to`
+    async fn block_events(&self, from: u64, to: u64) -> Result<Vec<BlockEvent>>;
+}
+
+pub struct EthersBlockSource {
+    provider: Provider<Http>,
+    contract: Address,
+}
+
+impl EthersBlockSource {
+    pub fn new(rpc_url: &str, contract: &str) -> Result<Self> {
+        Ok(Self {
+            provider: Provider::<Http>::try_from(rpc_url)?,
+            contract: contract.parse().with_context(|| format!("invalid contract address {}", contract))?,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl L1BlockSource for EthersBlockSource {
+    async fn latest_block(&self) -> Result<u64> {
+        Ok(self.provider.get_block_number().await?.as_u64())
+    }
+
+    async fn block_hash(&self, number: u64) -> Result<Option<H256>> {
+        let block = self.provider.get_block(BlockNumber::Number(number.into())).await?;
+        Ok(block.and_then(|b| b.hash))
+    }
+
+    async fn block_events(&self, from: u64, to: u64) -> Result<Vec<BlockEvent>> {
+        let commit_topic = H256::from(keccak256(BLOCK_COMMIT_EVENT));
+        let verify_topic = H256::from(keccak256(BLOCK_VERIFICATION_EVENT));
+        let filter = Filter::new()
+            .address(self.contract)
+            .topic0(vec![commit_topic, verify_topic])
+            .from_block(from)
+            .to_block(to);
+        let logs = self.provider.get_logs(&filter).await?;
+        logs.iter().map(|log| BlockEvent::from_log(log, commit_topic)).collect()
+    }
+}
+
+/// A chain scripted by tests: blocks are mined with the given events, and the latest blocks can be
+/// replaced to simulate a reorg. Clones share the same chain.
+#[derive(Clone, Default)]
+pub struct ScriptedBlockSource {
+    // block hashes and events, by block number from 0
+    chain: Arc<Mutex<Vec<(H256, Vec<BlockEvent>)>>>,
+    // makes every mined block hash different, even after a reorg
+    mined: Arc<Mutex<u64>>,
+}
+
+impl ScriptedBlockSource {
+    // mines a block with events of `(kind, l2 block id)`, returns its number
+    pub fn mine(&self, events: &[(BlockEventKind, i64)]) -> u64 {
+        let mut chain = self.chain.lock().unwrap();
+        let mut mined = self.mined.lock().unwrap();
+        *mined += 1;
+        let number = chain.len() as u64;
+        let events = events
+            .iter()
+            .enumerate()
+            .map(|(idx, &(kind, block_id))| BlockEvent {
+                kind,
+                block_id,
+                tx_hash: H256::from_low_u64_be(*mined * 1000 + idx as u64),
+                block_number: number,
+                log_index: idx as u64,
+            })
+            .collect();
+        chain.push((H256::from_low_u64_be(*mined), events));
+        number
+    }
+
+    // drops the latest `depth` blocks, new blocks mined after it replace them
+    pub fn reorg(&self, depth: usize) {
+        let mut chain = self.chain.lock().unwrap();
+        let len = chain.len();
+        chain.truncate(len - depth.min(len));
+    }
+}
+
+#[tonic::async_trait]
+impl L1BlockSource for ScriptedBlockSource {
+    async fn latest_block(&self) -> Result<u64> {
+        Ok((self.chain.lock().unwrap().len() as u64).saturating_sub(1))
+    }
+
+    async fn block_hash(&self, number: u64) -> Result<Option<H256>> {
+        Ok(self.chain.lock().unwrap().get(number as usize).map(|(hash, _)| *hash))
+    }
+
+    async fn block_events(&self, from: u64, to: u64) -> Result<Vec<BlockEvent>> {
+        let chain = self.chain.lock().unwrap();
+        Ok(chain
+            .iter()
+            .skip(from as usize)
+            .take((to + 1).saturating_sub(from) as usize)
+            .flat_map(|(_, events)| events.clone())
+            .collect())
+    }
+}
+
+pub fn source_from_settings(settings: &L1WatcherSettings) -> Result<Box<dyn L1BlockSource>> {
+    Ok(Box::new(EthersBlockSource::new(&settings.rpc_url, &settings.contract_address)?))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScannedBlock {
+    pub number: u64,
+    pub hash: H256,
+    pub events: Vec<BlockEvent>,
+}
+
+/// Polls a [`L1BlockSource`] and turns the events into [`StatusUpdate`]s, reverting those of reorged blocks.
+pub struct BlockStatusWatcher {
+    source: Box<dyn L1BlockSource>,
+    reorg_depth: usize,
+    max_scan_blocks: u64,
+    next_block: u64,
+    // the latest L1 block seen by the last poll
+    latest_block: u64,
+    // the latest scanned blocks, at most `reorg_depth` but at least the last one
+    recent: VecDeque<ScannedBlock>,
+    latest_verified: Option<i64>,
+}
+
+impl BlockStatusWatcher {
+    // resumes after the `scanned` blocks saved before, if any
+    pub fn new(
+        source: Box<dyn L1BlockSource>,
+        settings: &L1WatcherSettings,
+        latest_verified: Option<i64>,
+        scanned: Vec<ScannedBlock>,
+    ) -> Self {
+        let next_block = scanned.last().map_or(settings.from_block, |block| block.number + 1);
+        Self {
+            source,
+            reorg_depth: settings.reorg_depth,
+            max_scan_blocks: settings.max_scan_blocks.max(1),
+            next_block,
+            latest_block: 0,
+            recent: scanned.into(),
+            latest_verified,
+        }
+    }
+
+    // the latest L2 block verified on L1
+    pub fn latest_verified(&self) -> Option<i64> {
+        self.latest_verified
+    }
+
+    // the remembered blocks, to be saved with the updates of the last poll
+    pub fn scanned(&self) -> impl Iterator<Item = &ScannedBlock> {
+        self.recent.iter()
+    }
+
+    // whether there are more blocks to scan right away
+    pub fn is_behind(&self) -> bool {
+        self.next_block <= self.latest_block
+    }
+
+    async fn revert_reorged(&mut self, updates: &mut Vec<StatusUpdate>) -> Result<()> {
+        while let Some(block) = self.recent.back() {
+            if self.source.block_hash(block.number).await? == Some(block.hash) {
+                break;
+            }
+            let block = self.recent.pop_back().unwrap();
+            log::warn!("L1 block {} {:?} is reorged", block.number, block.hash);
+            for event in block.events.iter().rev() {
+                updates.push(match event.kind {
+                    BlockEventKind::Commit => StatusUpdate {
+                        block_id: event.block_id,
+                        status: l2_block::BlockStatus::Uncommited,
+                        l1_tx_hash: None,
+                    },
+                    BlockEventKind::Verify => StatusUpdate {
+                        block_id: event.block_id,
+                        status: l2_block::BlockStatus::Commited,
+                        l1_tx_hash: None,
+                    },
+                });
+                // blocks are verified in order, so the one before it is still verified
+                if event.kind == BlockEventKind::Verify && self.latest_verified >= Some(event.block_id) {
+                    self.latest_verified = Some(event.block_id - 1).filter(|id| *id >= 0);
+                }
+            }
+            self.next_block = block.number;
+        }
+        Ok(())
+    }
+
+    // scans at most `max_scan_blocks` blocks, see `is_behind` for whether to poll again right away
+    pub async fn poll(&mut self) -> Result<Vec<StatusUpdate>> {
+        let mut updates = Vec::new();
+        self.revert_reorged(&mut updates).await?;
+
+        self.latest_block = self.source.latest_block().await?;
+        if self.latest_block < self.next_block {
+            return Ok(updates);
+        }
+        let (from, to) = (self.next_block, self.latest_block.min(self.next_block + self.max_scan_blocks - 1));
+        let mut events = self.source.block_events(from, to).await?;
+        events.retain(|e| (from..=to).contains(&e.block_number));
+        events.sort_by_key(|e| (e.block_number, e.log_index));
+        for event in &events {
+            log::info!("L1 {:?} of block {} in tx {:?}", event.kind, event.block_id, event.tx_hash);
+            updates.push(match event.kind {
+                BlockEventKind::Commit => StatusUpdate {
+                    block_id: event.block_id,
+                    status: l2_block::BlockStatus::Commited,
+                    l1_tx_hash: Some(event.tx_hash),
+                },
+                BlockEventKind::Verify => StatusUpdate {
+                    block_id: event.block_id,
+                    status: l2_block::BlockStatus::Verified,
+                    l1_tx_hash: None,
+                },
+            });
+            if event.kind == BlockEventKind::Verify && self.latest_verified < Some(event.block_id) {
+                self.latest_verified = Some(event.block_id);
+            }
+        }
+        // only the blocks which may still be reorged are remembered and checked on the next poll,
+        // and the last scanned one to resume from
+        let keep = self.reorg_depth.max(1);
+        let remember_from = (self.latest_block + 1).saturating_sub(keep as u64).clamp(from, to);
+        for number in remember_from..=to {
+            let hash = self
+                .source
+                .block_hash(number)
+                .await?
+                .ok_or_else(|| anyhow!("L1 block {} disappears", number))?;
+            let events = events.iter().filter(|e| e.block_number == number).cloned().collect();
+            self.recent.push_back(ScannedBlock { number, hash, events });
+        }
+        while self.recent.len() > keep {
+            self.recent.pop_front();
+        }
+        self.next_block = to + 1;
+        Ok(updates)
+    }
+}
+
+// saves the updates of a poll together with the blocks the watcher remembers after it
+pub async fn apply_updates<'a>(pool: &PgPool, updates: &[StatusUpdate], scanned: impl Iterator<Item = &'a ScannedBlock>) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    for update in updates {
+        let l1_tx_hash = update.l1_tx_hash.map(|hash| format!("{:?}", hash));
+        // a revert to `Uncommited` clears the hash of the reorged commit
+        let clear_hash = update.status == l2_block::BlockStatus::Uncommited;
+        sqlx::query(&format!(
+            "update {} set status = $1,
+                l1_tx_hash = case when $2 then null else coalesce($3, l1_tx_hash) end
+            where block_id = $4",
+            tablenames::L2_BLOCK
+        ))
+        .bind(update.status)
+        .bind(clear_hash)
+        .bind(l1_tx_hash)
+        .bind(update.block_id)
+        .execute(&mut tx)
+        .await?;
+    }
+    sqlx::query(&format!("delete from {}", L1_SCANNED_BLOCK)).execute(&mut tx).await?;
+    for block in scanned {
+        sqlx::query(&format!(
+            "insert into {} (block_number, block_hash, events) values ($1, $2, $3)",
+            L1_SCANNED_BLOCK
+        ))
+        .bind(block.number as i64)
+        .bind(format!("{:?}", block.hash))
+        .bind(sqlx::types::Json(&block.events))
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn load_scanned(pool: &PgPool) -> Result<Vec<ScannedBlock>> {
+    let rows = sqlx::query(&format!(
+        "select block_number, block_hash, events from {} order by block_number",
+        L1_SCANNED_BLOCK
+    ))
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            let hash: String = row.try_get("block_hash")?;
+            let events: sqlx::types::Json<Vec<BlockEvent>> = row.try_get("events")?;
+            Ok(ScannedBlock {
+                number: row.try_get::<i64, _>("block_number")? as u64,
+                hash: hash.parse().with_context(|| format!("invalid block hash {}", hash))?,
+                events: events.0,
+            })
+        })
+        .collect()
+}
+
+pub async fn load_latest_verified(pool: &PgPool) -> Result<Option<i64>> {
+    Ok(
+        sqlx::query_scalar(&format!("select max(block_id) from {} where status = $1", tablenames::L2_BLOCK))
+            .bind(l2_block::BlockStatus::Verified)
+            .fetch_one(pool)
+            .await?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use l2_block::BlockStatus;
+
+    fn statuses(updates: &[StatusUpdate]) -> Vec<(i64, BlockStatus)> {
+        updates.iter().map(|u| (u.block_id, u.status)).collect()
+    }
+
+    fn settings(reorg_depth: usize, max_scan_blocks: u64) -> L1WatcherSettings {
+        L1WatcherSettings {
+            rpc_url: String::new(),
+            contract_address: String::new(),
+            from_block: 0,
+            reorg_depth,
+            max_scan_blocks,
+            poll_interval_secs: None,
+        }
+    }
+
+    #[test]
+    fn test_block_status_reorg() {
+        let source = ScriptedBlockSource::default();
+        let mut watcher = BlockStatusWatcher::new(Box::new(source.clone()), &settings(2, 100), None, vec![]);
+        source.mine(&[(BlockEventKind::Commit, 0)]);
+        source.mine(&[(BlockEventKind::Verify, 0), (BlockEventKind::Commit, 1)]);
+        let updates = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(
+            statuses(&updates),
+            vec![(0, BlockStatus::Commited), (0, BlockStatus::Verified), (1, BlockStatus::Commited)]
+        );
+        assert!(updates[0].l1_tx_hash.is_some());
+        assert_eq!(watcher.latest_verified(), Some(0));
+
+        // the latest block is replaced by one without the verification
+        source.reorg(1);
+        source.mine(&[(BlockEventKind::Commit, 1)]);
+        let updates = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(
+            statuses(&updates),
+            vec![(1, BlockStatus::Uncommited), (0, BlockStatus::Commited), (1, BlockStatus::Commited)]
+        );
+        assert_eq!(watcher.latest_verified(), None);
+        assert!(futures::executor::block_on(watcher.poll()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_block_status_restart() {
+        let source = ScriptedBlockSource::default();
+        let mut watcher = BlockStatusWatcher::new(Box::new(source.clone()), &settings(2, 2), None, vec![]);
+        source.mine(&[(BlockEventKind::Commit, 0)]);
+        source.mine(&[]);
+        source.mine(&[(BlockEventKind::Commit, 1)]);
+        // scanned in chunks of 2 blocks
+        let updates = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(statuses(&updates), vec![(0, BlockStatus::Commited)]);
+        assert!(watcher.is_behind());
+        let updates = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(statuses(&updates), vec![(1, BlockStatus::Commited)]);
+        assert!(!watcher.is_behind());
+        let scanned: Vec<ScannedBlock> = watcher.scanned().cloned().collect();
+        assert_eq!(scanned.iter().map(|b| b.number).collect::<Vec<_>>(), vec![1, 2]);
+
+        // the commit of block 1 is reorged while stopped, and reverted after the restart
+        source.reorg(1);
+        source.mine(&[]);
+        let mut watcher = BlockStatusWatcher::new(Box::new(source.clone()), &settings(2, 2), None, scanned);
+        let updates = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(statuses(&updates), vec![(1, BlockStatus::Uncommited)]);
+        assert!(futures::executor::block_on(watcher.poll()).unwrap().is_empty());
+    }
+}
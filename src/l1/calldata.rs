@@ -0,0 +1,197 @@
+// ABI-encoded calldata of the rollup contract functions which submit a block to L1:
+//   commitBlock(uint32 blockId, uint256 oldRoot, uint256 newRoot, bytes32 commitment, bytes publicData, uint32[] priorityOpPositions)
+//   verifyBlock(uint32 blockId, bytes32 commitment, uint256[] proof)
+use crate::types::l2::{commitment::fr_to_u256, BlockCommitment, L2Block, L2BlockSerde, PubDataAux};
+use ethers::abi::{self, ParamType, Token};
+use ethers::core::types::U256;
+
+pub struct CommitBlockCall {
+    pub block_id: u32,
+    pub old_root: U256,
+    pub new_root: U256,
+    pub commitment: U256,
+    pub public_data: Vec<u8>,
+    // deposits, key updates and full exits, which the contract matches against its priority queue
+    pub priority_op_positions: Vec<u32>,
+}
+
+fn priority_op_positions(aux: &PubDataAux) -> Vec<u32> {
+    let mut positions: Vec<u32> = aux
+        .deposit_txs_pos
+        .iter()
+        .chain(aux.key_update_txs_pos.iter())
+        .chain(aux.full_exit_txs_pos.iter())
+        .cloned()
+        .collect();
+    positions.sort_unstable();
+    positions
+}
+
+fn u256_to_fixed_bytes(u: &U256) -> Vec<u8> {
+    let mut bytes = vec![0u8; 32];
+    u.to_big_endian(&mut bytes);
+    bytes
+}
+
+impl CommitBlockCall {
+    pub const NAME: &'static str = "commitBlock";
+
+    // build from a block loaded from db, see `l2_block` table
+    pub fn from_stored(block_id: u32, detail: &L2BlockSerde, public_data: Vec<u8>, aux: &PubDataAux) -> Self {
+        let txdata_hash = (fr_to_u256(&detail.txdata_hash_hi.0) << 128u8) | fr_to_u256(&detail.txdata_hash_lo.0);
+        let commitment = BlockCommitment {
+            block_id: block_id as u64,
+            old_root: detail.old_root.0,
+            new_root: detail.new_root.0,
+            txdata_hash,
+        };
+        Self {
+            block_id,
+            old_root: fr_to_u256(&detail.old_root.0),
+            new_root: fr_to_u256(&detail.new_root.0),
+            commitment: commitment.hash(),
+            public_data,
+            priority_op_positions: priority_op_positions(aux),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let params = [
+            ParamType::Uint(32),
+            ParamType::Uint(256),
+            ParamType::Uint(256),
+            ParamType::FixedBytes(32),
+            ParamType::Bytes,
+            ParamType::Array(Box::new(ParamType::Uint(32))),
+        ];
+        let tokens = [
+            Token::Uint(self.block_id.into()),
+            Token::Uint(self.old_root),
+            Token::Uint(self.new_root),
+            Token::FixedBytes(u256_to_fixed_bytes(&self.commitment)),
+            Token::Bytes(self.public_data.clone()),
+            Token::Array(self.priority_op_positions.iter().map(|pos| Token::Uint((*pos).into())).collect()),
+        ];
+        encode_call(Self::NAME, &params, &tokens)
+    }
+}
+
+impl From<&L2Block> for CommitBlockCall {
+    fn from(block: &L2Block) -> Self {
+        Self {
+            block_id: block.block_id as u32,
+            old_root: fr_to_u256(&block.detail.old_root),
+            new_root: fr_to_u256(&block.detail.new_root),
+            commitment: BlockCommitment::new(block.block_id, &block.detail).hash(),
+            public_data: block.public_data.clone(),
+            priority_op_positions: priority_op_positions(&block.public_data_aux),
+        }
+    }
+}
+
+pub struct VerifyBlockCall {
+    pub block_id: u32,
+    pub commitment: U256,
+    pub proof: Vec<U256>,
+}
+
+impl VerifyBlockCall {
+    pub const NAME: &'static str = "verifyBlock";
+
+    pub fn new(commit: &CommitBlockCall, proof: Vec<U256>) -> Self {
+        Self {
+            block_id: commit.block_id,
+            commitment: commit.commitment,
+            proof,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let params = [
+            ParamType::Uint(32),
+            ParamType::FixedBytes(32),
+            ParamType::Array(Box::new(ParamType::Uint(256))),
+        ];
+        let tokens = [
+            Token::Uint(self.block_id.into()),
+            Token::FixedBytes(u256_to_fixed_bytes(&self.commitment)),
+            Token::Array(self.proof.iter().map(|p| Token::Uint(*p)).collect()),
+        ];
+        encode_call(Self::NAME, &params, &tokens)
+    }
+}
+
+fn encode_call(name: &str, params: &[ParamType], tokens: &[Token]) -> Vec<u8> {
+    let mut calldata = abi::short_signature(name, params).to_vec();
+    calldata.extend(abi::encode(tokens));
+    calldata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMITMENT: &str = "0a78081881c0c85f02668b8cb5d2b677ced520d7f86426d24a4991a39c2a11af";
+
+    #[test]
+    fn test_commit_block_calldata() {
+        let call = CommitBlockCall {
+            block_id: 1,
+            old_root: U256::from(1),
+            new_root: U256::from(2),
+            commitment: U256::from_big_endian(&hex::decode(COMMITMENT).unwrap()),
+            public_data: vec![0x12, 0x34, 0x56],
+            priority_op_positions: vec![0, 1],
+        };
+        let expected = [
+            "a5299073",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+            COMMITMENT,
+            "00000000000000000000000000000000000000000000000000000000000000c0",
+            "0000000000000000000000000000000000000000000000000000000000000100",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "1234560000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+        ]
+        .concat();
+        assert_eq!(hex::encode(call.encode()), expected);
+    }
+
+    #[test]
+    fn test_verify_block_calldata() {
+        let call = VerifyBlockCall {
+            block_id: 1,
+            commitment: U256::from_big_endian(&hex::decode(COMMITMENT).unwrap()),
+            proof: vec![U256::from(1), U256::from(2), U256::from(3)],
+        };
+        let expected = [
+            "1e86f892",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            COMMITMENT,
+            "0000000000000000000000000000000000000000000000000000000000000060",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+            "0000000000000000000000000000000000000000000000000000000000000001",
+            "0000000000000000000000000000000000000000000000000000000000000002",
+            "0000000000000000000000000000000000000000000000000000000000000003",
+        ]
+        .concat();
+        assert_eq!(hex::encode(call.encode()), expected);
+    }
+
+    #[test]
+    fn test_priority_op_positions() {
+        let aux = PubDataAux {
+            deposit_txs_pos: vec![1, 3],
+            deposit_count: 2,
+            key_update_txs_pos: vec![2],
+            key_update_count: 1,
+            full_exit_txs_pos: vec![0],
+            full_exit_count: 1,
+        };
+        assert_eq!(priority_op_positions(&aux), vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,382 @@
+// Deposits read from the Deposit events of the rollup contract, instead of trusting the `DepositMessage` of the exchange.
+// keep in sync with the rollup contract:
+//   event Deposit(uint32 accountId, uint16 tokenId, uint256 amount)
+// Deposits to unregistered accounts are rejected, new accounts are still registered by `UserMessage`.
+// A deposit is consumed like a kafka msg of the pseudo partition `L1_DEPOSIT_TOPIC`, with its L1 position as the offset,
+// so the scanned L1 position is persisted with the consumed offsets, in snapshots and in the `kafka_offset` table.
+use crate::config::L1DepositSettings;
+use crate::types::l2::DepositTx;
+use crate::types::matchengine::messages::{MsgOffset, TopicPartition};
+use anyhow::{anyhow, bail, Context, Result};
+use crossbeam_channel::Receiver;
+use ethers::abi::{self, ParamType, Token};
+use ethers::core::types::{Address, Filter, Log, H256};
+use ethers::providers::{Http, Middleware, Provider};
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{BufRead, BufReader};
+
+pub const DEPOSIT_EVENT: &str = "Deposit(uint32,uint16,uint256)";
+pub const L1_DEPOSIT_TOPIC: &str = "l1_deposit";
+// decimals of most ERC20 tokens and ETH
+pub const DEFAULT_L1_DECIMALS: u32 = 18;
+// a block has far less logs than this
+const LOG_INDEX_BITS: u32 = 24;
+
+pub fn topic_partition() -> TopicPartition {
+    TopicPartition {
+        topic: L1_DEPOSIT_TOPIC.to_string(),
+        partition: 0,
+    }
+}
+
+fn block_of(position: i64) -> u64 {
+    position as u64 >> LOG_INDEX_BITS
+}
+
+/// Converts an amount in the decimals of the L1 token to the L2 precision of the token,
+/// returns the converted amount and the dust of the L1 amount which is rounded off, or None on overflow.
+pub fn to_l2_amount(amount: u128, l1_decimals: u32, l2_prec: u32) -> Option<(u128, u128)> {
+    if l1_decimals >= l2_prec {
+        let unit = 10u128.checked_pow(l1_decimals - l2_prec)?;
+        Some((amount / unit, amount % unit))
+    } else {
+        let unit = 10u128.checked_pow(l2_prec - l1_decimals)?;
+        Some((amount.checked_mul(unit)?, 0))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DepositEvent {
+    pub tx_hash: H256,
+    pub log_index: u64,
+    pub block_number: u64,
+    pub account_id: u32,
+    pub token_id: u32,
+    pub amount: u128,
+}
+
+impl DepositEvent {
+    // the order of events on L1, and the offset of the event in `L1_DEPOSIT_TOPIC`
+    pub fn position(&self) -> i64 {
+        ((self.block_number << LOG_INDEX_BITS) | self.log_index) as i64
+    }
+
+    pub fn msg_offset(&self) -> MsgOffset {
+        MsgOffset {
+            tp: topic_partition(),
+            offset: self.position(),
+        }
+    }
+
+    // `amount` is in the L2 precision, see `to_l2_amount`
+    pub fn to_deposit_tx(&self, amount: u128) -> DepositTx {
+        DepositTx {
+            account_id: self.account_id,
+            token_id: self.token_id,
+            amount,
+            l2key: None,
+        }
+    }
+
+    fn from_log(log: &Log) -> Result<Self> {
+        let tokens = abi::decode(&[ParamType::Uint(32), ParamType::Uint(16), ParamType::Uint(256)], &log.data)?;
+        let uint = |token: &Token| token.clone().into_uint().ok_or_else(|| anyhow!("invalid deposit log {:?}", log));
+        let amount = uint(&tokens[2])?;
+        if amount.bits() > 128 {
+            bail!("deposit amount {} overflows", amount);
+        }
+        let log_index = log.log_index.ok_or_else(|| anyhow!("pending deposit log"))?.as_u64();
+        if log_index >= 1 << LOG_INDEX_BITS {
+            bail!("log index {} of deposit overflows", log_index);
+        }
+        Ok(Self {
+            tx_hash: log.transaction_hash.ok_or_else(|| anyhow!("pending deposit log"))?,
+            log_index,
+            block_number: log.block_number.ok_or_else(|| anyhow!("pending deposit log"))?.as_u64(),
+            account_id: uint(&tokens[0])?.as_u32(),
+            token_id: uint(&tokens[1])?.as_u32(),
+            amount: amount.as_u128(),
+        })
+    }
+}
+
+#[tonic::async_trait]
+pub trait L1EventSource: Send + Sync {
+    async fn latest_block(&self) -> Result<u64>;
+    // deposits in blocks `from..=to`
+    async fn deposits(&self, from: u64, to: u64) -> Result<Vec<DepositEvent>>;
+}
+
+// reads the events from a node by JSON-RPC, e.g. a local dev chain
+pub struct EthersEventSource {
+    provider: Provider<Http>,
+    contract: Address,
+}
+
+impl EthersEventSource {
+    pub fn new(rpc_url: &str, contract: &str) -> Result<Self> {
+        Ok(Self {
+            provider: Provider::<Http>::try_from(rpc_url)?,
+            contract: contract.parse().with_context(|| format!("invalid contract address {}", contract))?,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl L1EventSource for EthersEventSource {
+    async fn latest_block(&self) -> Result<u64> {
+        Ok(self.provider.get_block_number().await?.as_u64())
+    }
+
+    async fn deposits(&self, from: u64, to: u64) -> Result<Vec<DepositEvent>> {
+        let filter = Filter::new()
+            .address(self.contract)
+            .event(DEPOSIT_EVENT)
+            .from_block(from)
+            .to_block(to);
+        let logs = self.provider.get_logs(&filter).await?;
+        logs.iter().map(DepositEvent::from_log).collect()
+    }
+}
+
+// mock source for testing, one `DepositEvent` per line in json.
+// The file is read again on every call, so deposits can be appended while running,
+// and the latest block is the largest block number in it.
+pub struct FileEventSource {
+    path: String,
+}
+
+impl FileEventSource {
+    pub fn new(path: &str) -> Self {
+        Self { path: path.to_string() }
+    }
+
+    fn read(&self) -> Result<Vec<DepositEvent>> {
+        let file = fs::File::open(&self.path).with_context(|| format!("failed to open {}", self.path))?;
+        BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
+
+#[tonic::async_trait]
+impl L1EventSource for FileEventSource {
+    async fn latest_block(&self) -> Result<u64> {
+        Ok(self.read()?.iter().map(|e| e.block_number).max().unwrap_or(0))
+    }
+
+    async fn deposits(&self, from: u64, to: u64) -> Result<Vec<DepositEvent>> {
+        Ok(self.read()?.into_iter().filter(|e| (from..=to).contains(&e.block_number)).collect())
+    }
+}
+
+pub fn source_from_settings(settings: &L1DepositSettings) -> Result<Box<dyn L1EventSource>> {
+    if let Some(path) = &settings.mock_file {
+        return Ok(Box::new(FileEventSource::new(path)));
+    }
+    match (&settings.rpc_url, &settings.contract_address) {
+        (Some(rpc_url), Some(contract)) => Ok(Box::new(EthersEventSource::new(rpc_url, contract)?)),
+        _ => bail!("l1_deposit needs either mock_file, or both rpc_url and contract_address"),
+    }
+}
+
+/// Polls a [`L1EventSource`] for deposits which have enough confirmations.
+/// Every deposit after the cursor is returned once, in the order of L1 blocks and log indexes.
+pub struct DepositWatcher {
+    source: Box<dyn L1EventSource>,
+    confirmations: u64,
+    next_block: u64,
+    // the position of the last returned deposit
+    cursor: Option<i64>,
+}
+
+impl DepositWatcher {
+    // `cursor` is the consumed offset of `L1_DEPOSIT_TOPIC` the state is restored with
+    pub fn new(source: Box<dyn L1EventSource>, confirmations: u64, from_block: u64, cursor: Option<i64>) -> Self {
+        Self {
+            source,
+            confirmations,
+            // the block of the cursor may have more deposits after it
+            next_block: cursor.map_or(from_block, |cursor| from_block.max(block_of(cursor))),
+            cursor,
+        }
+    }
+
+    // the first block not scanned yet
+    pub fn next_block(&self) -> u64 {
+        self.next_block
+    }
+
+    pub async fn poll(&mut self) -> Result<Vec<DepositEvent>> {
+        let latest = self.source.latest_block().await?;
+        // a block is confirmed when `confirmations` blocks are mined on top of it
+        let confirmed = match latest.checked_sub(self.confirmations) {
+            Some(confirmed) if confirmed >= self.next_block => confirmed,
+            _ => return Ok(vec![]),
+        };
+        let mut events = self.source.deposits(self.next_block, confirmed).await?;
+        events.sort_by_key(DepositEvent::position);
+        self.next_block = confirmed + 1;
+
+        let mut deposits = Vec::new();
+        for event in events {
+            // the block of the cursor is scanned again after a restart, and an event may be delivered twice
+            if self.cursor.map_or(false, |cursor| event.position() <= cursor) {
+                log::debug!("skip consumed deposit {:?}", event);
+                continue;
+            }
+            log::debug!("l1 deposit {:?}", event);
+            self.cursor = Some(event.position());
+            deposits.push(event);
+        }
+        Ok(deposits)
+    }
+}
+
+/// Decides where L1 deposits go in the tx stream, so that blocks are the same when they are replayed.
+/// Deposits are only applied while the pending block is empty. A new block takes all deposits received by then,
+/// while a saved block takes exactly the deposits up to the cursor it is saved with, waiting for the watcher if needed.
+pub struct DepositScheduler {
+    receiver: Receiver<DepositEvent>,
+    // the `L1_DEPOSIT_TOPIC` offset of saved blocks by block id, see `storage::load_l1_deposit_cursors`
+    saved_cursors: BTreeMap<usize, i64>,
+    saved_block_num: usize,
+    received: VecDeque<DepositEvent>,
+    // the position of the last received deposit
+    cursor: i64,
+}
+
+impl DepositScheduler {
+    pub fn new(receiver: Receiver<DepositEvent>, saved_cursors: BTreeMap<usize, i64>, saved_block_num: usize, cursor: Option<i64>) -> Self {
+        Self {
+            receiver,
+            saved_cursors,
+            saved_block_num,
+            received: VecDeque::new(),
+            cursor: cursor.unwrap_or(-1),
+        }
+    }
+
+    // the deposits to apply at the start of block `block_id`
+    pub fn take(&mut self, block_id: usize) -> Result<Vec<DepositEvent>> {
+        if block_id >= self.saved_block_num {
+            for event in self.receiver.try_iter() {
+                self.cursor = event.position();
+                self.received.push_back(event);
+            }
+            return Ok(self.received.drain(..).collect());
+        }
+        let saved_cursor = match self.saved_cursors.range(..=block_id).next_back() {
+            Some((_, cursor)) => *cursor,
+            None => return Ok(vec![]),
+        };
+        while self.cursor < saved_cursor {
+            let event = self
+                .receiver
+                .recv()
+                .map_err(|_| anyhow!("l1 deposits stop before {}, which block {} is saved with", saved_cursor, block_id))?;
+            self.cursor = event.position();
+            self.received.push_back(event);
+        }
+        let num = self.received.iter().take_while(|e| e.position() <= saved_cursor).count();
+        Ok(self.received.drain(..num).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn event_line(tx_hash: u64, log_index: u64, block_number: u64, amount: u128) -> String {
+        format!(
+            r#"{{"tx_hash": "{:?}", "log_index": {}, "block_number": {}, "account_id": 1, "token_id": 2, "amount": {}}}"#,
+            H256::from_low_u64_be(tx_hash),
+            log_index,
+            block_number,
+            amount
+        )
+    }
+
+    fn event(block_number: u64, log_index: u64) -> DepositEvent {
+        serde_json::from_str(&event_line(block_number, log_index, block_number, 1)).unwrap()
+    }
+
+    #[test]
+    fn test_deposit_watcher() {
+        let path = std::env::temp_dir().join(format!("l1_deposits_{}.jsonl", std::process::id()));
+        let mut file = fs::File::create(&path).unwrap();
+        for line in [
+            event_line(1, 0, 1, 100),
+            event_line(1, 1, 1, 200),
+            // the same event delivered twice
+            event_line(1, 1, 1, 200),
+            event_line(2, 0, 3, 300),
+        ] {
+            writeln!(file, "{}", line).unwrap();
+        }
+
+        let source = FileEventSource::new(path.to_str().unwrap());
+        let mut watcher = DepositWatcher::new(Box::new(source), 2, 0, None);
+        // block 3 is the latest, so only block 1 has 2 confirmations
+        let events = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(events.iter().map(|e| e.amount).collect::<Vec<_>>(), vec![100, 200]);
+        assert_eq!(watcher.next_block(), 2);
+        assert!(futures::executor::block_on(watcher.poll()).unwrap().is_empty());
+
+        writeln!(file, "{}", event_line(3, 0, 5, 400)).unwrap();
+        let events = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].amount, 300);
+        assert_eq!((events[0].account_id, events[0].token_id), (1, 2));
+
+        // restarted with the first deposit consumed, the rest of its block is scanned again
+        let source = FileEventSource::new(path.to_str().unwrap());
+        let cursor = event(1, 0).position();
+        let mut watcher = DepositWatcher::new(Box::new(source), 2, 0, Some(cursor));
+        assert_eq!(watcher.next_block(), 1);
+        let events = futures::executor::block_on(watcher.poll()).unwrap();
+        assert_eq!(events.iter().map(|e| e.amount).collect::<Vec<_>>(), vec![200, 300]);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn test_deposit_scheduler() {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        // block 1 is saved with the deposits up to (3, 0), block 2 with none more
+        let saved_cursors = [(1, event(3, 0).position()), (2, event(3, 0).position())].into_iter().collect();
+        let mut scheduler = DepositScheduler::new(receiver, saved_cursors, 3, Some(event(1, 0).position()));
+        for e in [event(2, 0), event(3, 0), event(4, 0)] {
+            sender.send(e).unwrap();
+        }
+        assert!(scheduler.take(0).unwrap().is_empty());
+        // the deposit of block 4 arrived already, but block 1 is saved without it
+        let taken = scheduler.take(1).unwrap();
+        assert_eq!(taken, vec![event(2, 0), event(3, 0)]);
+        assert!(scheduler.take(2).unwrap().is_empty());
+
+        // a new block takes whatever is received
+        sender.send(event(4, 1)).unwrap();
+        assert_eq!(scheduler.take(3).unwrap(), vec![event(4, 0), event(4, 1)]);
+
+        // a saved block waits for its deposits, which never come
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let saved_cursors = [(0, event(2, 0).position())].into_iter().collect();
+        let mut scheduler = DepositScheduler::new(receiver, saved_cursors, 1, None);
+        drop(sender);
+        assert!(scheduler.take(0).is_err());
+    }
+
+    #[test]
+    fn test_to_l2_amount() {
+        // 1.5 ETH to 4 decimals
+        assert_eq!(to_l2_amount(1_500_000_000_000_000_123, 18, 4), Some((15_000, 123)));
+        assert_eq!(to_l2_amount(15, 4, 6), Some((1_500, 0)));
+        assert_eq!(to_l2_amount(u128::MAX, 0, 6), None);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod block_status;
+pub mod calldata;
+pub mod deposit;
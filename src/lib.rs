@@ -9,8 +9,12 @@ pub mod account;
 pub mod config;
 pub mod r#const;
 pub mod grpc;
+pub mod l1;
+pub mod metrics;
 pub mod msg;
 pub mod params;
+pub mod shutdown;
 pub mod state;
+pub mod storage;
 pub mod test_utils;
 pub mod types;
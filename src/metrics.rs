@@ -0,0 +1,133 @@
+// Prometheus metrics, and an http server for `/metrics`, `/health` and `/ready`.
+// Metrics are registered to the default registry when first used.
+use crate::config::MetricsSettings;
+use crate::msg::pipeline::PipelineStats;
+use crate::shutdown::Shutdown;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+lazy_static! {
+    pub static ref TXS_APPLIED: IntCounterVec =
+        register_int_counter_vec!("state_keeper_txs_applied_total", "L2 txs applied to the state", &["tx_type"]).unwrap();
+    pub static ref MSGS_REJECTED: IntCounterVec =
+        register_int_counter_vec!("state_keeper_msgs_rejected_total", "msgs recorded as dead letters", &["reason"]).unwrap();
+    pub static ref L1_DEPOSITS_REJECTED: IntCounter =
+        register_int_counter!("state_keeper_l1_deposits_rejected_total", "L1 deposits which can not be credited").unwrap();
+    pub static ref MSG_HANDLE_SECONDS: HistogramVec =
+        register_histogram_vec!("state_keeper_msg_handle_seconds", "time to apply a msg to the state", &["msg_type"]).unwrap();
+    pub static ref BLOCK_SEAL_SECONDS: Histogram = register_histogram!(
+        "state_keeper_block_seal_latency_seconds",
+        "time from the first tx of a block being applied to the block being sealed",
+        vec![0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]
+    )
+    .unwrap();
+    pub static ref SNAPSHOT_SECONDS: Histogram = register_histogram!(
+        "state_keeper_snapshot_seconds",
+        "time to dump a snapshot of the state",
+        vec![1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0]
+    )
+    .unwrap();
+    pub static ref DB_WRITE_SECONDS: Histogram =
+        register_histogram!("state_keeper_db_write_seconds", "time to save a block with its task and offsets").unwrap();
+    pub static ref LOCK_WAIT_SECONDS: HistogramVec = register_histogram_vec!(
+        "state_keeper_state_lock_wait_seconds",
+        "time to acquire the lock of the global state",
+        &["mode"],
+        vec![0.00001, 0.0001, 0.001, 0.01, 0.1, 1.0]
+    )
+    .unwrap();
+    pub static ref ACCOUNTS: IntGauge = register_int_gauge!("state_keeper_accounts", "accounts in the account tree").unwrap();
+    pub static ref ORDERS: IntGauge = register_int_gauge!("state_keeper_orders", "orders in the order trees").unwrap();
+    pub static ref BLOCKS: IntGauge = register_int_gauge!("state_keeper_blocks", "blocks generated").unwrap();
+    pub static ref L1_VERIFIED_BLOCK: IntGauge =
+        register_int_gauge!("state_keeper_l1_verified_block", "the latest block verified on L1, -1 if none").unwrap();
+    static ref MSG_QUEUE: IntGauge = register_int_gauge!("state_keeper_msg_queue", "msgs waiting to be processed").unwrap();
+    static ref BLOCK_QUEUE: IntGauge = register_int_gauge!("state_keeper_block_queue", "blocks waiting to be saved").unwrap();
+    static ref SOURCE_PAUSED: IntGauge =
+        register_int_gauge!("state_keeper_source_paused", "1 if the msg source is paused by backpressure").unwrap();
+    static ref KAFKA_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "state_keeper_kafka_lag",
+        "msgs in kafka not consumed by the processor yet",
+        &["topic", "partition"]
+    )
+    .unwrap();
+    pub static ref KAFKA_OFFSET: IntGaugeVec =
+        register_int_gauge_vec!("state_keeper_kafka_offset", "last received kafka offset", &["topic", "partition"]).unwrap();
+}
+
+// the pipeline stats are kept by the pipeline itself, and copied to the gauges on scraping
+fn refresh_pipeline() {
+    let stats = PipelineStats::get();
+    MSG_QUEUE.set(stats.msg_queue() as i64);
+    BLOCK_QUEUE.set(stats.block_queue() as i64);
+    SOURCE_PAUSED.set(stats.source_paused() as i64);
+    for (tp, lag) in stats.lags() {
+        KAFKA_LAG.with_label_values(&[&tp.topic, &tp.partition.to_string()]).set(lag);
+    }
+}
+
+pub fn gather() -> String {
+    refresh_pipeline();
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+fn respond(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder().status(status).body(Body::from(body)).unwrap()
+}
+
+fn route(req: &Request<Body>, settings: &MetricsSettings) -> Response<Body> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => respond(StatusCode::OK, gather()),
+        (&Method::GET, "/health") => respond(StatusCode::OK, "ok".to_string()),
+        (&Method::GET, "/ready") => {
+            if PipelineStats::get().caught_up(settings.ready_max_lag, settings.ready_max_block_queue) {
+                respond(StatusCode::OK, "ready".to_string())
+            } else {
+                respond(StatusCode::SERVICE_UNAVAILABLE, "catching up".to_string())
+            }
+        }
+        _ => respond(StatusCode::NOT_FOUND, String::new()),
+    }
+}
+
+// serves until `shutdown` is triggered
+pub fn run_metrics_server(addr: SocketAddr, settings: &'static MetricsSettings, shutdown: Shutdown) -> anyhow::Result<()> {
+    let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Build runtime");
+
+    rt.block_on(async {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |req| async move { Ok::<_, Infallible>(route(&req, settings)) }))
+        });
+        log::info!("serving metrics on {}", addr);
+        hyper::Server::try_bind(&addr)?
+            .serve(make_svc)
+            .with_graceful_shutdown(async move { shutdown.wait().await })
+            .await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() {
+        TXS_APPLIED.with_label_values(&["nop"]).inc();
+        let text = gather();
+        assert!(text.contains("state_keeper_txs_applied_total{tx_type=\"nop\"}"));
+        assert!(text.contains("state_keeper_msg_queue"));
+    }
+}
@@ -0,0 +1,346 @@
+// Msgs which fail parsing or processing are recorded as dead letters instead of crashing blindly,
+// and can be inspected and re-injected by the `dead_letters` tool after a fix.
+// A msg is only rejected cleanly if it failed before touching the state, otherwise the state keeper halts
+// whatever the policy is, since the state no longer matches any sequence of msgs.
+use super::msg_loader::keyed_payload;
+use super::msg_processor::Processor;
+use crate::config::{DeadLetterPolicy, DeadLetterSettings};
+use crate::metrics;
+use crate::state::ManagerWrapper;
+use crate::test_utils::messages::WrappedMessage;
+use crate::types::matchengine::messages::MsgOffset;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Parse,
+    Process,
+    // rejected without processing, since an account it touches is quarantined
+    Quarantined,
+    // rejected without processing, since its tx type is disabled by settings, see `Processor::disabled_reason`
+    Disabled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub stage: Stage,
+    pub offset: Option<MsgOffset>,
+    // kafka key, i.e. the msg type
+    pub key: String,
+    // raw payload, in the same format as in kafka
+    pub payload: String,
+    pub error: String,
+}
+
+// the metric label for a dead letter by the first matching part of its error, most errors are panic msgs
+const PROCESS_REASONS: &[(&str, &str)] = &[
+    ("invalid sig", "invalid_signature"),
+    ("verify sig", "invalid_signature"),
+    ("empty signature", "invalid_signature"),
+    ("quarantined", "quarantined"),
+    ("to self", "self_transfer"),
+    ("self trade", "self_transfer"),
+    ("balance", "balance"),
+    ("order", "order"),
+    ("account", "account"),
+    ("assertion failed", "state_mismatch"),
+];
+
+impl DeadLetter {
+    pub fn reason(&self) -> &'static str {
+        match self.stage {
+            Stage::Parse => "unparsable",
+            Stage::Quarantined => "quarantined",
+            Stage::Disabled => "disabled",
+            Stage::Process => PROCESS_REASONS
+                .iter()
+                .find(|(pattern, _)| self.error.contains(pattern))
+                .map_or("other", |(_, reason)| reason),
+        }
+    }
+
+    pub fn of_msg(msg: &WrappedMessage, error: String) -> Self {
+        let (key, payload) = keyed_payload(msg).unwrap_or_else(|| ("unknown", format!("{:?}", msg)));
+        Self {
+            stage: Stage::Process,
+            offset: msg_offset(msg).cloned(),
+            key: key.to_string(),
+            payload,
+            error,
+        }
+    }
+}
+
+/// Appends dead letters to a json lines file, shared by the msg loader and the msg processor.
+/// Without a file, dead letters are only logged.
+#[derive(Clone, Default)]
+pub struct DeadLetterStore {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl DeadLetterStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open dead letter file {}", path))?;
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(file))),
+        })
+    }
+
+    pub fn from_settings(settings: &DeadLetterSettings) -> Result<Self> {
+        settings.path.as_deref().map_or_else(|| Ok(Self::default()), Self::open)
+    }
+
+    pub fn record(&self, letter: &DeadLetter) {
+        log::error!("dead letter {:?}", letter);
+        metrics::MSGS_REJECTED.with_label_values(&[letter.reason()]).inc();
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            let line = serde_json::to_string(letter).unwrap();
+            // losing a dead letter is worse than stopping
+            writeln!(file, "{}", line).expect("failed to write dead letter");
+            file.flush().expect("failed to write dead letter");
+        }
+    }
+
+    pub fn read(path: &str) -> Result<Vec<DeadLetter>> {
+        let file = File::open(path).with_context(|| format!("failed to open {}", path))?;
+        BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
+
+pub fn msg_offset(msg: &WrappedMessage) -> Option<&MsgOffset> {
+    match msg {
+        WrappedMessage::DEPOSIT(m) => m.offset(),
+        WrappedMessage::ORDER(m) => m.offset(),
+        WrappedMessage::TRADE(m) => m.offset(),
+        WrappedMessage::TRANSFER(m) => m.offset(),
+        WrappedMessage::USER(m) => m.offset(),
+        WrappedMessage::WITHDRAW(m) => m.offset(),
+        WrappedMessage::FULLEXIT(m) => m.offset(),
+        WrappedMessage::L1DEPOSIT(_) => None,
+        WrappedMessage::SKIPPED(offset) => Some(offset),
+    }
+}
+
+// accounts touched by a msg
+pub fn msg_accounts(msg: &WrappedMessage) -> Vec<u32> {
+    match msg {
+        WrappedMessage::DEPOSIT(m) => vec![m.user_id],
+        WrappedMessage::ORDER(m) => vec![m.order.user],
+        WrappedMessage::TRADE(m) => vec![m.ask_user_id, m.bid_user_id],
+        WrappedMessage::TRANSFER(m) => vec![m.user_from, m.user_to],
+        WrappedMessage::USER(m) => vec![m.user_id],
+        WrappedMessage::WITHDRAW(m) => vec![m.user_id],
+        WrappedMessage::FULLEXIT(m) => vec![m.user_id],
+        WrappedMessage::L1DEPOSIT(event) => vec![event.account_id],
+        WrappedMessage::SKIPPED(_) => vec![],
+    }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Runs the [`Processor`] on msgs and applies the [`DeadLetterPolicy`] to those it rejects.
+/// Quarantined accounts are kept by the [`ManagerWrapper`], which persists them with its snapshots.
+pub struct DeadLetterGuard {
+    policy: DeadLetterPolicy,
+    store: DeadLetterStore,
+}
+
+impl DeadLetterGuard {
+    pub fn new(policy: DeadLetterPolicy, store: DeadLetterStore) -> Self {
+        Self { policy, store }
+    }
+
+    pub fn handle(&mut self, processor: &mut Processor, manager: &mut ManagerWrapper, msg: WrappedMessage) {
+        let accounts = msg_accounts(&msg);
+        if let Some(account_id) = accounts.iter().find(|a| manager.quarantined().contains(a)) {
+            let error = format!("account {} is quarantined", account_id);
+            let letter = DeadLetter {
+                stage: Stage::Quarantined,
+                ..DeadLetter::of_msg(&msg, error)
+            };
+            self.reject(manager, letter);
+            return;
+        }
+        if let Some(error) = processor.disabled_reason(&msg) {
+            let letter = DeadLetter {
+                stage: Stage::Disabled,
+                ..DeadLetter::of_msg(&msg, error.to_string())
+            };
+            self.reject(manager, letter);
+            return;
+        }
+        if self.policy == DeadLetterPolicy::Halt {
+            processor.handle_msg(manager, msg);
+            return;
+        }
+
+        let origin = msg.clone();
+        let (root, tx_num) = (manager.root(), manager.tx_num());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| processor.handle_msg(manager, msg)));
+        if let Err(payload) = result {
+            let letter = DeadLetter::of_msg(&origin, panic_message(payload.as_ref()));
+            let untouched = !manager.is_poisoned() && manager.root() == root && manager.tx_num() == tx_num;
+            if !untouched {
+                self.store.record(&letter);
+                panic!("state is modified by the rejected msg {:?}, halt", letter.offset);
+            }
+            self.reject(manager, letter);
+            if self.policy == DeadLetterPolicy::QuarantineAccount {
+                log::warn!("quarantine accounts {:?}", accounts);
+                manager.quarantine(&accounts);
+            }
+        }
+    }
+
+    // a rejected msg leaves no tx, so its offset is consumed here or it is read again after a restart
+    fn reject(&mut self, manager: &mut ManagerWrapper, letter: DeadLetter) {
+        self.store.record(&letter);
+        if let Some(offset) = letter.offset {
+            manager.consume_offset(offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::GlobalState;
+    use crate::test_utils::init_test_settings;
+    use crate::types::matchengine::messages::{FullExitMessage, TopicPartition, WithdrawMessage};
+    use fluidex_common::rust_decimal_macros::dec;
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_quarantine_account() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut manager = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        let mut processor = Processor::default();
+        let path = std::env::temp_dir().join(format!("dead_letters_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut guard = DeadLetterGuard::new(DeadLetterPolicy::QuarantineAccount, DeadLetterStore::open(path).unwrap());
+
+        // withdraw more than the balance of an account which does not exist
+        let withdraw = WithdrawMessage {
+            timestamp: 0.0,
+            user_id: 1,
+            asset: "ETH".to_string(),
+            business: "withdraw".to_string(),
+            change: dec!(-1),
+            balance: dec!(0),
+            balance_available: dec!(0),
+            balance_frozen: dec!(0),
+            detail: String::new(),
+            signature: [0u8; 64],
+        };
+        let offset = MsgOffset {
+            tp: TopicPartition {
+                topic: "test".to_string(),
+                partition: 0,
+            },
+            offset: 7,
+        };
+        let tp = offset.tp.clone();
+        let at = |offset: i64| MsgOffset { tp: tp.clone(), offset };
+        let root = manager.root();
+        guard.handle(
+            &mut processor,
+            &mut manager,
+            WrappedMessage::WITHDRAW((withdraw.clone(), offset).into()),
+        );
+        assert_eq!(manager.root(), root);
+        assert!(manager.quarantined().contains(&1));
+        // the offsets of rejected msgs are consumed though they leave no tx
+        assert_eq!(manager.consumed_offsets().get(&tp), Some(&7));
+        guard.handle(&mut processor, &mut manager, WrappedMessage::WITHDRAW((withdraw, at(8)).into()));
+        assert_eq!(manager.consumed_offsets().get(&tp), Some(&8));
+        // as are those of msgs which could not be parsed
+        guard.handle(&mut processor, &mut manager, WrappedMessage::SKIPPED(at(9)));
+        assert_eq!(manager.consumed_offsets().get(&tp), Some(&9));
+
+        let letters = DeadLetterStore::read(path).unwrap();
+        std::fs::remove_file(path).ok();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0].key, "withdraws");
+        assert_eq!(letters[0].offset.as_ref().unwrap().offset, 7);
+        assert_eq!(letters[1].stage, Stage::Quarantined);
+        assert_eq!(letters[1].error, "account 1 is quarantined");
+    }
+
+    #[test]
+    fn test_disabled_full_exit() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut manager = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        let mut processor = Processor::default();
+        let path = std::env::temp_dir().join(format!("dead_letters_full_exit_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        // rejected even when any rejected msg halts, since the state is untouched
+        let mut guard = DeadLetterGuard::new(DeadLetterPolicy::Halt, DeadLetterStore::open(path).unwrap());
+
+        let full_exit = FullExitMessage {
+            serial_id: 3,
+            user_id: 1,
+            asset: "ETH".to_string(),
+        };
+        let (root, tx_num) = (manager.root(), manager.tx_num());
+        guard.handle(&mut processor, &mut manager, WrappedMessage::FULLEXIT(full_exit.into()));
+        assert_eq!((manager.root(), manager.tx_num()), (root, tx_num));
+
+        let letters = DeadLetterStore::read(path).unwrap();
+        std::fs::remove_file(path).ok();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].stage, Stage::Disabled);
+        assert_eq!(letters[0].key, "fullexits");
+        assert_eq!(letters[0].reason(), "disabled");
+    }
+
+    #[test]
+    fn test_reason() {
+        let letter = |stage, error: &str| DeadLetter {
+            stage,
+            offset: None,
+            key: "transfer".to_string(),
+            payload: String::new(),
+            error: error.to_string(),
+        };
+        assert_eq!(letter(Stage::Parse, "invalid sig").reason(), "unparsable");
+        assert_eq!(letter(Stage::Quarantined, "account 1 is quarantined").reason(), "quarantined");
+        assert_eq!(
+            letter(Stage::Process, "invalid sig for transfer TransferTx {..}").reason(),
+            "invalid_signature"
+        );
+        assert_eq!(letter(Stage::Process, "From user must have sufficient balance").reason(), "balance");
+        assert_eq!(letter(Stage::Process, "unknown order 3 of account 1").reason(), "order");
+        assert_eq!(
+            letter(Stage::Process, "assertion failed: `(left == right)`").reason(),
+            "state_mismatch"
+        );
+        assert_eq!(letter(Stage::Process, "unknown panic").reason(), "other");
+    }
+}
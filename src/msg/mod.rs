@@ -1,3 +1,7 @@
+pub mod dead_letter;
 pub mod msg_loader;
+pub mod msg_merge;
 pub mod msg_processor;
+pub mod msg_source;
 pub mod msg_utils;
+pub mod pipeline;
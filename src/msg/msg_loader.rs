@@ -1,92 +1,109 @@
-use crate::test_utils::messages::{parse_msg, WrappedMessage};
-use crate::types::matchengine::messages::{DepositMessage, OrderMessage, TradeMessage, TransferMessage, UserMessage, WithdrawMessage};
+use super::dead_letter::{DeadLetter, DeadLetterStore, Stage};
+use super::msg_merge::PartitionMerger;
+use super::msg_source::MessageSource;
+use super::pipeline::{send_async, PipelineStats};
+use crate::config::{DeadLetterPolicy, Settings};
+use crate::metrics;
+use crate::shutdown::Shutdown;
+use crate::test_utils::messages::WrappedMessage;
+use crate::types::matchengine::messages::{
+    DepositMessage, FullExitMessage, MsgOffset, OrderMessage, PartitionOffsets, TopicPartition, TradeMessage, TransferMessage, UserMessage,
+    WithdrawMessage,
+};
 //use fluidex_common::message::consumer::{Simple, SimpleConsumer, SimpleMessageHandler};
 use fluidex_common::rdkafka;
-use rdkafka::consumer::{Consumer, ConsumerContext, MessageStream, StreamConsumer};
+use rdkafka::consumer::{Consumer, StreamConsumer};
 use rdkafka::error::KafkaError;
 use rdkafka::message::{BorrowedMessage, Message};
 use rdkafka::{Offset, TopicPartitionList};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::time::{Duration, Instant};
 //use std::sync::{Mutex};
 use futures::StreamExt;
 
-pub fn load_msgs_from_file(
-    filepath: &str,
-    sender: crossbeam_channel::Sender<WrappedMessage>,
-) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
-    let filepath = filepath.to_string();
-    println!("loading from {}", filepath);
-    Some(std::thread::spawn(move || {
-        let file = File::open(filepath)?;
-        // since
-        for l in BufReader::new(file).lines() {
-            let msg = parse_msg(l?).expect("invalid data");
-            sender.try_send(msg)?;
-        }
-        Ok(())
-    }))
-}
-
-const UNIFY_TOPIC: &str = "unifyevents";
 const MSG_TYPE_DEPOSITS: &str = "deposits";
+const MSG_TYPE_FULL_EXITS: &str = "fullexits";
 const MSG_TYPE_ORDERS: &str = "orders";
 const MSG_TYPE_TRADES: &str = "trades";
 const MSG_TYPE_TRANSFERS: &str = "transfers";
 const MSG_TYPE_USERS: &str = "registeruser";
 const MSG_TYPE_WITHDRAWS: &str = "withdraws";
 
-pub fn load_msgs_from_mq(
-    brokers: &str,
-    offset: Option<i64>,
-    sender: crossbeam_channel::Sender<WrappedMessage>,
-) -> Option<std::thread::JoinHandle<anyhow::Result<()>>> {
-    let brokers = brokers.to_owned();
-    Some(std::thread::spawn(move || {
+// how often to check whether buffered msgs can be released while no msg arrives
+const MERGE_TICK: Duration = Duration::from_millis(50);
+// how often to retry sending to a full msg channel
+const BACKPRESSURE_RETRY: Duration = Duration::from_millis(10);
+const WATERMARK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// consumes the topic-partitions in `Settings::kafka`, starting after `offsets`
+pub struct KafkaSource {
+    brokers: String,
+    offsets: PartitionOffsets,
+    dead_letters: DeadLetterStore,
+}
+
+impl KafkaSource {
+    pub fn new(brokers: &str, offsets: PartitionOffsets, dead_letters: DeadLetterStore) -> Self {
+        Self {
+            brokers: brokers.to_owned(),
+            offsets,
+            dead_letters,
+        }
+    }
+}
+
+impl MessageSource for KafkaSource {
+    fn run(self: Box<Self>, sender: crossbeam_channel::Sender<WrappedMessage>, shutdown: Shutdown) -> anyhow::Result<()> {
+        let Self {
+            brokers,
+            offsets,
+            dead_letters,
+        } = *self;
+        let settings = Settings::kafka();
         let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
 
+        let partitions = settings.topic_partitions();
         let mut writer = MessageWriter {
             sender,
-            offset: offset.unwrap_or(-1),
+            offsets: partitions.iter().map(|tp| offsets.get(tp).copied().unwrap_or(-1)).collect(),
+            merger: PartitionMerger::new(partitions.len()),
+            watermark_interval: Duration::from_millis(settings.watermark_check_ms),
+            partitions,
+            dead_letters,
+            policy: Settings::dead_letter().policy,
+            lag_interval: Duration::from_secs(Settings::pipeline().lag_report_secs),
         };
         rt.block_on(async move {
             let mut config = rdkafka::config::ClientConfig::new();
             config
                 .set("bootstrap.servers", brokers)
-                .set("group.id", "rollup_msg_consumer")
+                .set("group.id", &settings.group_id)
                 .set("enable.partition.eof", "false")
                 .set("session.timeout.ms", "6000")
                 .set("enable.auto.commit", "false");
-            if offset.is_none() {
+            if offsets.is_empty() {
                 config.set("auto.offset.reset", "earliest");
             }
             let mut consumer: StreamConsumer = config.create().unwrap();
             loop {
                 //alway reset to last offset
                 let handle = tokio::runtime::Handle::current();
-                let assign_offset = writer.last_offset();
+                let assignment = writer.assignment();
                 let join_handle = handle.spawn_blocking(move || {
-                    let mut partitions = TopicPartitionList::new();
-                    let offset = if assign_offset < 0 {
-                        Offset::Beginning
-                    } else {
-                        Offset::Offset(assign_offset)
-                    };
-                    log::debug!("assign offset {:?} to consumer", offset);
-                    partitions.add_partition_offset(UNIFY_TOPIC, 0, offset).unwrap();
-                    consumer.assign(&partitions).unwrap();
+                    log::debug!("assign offsets {:?} to consumer", assignment);
+                    consumer.assign(&assignment).unwrap();
                     return consumer;
                 });
 
                 consumer = join_handle.await.unwrap();
 
                 tokio::select! {
-                    _ = tokio::signal::ctrl_c() => {
-                        log::info!("Ctrl-c received, shutting down");
+                    // msgs still buffered for merging are not consumed, they are fetched again on the next start
+                    _ = shutdown.wait() => {
+                        log::info!("stop consuming kafka");
                         return;
                     },
 
-                    err = writer.handle_stream(consumer.stream()) => {
+                    err = writer.handle_stream(&consumer) => {
                         log::error!("Kafka consumer error: {}", err);
                     }
                 }
@@ -94,79 +111,222 @@ pub fn load_msgs_from_mq(
         });
 
         Ok(())
-    }))
+    }
 }
 
 struct MessageWriter {
     sender: crossbeam_channel::Sender<WrappedMessage>,
-    offset: i64,
+    partitions: Vec<TopicPartition>,
+    // last received offset of each partition, -1 if none
+    offsets: Vec<i64>,
+    merger: PartitionMerger<WrappedMessage>,
+    // how often to check whether the partitions holding back the merge are caught up
+    watermark_interval: Duration,
+    dead_letters: DeadLetterStore,
+    policy: DeadLetterPolicy,
+    lag_interval: Duration,
 }
 
 impl MessageWriter {
-    fn last_offset(&self) -> i64 {
-        self.offset
+    fn assignment(&self) -> TopicPartitionList {
+        let mut list = TopicPartitionList::new();
+        for (tp, &offset) in self.partitions.iter().zip(&self.offsets) {
+            let offset = if offset < 0 { Offset::Beginning } else { Offset::Offset(offset) };
+            list.add_partition_offset(&tp.topic, tp.partition, offset).unwrap();
+        }
+        list
     }
 
-    async fn handle_stream<C, R>(&mut self, mut strm: MessageStream<'_, C, R>) -> KafkaError
-    where
-        C: ConsumerContext + 'static,
-    {
+    async fn handle_stream(&mut self, consumer: &StreamConsumer) -> KafkaError {
+        let mut strm = consumer.stream();
+        let mut lag_reported = Instant::now();
+        let mut watermark_checked = Instant::now();
         loop {
-            match strm.next().await.expect("Kafka's stream has no EOF") {
-                Err(KafkaError::NoMessageReceived) => {} //nothing to do yet
-                Err(KafkaError::PartitionEOF(_)) => {}   //simply omit this type of error
-                Err(e) => {
+            match tokio::time::timeout(MERGE_TICK, strm.next()).await {
+                Err(_) => {} // no new msg, but a caught up partition may release buffered msgs
+                Ok(next) => match next.expect("Kafka's stream has no EOF") {
+                    Err(KafkaError::NoMessageReceived) => {} //nothing to do yet
+                    Err(KafkaError::PartitionEOF(_)) => {}   //simply omit this type of error
+                    Err(e) => {
+                        return e;
+                    }
+                    Ok(m) => self.on_message(&m),
+                },
+            }
+            if watermark_checked.elapsed() >= self.watermark_interval {
+                self.check_watermarks(consumer);
+                watermark_checked = Instant::now();
+            }
+            while let Some(message) = self.merger.pop() {
+                if let Err(e) = self.send(consumer, message).await {
                     return e;
                 }
-                Ok(m) => self.on_message(&m),
+            }
+            if lag_reported.elapsed() >= self.lag_interval {
+                self.report_lags(consumer);
+                lag_reported = Instant::now();
+            }
+        }
+    }
+
+    // pauses fetching while the processor falls behind, so msgs pile up in kafka rather than in memory
+    async fn send(&self, consumer: &StreamConsumer, message: WrappedMessage) -> Result<(), KafkaError> {
+        let message = match self.sender.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(crossbeam_channel::TrySendError::Full(message)) => message,
+            Err(crossbeam_channel::TrySendError::Disconnected(_)) => panic!("msg receiver dropped"),
+        };
+        let assignment = self.assignment();
+        log::warn!("msg channel is full, pause consuming");
+        consumer.pause(&assignment)?;
+        PipelineStats::get().set_source_paused(true);
+        if !send_async(&self.sender, message, BACKPRESSURE_RETRY).await {
+            panic!("msg receiver dropped");
+        }
+        PipelineStats::get().set_source_paused(false);
+        log::info!("msg channel has room, resume consuming");
+        consumer.resume(&assignment)
+    }
+
+    // a partition holding back the merge no longer does once it is read up to its high watermark
+    fn check_watermarks(&mut self, consumer: &StreamConsumer) {
+        for idx in self.merger.blocking() {
+            let tp = &self.partitions[idx];
+            // fetching watermarks is a blocking request
+            match tokio::task::block_in_place(|| consumer.fetch_watermarks(&tp.topic, tp.partition, WATERMARK_TIMEOUT)) {
+                Ok((_, high)) if self.offsets[idx] + 1 >= high => {
+                    log::debug!("{:?} is caught up at {}", tp, high);
+                    self.merger.set_caught_up(idx);
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("failed to fetch watermarks of {:?}: {}", tp, e),
             }
         }
     }
 
+    fn report_lags(&self, consumer: &StreamConsumer) {
+        let stats = PipelineStats::get();
+        for (tp, &offset) in self.partitions.iter().zip(&self.offsets) {
+            // fetching watermarks is a blocking request
+            let watermarks = tokio::task::block_in_place(|| consumer.fetch_watermarks(&tp.topic, tp.partition, WATERMARK_TIMEOUT));
+            metrics::KAFKA_OFFSET
+                .with_label_values(&[&tp.topic, &tp.partition.to_string()])
+                .set(offset);
+            match watermarks {
+                Ok((low, high)) => stats.set_watermarks(tp, low, high),
+                Err(e) => log::warn!("failed to fetch watermarks of {:?}: {}", tp, e),
+            }
+        }
+        log::info!(
+            "msg queue {}, block queue {}, kafka lags {:?}",
+            stats.msg_queue(),
+            stats.block_queue(),
+            stats.lags()
+        );
+    }
+
     fn on_message(&mut self, msg: &BorrowedMessage<'_>) {
-        let msg_type = std::str::from_utf8(msg.key().unwrap()).unwrap();
-        let msg_payload = std::str::from_utf8(msg.payload().unwrap()).unwrap();
+        let idx = match self
+            .partitions
+            .iter()
+            .position(|tp| tp.topic == msg.topic() && tp.partition == msg.partition())
+        {
+            Some(idx) => idx,
+            None => return,
+        };
         let offset: i64 = msg.offset();
-        log::debug!("got message at offset {}", offset);
+        log::debug!("got message of {:?} at offset {}", self.partitions[idx], offset);
 
-        let last_offset: i64 = self.offset;
+        let last_offset: i64 = self.offsets[idx];
         //tolerance re-winded msg
         if offset <= last_offset {
             return;
         }
         if last_offset != 0 && offset != last_offset + 1 {
-            panic!("offset not continuous {} {}", last_offset, offset);
+            panic!("offset of {:?} not continuous {} {}", self.partitions[idx], last_offset, offset);
         }
-        self.offset = offset;
+        self.offsets[idx] = offset;
 
-        let message = match msg_type {
-            MSG_TYPE_DEPOSITS => {
-                let data: DepositMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::DEPOSIT((data, offset).into())
-            }
-            MSG_TYPE_ORDERS => {
-                let data: OrderMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::ORDER((data, offset).into())
-            }
-            MSG_TYPE_TRADES => {
-                let data: TradeMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::TRADE((data, offset).into())
-            }
-            MSG_TYPE_USERS => {
-                let data: UserMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::USER((data, offset).into())
-            }
-            MSG_TYPE_TRANSFERS => {
-                let data: TransferMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::TRANSFER((data, offset).into())
-            }
-            MSG_TYPE_WITHDRAWS => {
-                let data: WithdrawMessage = serde_json::from_str(msg_payload).unwrap();
-                WrappedMessage::WITHDRAW((data, offset).into())
-            }
-            _ => return,
+        let msg_offset = MsgOffset {
+            tp: self.partitions[idx].clone(),
+            offset,
         };
-
-        self.sender.try_send(message).unwrap();
+        let key = String::from_utf8_lossy(msg.key().unwrap_or_default()).into_owned();
+        let payload = String::from_utf8_lossy(msg.payload().unwrap_or_default()).into_owned();
+        let parsed = std::str::from_utf8(msg.key().unwrap_or_default())
+            .and_then(|_| std::str::from_utf8(msg.payload().unwrap_or_default()))
+            .map_err(anyhow::Error::from)
+            .and_then(|_| parse_keyed_msg(&key, &payload, msg_offset.clone()));
+        let timestamp = msg.timestamp().to_millis().unwrap_or(0);
+        match parsed {
+            Ok(message) => self.merger.push(idx, timestamp, offset, message),
+            Err(e) => {
+                let letter = DeadLetter {
+                    stage: Stage::Parse,
+                    offset: Some(msg_offset.clone()),
+                    key,
+                    payload,
+                    error: format!("{:#}", e),
+                };
+                self.dead_letters.record(&letter);
+                // the accounts of a msg which can not be parsed are unknown, so it can only be skipped
+                if self.policy == DeadLetterPolicy::Halt {
+                    panic!("invalid msg {:?}", letter);
+                }
+                // in partition order, so its offset is consumed after the msgs before it
+                self.merger.push(idx, timestamp, offset, WrappedMessage::SKIPPED(msg_offset));
+            }
+        }
     }
 }
+
+// parse a msg keyed by its type, as it is in kafka
+pub fn parse_keyed_msg(msg_type: &str, msg_payload: &str, offset: MsgOffset) -> anyhow::Result<WrappedMessage> {
+    let message = match msg_type {
+        MSG_TYPE_DEPOSITS => {
+            let data: DepositMessage = serde_json::from_str(msg_payload)?;
+            WrappedMessage::DEPOSIT((data, offset).into())
+        }
+        MSG_TYPE_ORDERS => {
+            let data: OrderMessage = serde_json::from_str(msg_payload)?;
+            WrappedMessage::ORDER((data, offset).into())
+        }
+        MSG_TYPE_TRADES => {
+            let data: TradeMessage = serde_json::from_str(msg_payload)?;
+            WrappedMessage::TRADE((data, offset).into())
+        }
+        MSG_TYPE_USERS => {
+            let data: UserMessage = serde_json::from_str(msg_payload)?;
+            WrappedMessage::USER((data, offset).into())
+        }
+        MSG_TYPE_TRANSFERS => {
+            let data: TransferMessage = serde_json::from_str(msg_payload)?;
+            WrappedMessage::TRANSFER((data, offset).into())
+        }
+        MSG_TYPE_WITHDRAWS => {
+            let data: WithdrawMessage = serde_json::from_str(msg_payload)?;
+            WrappedMessage::WITHDRAW((data, offset).into())
+        }
+        MSG_TYPE_FULL_EXITS => {
+            let data: FullExitMessage = serde_json::from_str(msg_payload)?;
+            WrappedMessage::FULLEXIT((data, offset).into())
+        }
+        other => anyhow::bail!("unknown msg type {}", other),
+    };
+    Ok(message)
+}
+
+// the inverse of `parse_keyed_msg`, L1 deposits are not kafka msgs
+pub fn keyed_payload(msg: &WrappedMessage) -> Option<(&'static str, String)> {
+    let (key, payload) = match msg {
+        WrappedMessage::DEPOSIT(m) => (MSG_TYPE_DEPOSITS, serde_json::to_string(&**m)),
+        WrappedMessage::ORDER(m) => (MSG_TYPE_ORDERS, serde_json::to_string(&**m)),
+        WrappedMessage::TRADE(m) => (MSG_TYPE_TRADES, serde_json::to_string(&**m)),
+        WrappedMessage::TRANSFER(m) => (MSG_TYPE_TRANSFERS, serde_json::to_string(&**m)),
+        WrappedMessage::USER(m) => (MSG_TYPE_USERS, serde_json::to_string(&**m)),
+        WrappedMessage::WITHDRAW(m) => (MSG_TYPE_WITHDRAWS, serde_json::to_string(&**m)),
+        WrappedMessage::FULLEXIT(m) => (MSG_TYPE_FULL_EXITS, serde_json::to_string(&**m)),
+        WrappedMessage::L1DEPOSIT(_) | WrappedMessage::SKIPPED(_) => return None,
+    };
+    Some((key, payload.unwrap()))
+}
@@ -0,0 +1,100 @@
+// Kafka only keeps the order of msgs within a partition, while the order in which msgs of different partitions
+// arrive depends on timing. To generate the same blocks when replaying from the same offsets, msgs are merged
+// by (timestamp, index of the partition in settings, offset), and a msg is only released once every other partition
+// has a msg buffered too, or is known to have no more msgs in kafka, i.e. has been read up to its high watermark.
+// A msg appended to a caught up partition later is expected to have a later timestamp than the msgs released
+// meanwhile, so the merged order only depends on the msgs in kafka and never on when they are received.
+use std::collections::VecDeque;
+
+struct Buffered<T> {
+    timestamp: i64,
+    offset: i64,
+    item: T,
+}
+
+pub struct PartitionMerger<T> {
+    queues: Vec<VecDeque<Buffered<T>>>,
+    // the partition is read up to its high watermark
+    caught_up: Vec<bool>,
+}
+
+impl<T> PartitionMerger<T> {
+    pub fn new(partitions: usize) -> Self {
+        Self {
+            queues: (0..partitions).map(|_| VecDeque::new()).collect(),
+            caught_up: vec![false; partitions],
+        }
+    }
+
+    // msgs of the same partition must be pushed in the order of offsets
+    pub fn push(&mut self, partition_idx: usize, timestamp: i64, offset: i64, item: T) {
+        debug_assert!(self.queues[partition_idx].back().map_or(true, |b| b.offset < offset));
+        self.queues[partition_idx].push_back(Buffered { timestamp, offset, item });
+        self.caught_up[partition_idx] = false;
+    }
+
+    // the next offset to read from the partition is its high watermark
+    pub fn set_caught_up(&mut self, partition_idx: usize) {
+        self.caught_up[partition_idx] = true;
+    }
+
+    // partitions which hold back the others, whose high watermark is worth checking
+    pub fn blocking(&self) -> Vec<usize> {
+        if self.buffered() == 0 {
+            return vec![];
+        }
+        (0..self.queues.len())
+            .filter(|&idx| self.queues[idx].is_empty() && !self.caught_up[idx])
+            .collect()
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if !self.blocking().is_empty() {
+            return None;
+        }
+        let (idx, _) = self
+            .queues
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, queue)| queue.front().map(|b| (idx, (b.timestamp, idx, b.offset))))
+            .min_by_key(|(_, key)| *key)?;
+        self.queues[idx].pop_front().map(|b| b.item)
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_order() {
+        let mut merger = PartitionMerger::new(2);
+        merger.push(1, 10, 0, "b0");
+        merger.push(1, 30, 1, "b1");
+        // partition 0 may still have earlier msgs
+        assert_eq!(merger.pop(), None);
+        assert_eq!(merger.blocking(), vec![0]);
+        merger.push(0, 10, 5, "a5");
+        merger.push(0, 20, 6, "a6");
+        // ties are broken by the partition index
+        assert_eq!(merger.pop(), Some("a5"));
+        assert_eq!(merger.pop(), Some("b0"));
+        assert_eq!(merger.pop(), Some("a6"));
+        assert_eq!(merger.pop(), None);
+        // until partition 0 is read up to its high watermark, however long it takes
+        merger.set_caught_up(0);
+        assert_eq!(merger.pop(), Some("b1"));
+        assert_eq!(merger.buffered(), 0);
+        assert!(merger.blocking().is_empty());
+
+        // a new msg means the partition may have more
+        merger.push(0, 40, 7, "a7");
+        merger.push(1, 50, 2, "b2");
+        assert_eq!(merger.pop(), Some("a7"));
+        assert_eq!(merger.pop(), None);
+    }
+}
@@ -1,5 +1,8 @@
+use crate::l1::deposit::{to_l2_amount, DepositEvent, DEFAULT_L1_DECIMALS};
+use crate::metrics;
 use crate::msg::msg_utils::bytes_to_sig;
 use crate::state::ManagerWrapper;
+use crate::test_utils::messages::WrappedMessage;
 use crate::test_utils::types::{get_token_id_by_name, prec_token_id};
 use crate::types::l2::{self, OrderInput, OrderSide};
 use crate::types::matchengine::messages;
@@ -10,30 +13,96 @@ use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::types::{DecimalExt, FrExt};
 use fluidex_common::Fr;
 use num::Zero;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
-use std::time::Instant;
 
 use super::msg_utils::{check_state, exchange_order_to_rollup_order, TokenIdPair, TokenPair};
 
 pub struct Processor {
     pub enable_check_sig: bool,
-    pub balance_tx_total_time: f32,
-    pub trade_tx_total_time: f32,
-    pub transfer_tx_total_time: f32,
+    // see `Settings::place_order_tx`
+    pub enable_place_order: bool,
+    // see `Settings::full_exit_tx`
+    pub enable_full_exit: bool,
+    // deposits come from L1 instead of the exchange, see `Settings::l1_deposit`
+    pub enable_l1_deposit: bool,
+    // see `L1DepositSettings::token_decimals`
+    pub l1_token_decimals: BTreeMap<u32, u32>,
 }
 
 impl Default for Processor {
     fn default() -> Self {
         Processor {
             enable_check_sig: true,
-            balance_tx_total_time: 0.0,
-            trade_tx_total_time: 0.0,
-            transfer_tx_total_time: 0.0,
+            enable_place_order: false,
+            enable_full_exit: false,
+            enable_l1_deposit: false,
+            l1_token_decimals: BTreeMap::new(),
         }
     }
 }
 
+fn msg_type(msg: &WrappedMessage) -> &'static str {
+    match msg {
+        WrappedMessage::DEPOSIT(_) => "deposit",
+        WrappedMessage::ORDER(_) => "order",
+        WrappedMessage::TRADE(_) => "trade",
+        WrappedMessage::TRANSFER(_) => "transfer",
+        WrappedMessage::USER(_) => "user",
+        WrappedMessage::WITHDRAW(_) => "withdraw",
+        WrappedMessage::FULLEXIT(_) => "full_exit",
+        WrappedMessage::L1DEPOSIT(_) => "l1_deposit",
+        WrappedMessage::SKIPPED(_) => "skipped",
+    }
+}
+
+// the id of the exchange event a msg is about, to look up its txs by. transfers and L1 deposits have none
+pub fn source_id(msg: &WrappedMessage) -> Option<String> {
+    // balance msgs carry the id of the exchange event in their json `detail`
+    fn detail_id(detail: &str) -> Option<String> {
+        let detail: serde_json::Value = serde_json::from_str(detail).ok()?;
+        match detail.get("id")? {
+            serde_json::Value::String(id) => Some(id.clone()),
+            serde_json::Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        }
+    }
+
+    match msg {
+        WrappedMessage::DEPOSIT(m) => detail_id(&m.detail).map(|id| format!("deposit:{}", id)),
+        WrappedMessage::ORDER(m) => Some(format!("order:{}", m.order.id)),
+        WrappedMessage::TRADE(m) => Some(format!("trade:{}", m.id)),
+        WrappedMessage::TRANSFER(_) => None,
+        WrappedMessage::USER(m) => Some(format!("user:{}", m.user_id)),
+        WrappedMessage::WITHDRAW(m) => detail_id(&m.detail).map(|id| format!("withdraw:{}", id)),
+        WrappedMessage::FULLEXIT(m) => Some(format!("full_exit:{}", m.serial_id)),
+        WrappedMessage::L1DEPOSIT(_) | WrappedMessage::SKIPPED(_) => None,
+    }
+}
+
 impl Processor {
+    // why the msg is rejected without touching the state, for txs which are disabled by settings
+    pub fn disabled_reason(&self, msg: &WrappedMessage) -> Option<&'static str> {
+        match msg {
+            WrappedMessage::FULLEXIT(_) if !self.enable_full_exit => Some("full exit txs are disabled, see Settings::full_exit_tx"),
+            _ => None,
+        }
+    }
+    pub fn handle_msg(&mut self, manager: &mut ManagerWrapper, msg: WrappedMessage) {
+        let _timer = metrics::MSG_HANDLE_SECONDS.with_label_values(&[msg_type(&msg)]).start_timer();
+        manager.set_tx_source(source_id(&msg));
+        match msg {
+            WrappedMessage::DEPOSIT(deposit) => self.handle_deposit_msg(manager, deposit),
+            WrappedMessage::ORDER(order) => self.handle_order_msg(manager, order),
+            WrappedMessage::TRADE(trade) => self.handle_trade_msg(manager, trade),
+            WrappedMessage::TRANSFER(transfer) => self.handle_transfer_msg(manager, transfer),
+            WrappedMessage::USER(user) => self.handle_user_msg(manager, user),
+            WrappedMessage::WITHDRAW(withdraw) => self.handle_withdraw_msg(manager, withdraw),
+            WrappedMessage::FULLEXIT(full_exit) => self.handle_full_exit_msg(manager, full_exit),
+            WrappedMessage::L1DEPOSIT(deposit) => self.handle_l1_deposit(manager, deposit),
+            WrappedMessage::SKIPPED(offset) => manager.consume_offset(offset),
+        }
+    }
     pub fn handle_user_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::UserMessage>) {
         let (user_info, offset) = message.into_parts();
         //println!("handle_user_msg {:#?}", user_info);
@@ -63,6 +132,10 @@ impl Processor {
     pub fn handle_deposit_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::DepositMessage>) {
         let (deposit, offset) = message.into_parts();
         assert!(!deposit.change.is_sign_negative(), "should be a deposit");
+        if self.enable_l1_deposit {
+            log::debug!("skip deposit msg {:?}, deposits are read from L1", deposit);
+            return;
+        }
 
         let token_id = get_token_id_by_name(&deposit.asset);
         let account_id = deposit.user_id;
@@ -73,7 +146,6 @@ impl Processor {
         let expected_balance_before = manager.get_token_balance(deposit.user_id, token_id);
         assert_eq!(expected_balance_before, balance_before.to_fr(prec_token_id(token_id)));
 
-        let timing = Instant::now();
         let amount = deposit.change.to_u64(prec_token_id(token_id));
 
         /*
@@ -91,8 +163,30 @@ impl Processor {
                 offset,
             )
             .unwrap();
-
-        self.balance_tx_total_time += timing.elapsed().as_secs_f32();
+    }
+    // a deposit which can not be credited is still consumed, so it is not read from L1 again after a restart
+    pub fn handle_l1_deposit(&mut self, manager: &mut ManagerWrapper, event: DepositEvent) {
+        let offset = event.msg_offset();
+        if !manager.has_account(event.account_id) {
+            log::error!("reject l1 deposit {:?} to unregistered account", event);
+            metrics::L1_DEPOSITS_REJECTED.inc();
+            manager.consume_offset(offset);
+            return;
+        }
+        let l1_decimals = self.l1_token_decimals.get(&event.token_id).copied().unwrap_or(DEFAULT_L1_DECIMALS);
+        let (amount, dust) = match to_l2_amount(event.amount, l1_decimals, prec_token_id(event.token_id)) {
+            Some(converted) => converted,
+            None => {
+                log::error!("reject l1 deposit {:?} whose amount overflows", event);
+                metrics::L1_DEPOSITS_REJECTED.inc();
+                manager.consume_offset(offset);
+                return;
+            }
+        };
+        if dust > 0 {
+            log::warn!("l1 deposit {:?} is rounded down to {}, dust {}", event, amount, dust);
+        }
+        manager.deposit(event.to_deposit_tx(amount), Some(offset)).unwrap();
     }
     pub fn handle_withdraw_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::WithdrawMessage>) {
         let (withdraw, offset) = message.into_parts();
@@ -111,7 +205,6 @@ impl Processor {
         let precision = prec_token_id(token_id);
         let amount = (-withdraw.change).to_u64(precision);
 
-        let timing = Instant::now();
         let raw_sig = bytes_to_sig(withdraw.signature);
         let mut withdraw_tx = l2::WithdrawTx::new(account_id, token_id, amount as u128, balance_before.to_fr(precision));
         withdraw_tx.sig = Signature::from_raw(withdraw_tx.hash(), &raw_sig);
@@ -119,11 +212,37 @@ impl Processor {
             check_withdraw_sig(manager, &withdraw_tx, &raw_sig);
         }
         manager.withdraw(withdraw_tx, offset);
-        self.transfer_tx_total_time += timing.elapsed().as_secs_f32();
+    }
+    pub fn handle_full_exit_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::FullExitMessage>) {
+        let (full_exit, offset) = message.into_parts();
+        let token_id = get_token_id_by_name(&full_exit.asset);
+        log::info!(
+            "full exit #{} of user {} asset {}",
+            full_exit.serial_id,
+            full_exit.user_id,
+            full_exit.asset
+        );
+
+        manager.full_exit(
+            l2::FullExitTx {
+                account_id: full_exit.user_id,
+                token_id,
+            },
+            offset,
+        );
     }
     pub fn handle_order_msg(&mut self, manager: &mut ManagerWrapper, message: messages::Message<messages::OrderMessage>) {
-        let (order, _) = message.into_parts();
+        let (order, offset) = message.into_parts();
         match order.event {
+            messages::OrderEventType::FINISH if self.enable_place_order && manager.has_order(order.order.user, order.order.id as u32) => {
+                let (account_id, order_id) = (order.order.user, order.order.id as u32);
+                if manager.get_account_order_by_id(account_id, order_id).is_filled() {
+                    // a filled slot can be reused already
+                    manager.cancel_order(account_id, order_id);
+                } else {
+                    manager.cancel_order_tx(account_id, order_id, offset);
+                }
+            }
             messages::OrderEventType::FINISH => {
                 debug_assert_eq!(order.order.finished_base.is_zero(), order.order.finished_quote.is_zero());
                 if order.order.finished_base.is_zero() {
@@ -139,6 +258,24 @@ impl Processor {
                     manager.cancel_order(order.order.user, order.order.id as u32);
                 }
             }
+            messages::OrderEventType::PUT if self.enable_place_order => {
+                let (account_id, order_id) = (order.order.user, order.order.id as u32);
+                // the order may be put into the tree already by a trade, if the trade message comes first
+                if manager.has_order(account_id, order_id) {
+                    return;
+                }
+                // placing into a full tree would panic, so the order is left to its first trade as without
+                // PlaceOrder txs, when some slot may be free again
+                if !manager.has_free_order_slot(account_id, order_id) {
+                    log::warn!("no free slot to place order {} of user {}", order_id, account_id);
+                    return;
+                }
+                let order_input = exchange_order_to_rollup_order(&order.order);
+                if self.enable_check_sig {
+                    self.check_order_sig(manager, &order_input);
+                }
+                manager.place_order(l2::Order::from(order_input), offset);
+            }
             messages::OrderEventType::PUT => {}
             _ => {
                 log::debug!("skip order msg {:?}", order.event);
@@ -152,10 +289,11 @@ impl Processor {
             check_state(manager, state_before, &trade);
         }
 
-        let timing = Instant::now();
         let mut taker_order: Option<l2::Order> = None;
         let mut maker_order: Option<l2::Order> = None;
-        if let Some(ask_order_origin) = &trade.ask_order {
+        // with PlaceOrder txs, orders in the trade may have been placed already
+        let is_new_order = |o: &&messages::Order| !(self.enable_place_order && manager.has_order(o.user, o.id as u32));
+        if let Some(ask_order_origin) = trade.ask_order.as_ref().filter(is_new_order) {
             let ask_order_input = exchange_order_to_rollup_order(ask_order_origin);
             if self.enable_check_sig {
                 self.check_order_sig(manager, &ask_order_input);
@@ -171,7 +309,7 @@ impl Processor {
                 }
             };
         }
-        if let Some(bid_order_origin) = &trade.bid_order {
+        if let Some(bid_order_origin) = trade.bid_order.as_ref().filter(is_new_order) {
             let bid_order_input = exchange_order_to_rollup_order(bid_order_origin);
             if self.enable_check_sig {
                 self.check_order_sig(manager, &bid_order_input);
@@ -193,7 +331,6 @@ impl Processor {
             maker_order,
         };
         manager.full_spot_trade(tx, offset);
-        self.trade_tx_total_time += timing.elapsed().as_secs_f32();
         if let Some(state_after) = &trade.state_after {
             check_state(manager, state_after, &trade);
         }
@@ -209,8 +346,9 @@ impl Processor {
         assert!(from_balance >= amount, "From user must have sufficient balance");
 
         let to = transfer.user_to;
+        // rejected before touching the state, see `ManagerWrapper::transfer`
+        assert_ne!(from, to, "transfer to self is not allowed");
 
-        let timing = Instant::now();
         let raw_sig = bytes_to_sig(transfer.signature);
         let mut transfer_tx = l2::TransferTx::new(from, to, token_id, amount.to_u64(prec_token_id(token_id)) as u128);
         transfer_tx.sig = Signature::from_raw(transfer_tx.hash(), &raw_sig);
@@ -218,7 +356,6 @@ impl Processor {
             check_transfer_sig(manager, &transfer_tx, &raw_sig);
         }
         manager.transfer(transfer_tx, offset);
-        self.transfer_tx_total_time += timing.elapsed().as_secs_f32();
     }
     fn trade_into_spot_tx(&self, trade: &messages::TradeMessage) -> l2::SpotTradeTx {
         //allow information can be obtained from trade
@@ -284,13 +421,6 @@ impl Processor {
             .check_sig(order_to_put.account_id, &msg, &sig)
             .unwrap_or_else(|_| panic!("invalid sig for order {:?}", order_to_put));
     }
-
-    pub fn take_bench(&mut self) -> (f32, f32) {
-        let ret = (self.trade_tx_total_time, self.balance_tx_total_time);
-        self.trade_tx_total_time = 0.0;
-        self.balance_tx_total_time = 0.0;
-        ret
-    }
 }
 
 fn check_transfer_sig(manager: &ManagerWrapper, transfer: &l2::TransferTx, sig: &SignatureBJJ) {
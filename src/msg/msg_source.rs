@@ -0,0 +1,308 @@
+// Where msgs come from. Production, tests and benches all feed `WrappedMessage` through a `MessageSource`,
+// so they share the same processing path.
+use super::dead_letter::{self, DeadLetterStore};
+use super::msg_loader::{parse_keyed_msg, KafkaSource};
+use super::msg_merge::PartitionMerger;
+use super::pipeline::PipelineStats;
+use crate::config::{MsgSourceSettings, Settings};
+use crate::shutdown::Shutdown;
+use crate::test_utils::messages::{parse_msg, WrappedMessage};
+use crate::types::matchengine::messages::{MsgOffset, PartitionOffsets, TopicPartition};
+use anyhow::{anyhow, Context, Result};
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+pub trait MessageSource: Send {
+    // sends msgs until the source is exhausted or `shutdown` is triggered, a source like kafka may never be exhausted
+    fn run(self: Box<Self>, sender: crossbeam_channel::Sender<WrappedMessage>, shutdown: Shutdown) -> Result<()>;
+}
+
+pub fn spawn(
+    source: Box<dyn MessageSource>,
+    sender: crossbeam_channel::Sender<WrappedMessage>,
+    shutdown: Shutdown,
+) -> std::thread::JoinHandle<Result<()>> {
+    std::thread::spawn(move || source.run(sender, shutdown))
+}
+
+// all msgs of a finite source
+pub fn read_all(source: Box<dyn MessageSource>) -> Result<Vec<WrappedMessage>> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    source.run(sender, Shutdown::default())?;
+    Ok(receiver.try_iter().collect())
+}
+
+// `offsets` are the last consumed ones, only later msgs are sent
+pub fn from_settings(
+    settings: &MsgSourceSettings,
+    offsets: PartitionOffsets,
+    dead_letters: DeadLetterStore,
+) -> Result<Box<dyn MessageSource>> {
+    Ok(match settings {
+        MsgSourceSettings::Kafka => Box::new(KafkaSource::new(Settings::brokers(), offsets, dead_letters)),
+        MsgSourceSettings::File { path } => Box::new(JsonLinesSource::file(path)?.skip_to(&offsets)),
+        MsgSourceSettings::Stdin => Box::new(JsonLinesSource::stdin().skip_to(&offsets)),
+        MsgSourceSettings::Replay { table } => Box::new(ArchiveReplaySource::new(Settings::db(), table, offsets)),
+    })
+}
+
+/// Msgs in json lines, as written by the exchange for tests: `{"type": "OrderMessage", "value": {...}}`.
+/// The offset of a msg is its line number.
+pub struct JsonLinesSource {
+    reader: Box<dyn BufRead + Send>,
+    tp: TopicPartition,
+    // lines up to this one are skipped
+    last_offset: i64,
+}
+
+impl JsonLinesSource {
+    pub fn new(reader: Box<dyn BufRead + Send>, name: &str) -> Self {
+        Self {
+            reader,
+            tp: TopicPartition {
+                topic: name.to_string(),
+                partition: 0,
+            },
+            last_offset: -1,
+        }
+    }
+
+    pub fn file(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("failed to open {}", path))?;
+        Ok(Self::new(Box::new(BufReader::new(file)), "file"))
+    }
+
+    pub fn stdin() -> Self {
+        Self::new(Box::new(BufReader::new(io::stdin())), "stdin")
+    }
+
+    pub fn skip_to(mut self, offsets: &PartitionOffsets) -> Self {
+        self.last_offset = offsets.get(&self.tp).copied().unwrap_or(-1);
+        self
+    }
+}
+
+impl MessageSource for JsonLinesSource {
+    fn run(self: Box<Self>, sender: crossbeam_channel::Sender<WrappedMessage>, shutdown: Shutdown) -> Result<()> {
+        for (offset, line) in self.reader.lines().enumerate() {
+            if shutdown.is_triggered() {
+                log::info!("stop reading {:?} at line {}", self.tp, offset);
+                break;
+            }
+            let (offset, line) = (offset as i64, line?);
+            if offset <= self.last_offset || line.trim().is_empty() {
+                continue;
+            }
+            let msg = parse_msg(line).with_context(|| format!("invalid msg at line {}", offset))?;
+            let msg_offset = MsgOffset {
+                tp: self.tp.clone(),
+                offset,
+            };
+            if sender.send(msg.with_offset(msg_offset)).is_err() {
+                break;
+            }
+        }
+        PipelineStats::get().set_source_exhausted();
+        Ok(())
+    }
+}
+
+/// Replays kafka msgs archived in postgres, e.g. by a kafka connect sink, with the columns:
+/// `topic, partition_id, msg_offset, msg_key, payload, timestamp_ms`.
+/// Msgs are merged in the same order as they are consumed from kafka, reading a page of each partition at a time.
+pub struct ArchiveReplaySource {
+    db: String,
+    table: String,
+    offsets: PartitionOffsets,
+}
+
+impl ArchiveReplaySource {
+    pub fn new(db: &str, table: &str, offsets: PartitionOffsets) -> Self {
+        Self {
+            db: db.to_string(),
+            table: table.to_string(),
+            offsets,
+        }
+    }
+}
+
+const REPLAY_PAGE_SIZE: i64 = 10000;
+
+// an archived msg as stored, parsed with `parse_keyed_msg`
+struct ArchivedMsg {
+    offset: i64,
+    key: String,
+    payload: String,
+    timestamp_ms: i64,
+}
+
+impl MessageSource for ArchiveReplaySource {
+    fn run(self: Box<Self>, sender: crossbeam_channel::Sender<WrappedMessage>, shutdown: Shutdown) -> Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let pool = rt.block_on(PgPool::connect(&self.db))?;
+        let stmt = format!(
+            "select msg_offset, msg_key, payload, timestamp_ms from {}
+            where topic = $1 and partition_id = $2 and msg_offset > $3 order by msg_offset limit $4",
+            self.table
+        );
+        let fetch_page = |tp: &TopicPartition, after: i64, limit: i64| {
+            rt.block_on(async {
+                let rows = sqlx::query(&stmt)
+                    .bind(&tp.topic)
+                    .bind(tp.partition)
+                    .bind(after)
+                    .bind(limit)
+                    .fetch_all(&pool)
+                    .await?;
+                rows.iter()
+                    .map(|row| -> Result<_> {
+                        Ok(ArchivedMsg {
+                            offset: row.try_get("msg_offset")?,
+                            key: row.try_get("msg_key")?,
+                            payload: row.try_get("payload")?,
+                            timestamp_ms: row.try_get("timestamp_ms")?,
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+        };
+        replay_archive(
+            &Settings::kafka().topic_partitions(),
+            &self.offsets,
+            REPLAY_PAGE_SIZE,
+            fetch_page,
+            &sender,
+            &shutdown,
+        )?;
+        PipelineStats::get().set_source_exhausted();
+        Ok(())
+    }
+}
+
+// merges the pages returned by `fetch_page(tp, after_offset, limit)`. A partition is read up to its end
+// once a page is shorter than `page_size`, until then its next page is fetched as soon as its buffered msgs run out
+fn replay_archive<F>(
+    partitions: &[TopicPartition],
+    offsets: &PartitionOffsets,
+    page_size: i64,
+    mut fetch_page: F,
+    sender: &crossbeam_channel::Sender<WrappedMessage>,
+    shutdown: &Shutdown,
+) -> Result<()>
+where
+    F: FnMut(&TopicPartition, i64, i64) -> Result<Vec<ArchivedMsg>>,
+{
+    let mut merger = PartitionMerger::new(partitions.len());
+    let mut last_offsets: Vec<i64> = partitions.iter().map(|tp| offsets.get(tp).copied().unwrap_or(-1)).collect();
+    let mut exhausted = vec![false; partitions.len()];
+    loop {
+        let pending: Vec<usize> = if merger.buffered() == 0 {
+            (0..partitions.len()).filter(|&idx| !exhausted[idx]).collect()
+        } else {
+            merger.blocking()
+        };
+        if pending.is_empty() && merger.buffered() == 0 {
+            return Ok(());
+        }
+        for idx in pending {
+            let tp = &partitions[idx];
+            let page = fetch_page(tp, last_offsets[idx], page_size)?;
+            log::debug!("replay {} msgs of {:?} after offset {}", page.len(), tp, last_offsets[idx]);
+            exhausted[idx] = (page.len() as i64) < page_size;
+            for archived in page {
+                let msg_offset = MsgOffset {
+                    tp: tp.clone(),
+                    offset: archived.offset,
+                };
+                let msg = parse_keyed_msg(&archived.key, &archived.payload, msg_offset)
+                    .with_context(|| format!("invalid archived msg {} of {:?}", archived.offset, tp))?;
+                merger.push(idx, archived.timestamp_ms, archived.offset, msg);
+                last_offsets[idx] = archived.offset;
+            }
+            // set after the pushes, which clear it
+            if exhausted[idx] {
+                merger.set_caught_up(idx);
+            }
+        }
+        while let Some(msg) = merger.pop() {
+            if shutdown.is_triggered() {
+                log::info!("stop replaying at {:?}", dead_letter::msg_offset(&msg));
+                return Ok(());
+            }
+            sender.send(msg).map_err(|_| anyhow!("msg receiver dropped"))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_lines_source() {
+        let lines = r#"{"type": "UserMessage", "value": {"user_id": 0, "l1_address": "0x00", "l2_pubkey": "0x00"}}
+
+{"type": "UserMessage", "value": {"user_id": 1, "l1_address": "0x01", "l2_pubkey": "0x01"}}
+"#;
+        let source = JsonLinesSource::new(Box::new(io::Cursor::new(lines)), "test");
+        let msgs = read_all(Box::new(source)).unwrap();
+        assert_eq!(msgs.len(), 2);
+        match &msgs[1] {
+            WrappedMessage::USER(user) => {
+                assert_eq!(user.user_id, 1);
+                assert_eq!(user.offset().unwrap().offset, 2);
+            }
+            other => panic!("unexpected msg {:?}", other),
+        }
+
+        // resume after the first msg
+        let tp = TopicPartition {
+            topic: "test".to_string(),
+            partition: 0,
+        };
+        let offsets = PartitionOffsets::from([(tp, 0)]);
+        let source = JsonLinesSource::new(Box::new(io::Cursor::new(lines)), "test").skip_to(&offsets);
+        assert_eq!(read_all(Box::new(source)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_archive_replay() {
+        let tps: Vec<TopicPartition> = (0..3)
+            .map(|partition| TopicPartition {
+                topic: "unifyevents".to_string(),
+                partition,
+            })
+            .collect();
+        // (partition, offset, timestamp), partition 2 is empty
+        let archive = vec![(0, 0, 10), (0, 1, 30), (0, 2, 50), (0, 3, 60), (1, 4, 20), (1, 5, 40), (1, 6, 45)];
+        let fetch_page = |tp: &TopicPartition, after: i64, limit: i64| -> Result<Vec<ArchivedMsg>> {
+            Ok(archive
+                .iter()
+                .filter(|(partition, offset, _)| *partition == tp.partition && *offset > after)
+                .take(limit as usize)
+                .map(|&(_, offset, timestamp_ms)| ArchivedMsg {
+                    offset,
+                    key: "registeruser".to_string(),
+                    payload: format!(r#"{{"user_id": {}, "l1_address": "0x00", "l2_pubkey": "0x00"}}"#, offset),
+                    timestamp_ms,
+                })
+                .collect())
+        };
+        let replayed = |offsets: &PartitionOffsets| {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            replay_archive(&tps, offsets, 2, fetch_page, &sender, &Shutdown::default()).unwrap();
+            receiver
+                .try_iter()
+                .map(|msg| dead_letter::msg_offset(&msg).unwrap().offset)
+                .collect::<Vec<_>>()
+        };
+
+        // in the order of timestamps across pages of 2, without waiting for the empty partition
+        assert_eq!(replayed(&PartitionOffsets::new()), vec![0, 4, 1, 5, 6, 2, 3]);
+        // resumed after the consumed offsets
+        let offsets = PartitionOffsets::from([(tps[0].clone(), 1), (tps[1].clone(), 4)]);
+        assert_eq!(replayed(&offsets), vec![5, 6, 2, 3]);
+    }
+}
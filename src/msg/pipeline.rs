@@ -0,0 +1,163 @@
+// The ingestion pipeline is: msg source -> msg channel -> processor -> block channel -> db writer.
+// Both channels are bounded, so a slow db writer blocks the processor, and a slow processor pauses the source
+// (for kafka, fetching is paused so msgs are not buffered in memory either).
+// Queue depths and kafka lags are collected here to be logged and exported.
+// Lags are counted from the offsets consumed by the processor, so msgs received but still queued are lagging too.
+use crate::types::matchengine::messages::{PartitionOffsets, TopicPartition};
+use crossbeam_channel::{Sender, TrySendError};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static STATS: Lazy<PipelineStats> = Lazy::new(PipelineStats::default);
+
+#[derive(Default)]
+pub struct PipelineStats {
+    msg_queue: AtomicUsize,
+    // blocks sent by the processor and saved by the db writer, the blocks between are in the witness or block channel
+    blocks_sent: AtomicUsize,
+    blocks_saved: AtomicUsize,
+    source_paused: AtomicBool,
+    // a finite source has sent all its msgs
+    source_exhausted: AtomicBool,
+    // low and high watermarks of kafka, by partition
+    watermarks: Mutex<BTreeMap<TopicPartition, (i64, i64)>>,
+    // offsets of the last msgs the processor has consumed
+    consumed: Mutex<PartitionOffsets>,
+}
+
+impl PipelineStats {
+    pub fn get() -> &'static Self {
+        &STATS
+    }
+
+    pub fn set_msg_queue(&self, msg_queue: usize) {
+        self.msg_queue.store(msg_queue, Ordering::Relaxed);
+    }
+
+    pub fn msg_queue(&self) -> usize {
+        self.msg_queue.load(Ordering::Relaxed)
+    }
+
+    pub fn add_sent_block(&self) {
+        self.blocks_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_saved_block(&self) {
+        self.blocks_saved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // blocks sealed but not saved yet
+    pub fn block_queue(&self) -> usize {
+        let saved = self.blocks_saved.load(Ordering::Relaxed);
+        self.blocks_sent.load(Ordering::Relaxed).saturating_sub(saved)
+    }
+
+    pub fn set_source_paused(&self, paused: bool) {
+        self.source_paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn source_paused(&self) -> bool {
+        self.source_paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_source_exhausted(&self) {
+        self.source_exhausted.store(true, Ordering::Relaxed);
+    }
+
+    // whether the saved state is about as new as the msg source, for readiness probes
+    pub fn caught_up(&self, max_lag: i64, max_block_queue: usize) -> bool {
+        if self.block_queue() > max_block_queue {
+            return false;
+        }
+        let lags = self.lags();
+        if lags.is_empty() {
+            // no lag is known before the first report, or for a source other than kafka
+            return self.source_exhausted.load(Ordering::Relaxed) && self.msg_queue() == 0;
+        }
+        lags.values().sum::<i64>() <= max_lag
+    }
+
+    pub fn set_watermarks(&self, tp: &TopicPartition, low: i64, high: i64) {
+        self.watermarks.lock().unwrap().insert(tp.clone(), (low, high));
+    }
+
+    pub fn set_consumed(&self, offsets: &PartitionOffsets) {
+        self.consumed.lock().unwrap().clone_from(offsets);
+    }
+
+    // msgs in kafka not consumed by the processor yet, by partition
+    pub fn lags(&self) -> BTreeMap<TopicPartition, i64> {
+        let consumed = self.consumed.lock().unwrap();
+        self.watermarks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tp, &(low, high))| {
+                let next = consumed.get(tp).map_or(low, |offset| low.max(offset + 1));
+                (tp.clone(), (high - next).max(0))
+            })
+            .collect()
+    }
+}
+
+/// Sends `item` without blocking the async runtime, polling every `retry` while the channel is full.
+/// Returns false if the receiver is dropped.
+pub async fn send_async<T>(sender: &Sender<T>, mut item: T, retry: Duration) -> bool {
+    loop {
+        match sender.try_send(item) {
+            Ok(()) => return true,
+            Err(TrySendError::Disconnected(_)) => return false,
+            Err(TrySendError::Full(back)) => {
+                item = back;
+                tokio::time::sleep(retry).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caught_up() {
+        let stats = PipelineStats::default();
+        let tp = TopicPartition {
+            topic: "test".to_string(),
+            partition: 0,
+        };
+        assert!(!stats.caught_up(0, 0));
+        stats.set_watermarks(&tp, 0, 10);
+        // received but not consumed msgs are lagging
+        assert_eq!(stats.lags()[&tp], 10);
+        stats.set_consumed(&[(tp.clone(), 9)].into_iter().collect());
+        assert!(stats.caught_up(0, 0));
+        // blocks are not saved yet
+        stats.add_sent_block();
+        assert!(!stats.caught_up(0, 0));
+        stats.add_saved_block();
+        assert!(stats.caught_up(0, 0));
+    }
+
+    #[test]
+    fn test_send_async() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        sender.send(0).unwrap();
+        let consumer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            receiver.iter().collect::<Vec<_>>()
+        });
+        // waits until the consumer takes the first item
+        assert!(rt.block_on(send_async(&sender, 1, Duration::from_millis(5))));
+        drop(sender);
+        assert_eq!(consumer.join().unwrap(), vec![0, 1]);
+
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        drop(receiver);
+        assert!(!rt.block_on(send_async(&sender, 0, Duration::from_millis(5))));
+    }
+}
@@ -0,0 +1,98 @@
+// Graceful shutdown, in the order of the pipeline:
+//   1. the msg sources stop, dropping their msg senders
+//   2. the processor drains the msg channel, handles the pending block by `PendingBlockPolicy`,
+//      takes a final snapshot, and drops the block sender
+//   3. the db writer saves the remaining blocks
+//   4. the grpc server stops
+// All within `ShutdownSettings::timeout_secs` of Ctrl-C, or the process exits anyway. Since the db is the
+// source of truth for the next start, an exit in the middle loses no data, it only means more msgs to replay.
+use crate::config::ShutdownSettings;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// A one-shot signal, which can be checked by blocking loops or awaited by async ones.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.sender.send(true).ok();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    pub async fn wait(&self) {
+        let mut receiver = self.receiver.clone();
+        while !*receiver.borrow() {
+            // the sender lives as long as `self`
+            if receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Signals for the stages which do not stop by themselves when the stage before them stops.
+#[derive(Clone, Default)]
+pub struct ShutdownCoordinator {
+    /// stops the msg sources
+    pub intake: Shutdown,
+    /// stops the grpc server, after all blocks are saved
+    pub server: Shutdown,
+}
+
+impl ShutdownCoordinator {
+    // starts the shutdown on Ctrl-C, and exits the process if it does not finish within the timeout,
+    // or on a second Ctrl-C
+    pub async fn watch_signal(self, settings: &ShutdownSettings) {
+        if tokio::signal::ctrl_c().await.is_err() {
+            log::error!("failed to listen to Ctrl-C");
+            return;
+        }
+        log::info!("Ctrl-C received, shutting down");
+        self.intake.trigger();
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(settings.timeout_secs)) => {
+                log::error!("shutdown does not finish in {}s, exit", settings.timeout_secs);
+            }
+            _ = tokio::signal::ctrl_c() => {
+                log::warn!("Ctrl-C received again, exit");
+            }
+        }
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Shutdown::default();
+        let cloned = shutdown.clone();
+        assert!(!cloned.is_triggered());
+        let waiter = std::thread::spawn(move || futures::executor::block_on(cloned.wait()));
+        shutdown.trigger();
+        waiter.join().unwrap();
+        assert!(shutdown.is_triggered());
+        // waiting after the trigger returns at once
+        futures::executor::block_on(shutdown.wait());
+    }
+}
@@ -47,6 +47,18 @@ pub struct AccountUpdates {
     pub new_nonce: Option<Fr>,
 }
 
+// A tree mutation made by a tx, or by recovery from public data.
+#[derive(Clone)]
+pub enum StateUpdate {
+    InitAccount { account_id: u32, next_order_id: u32 },
+    Balance { account_id: u32, token_id: u32, balance: Fr },
+    L2Addr { account_id: u32, sign: Fr, ay: Fr },
+    Nonce { account_id: u32, nonce: Fr },
+    Order { account_id: u32, order_pos: u32, order: Order },
+    ClearOrder { account_id: u32, order_pos: u32 },
+    Batch(Vec<AccountUpdates>),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum GlobalStateError {
     #[error(transparent)]
@@ -195,6 +207,12 @@ impl GlobalState {
     pub fn root(&self) -> Fr {
         self.account_tree.lock().unwrap().get_root()
     }
+    pub fn account_num(&self) -> usize {
+        self.account_states.len()
+    }
+    pub fn order_num(&self) -> usize {
+        self.order_id_to_pos.len()
+    }
     fn recalculate_account_state_hash(&mut self, account_id: u32) -> Fr {
         let mut acc = self.account_states.get_mut(&account_id).unwrap();
         // TODO: for balance_root/order_root, we maintain two 'truth' here
@@ -241,6 +259,19 @@ impl GlobalState {
         !self.get_account(account_id).ay.is_zero()
     }
 
+    // whether `get_next_order_pos_for_user` can find a slot for the order without panicking
+    pub fn has_free_order_slot(&self, account_id: u32, order_id: u32) -> bool {
+        let order_state_tree = match self.order_states.get(&account_id) {
+            Some(tree) => tree,
+            None => return false,
+        };
+        order_state_tree.len() < self.max_order_num_per_user as usize
+            || order_state_tree.values().any(|order| {
+                order.is_default()
+                    || (self.allow_overwrite_order_leaf && (order.is_filled() || !order.is_active) && order.order_id < order_id)
+            })
+    }
+
     // find a position range 0..2**n where the slot is either empty or occupied by a close order
     // so we can place the new order here
     fn get_next_order_pos_for_user(&mut self, account_id: u32, order_id: u32) -> u32 {
@@ -255,6 +286,11 @@ impl GlobalState {
             return order_num as u32;
         }
         // now the tree is full
+        // a slot freed by `clear_account_order` holds the empty order, reusing it overwrites nothing
+        if let Some((&pos, _)) = order_state_tree.iter().find(|(_, order)| order.is_default()) {
+            log::debug!("reuse cleared order slot uid {} new order {} at {}", account_id, order_id, pos);
+            return pos;
+        }
         // we have to find a vicvim order to replace
         if self.allow_overwrite_order_leaf {
             let start_pos = *self.next_order_positions.get(&account_id).unwrap();
@@ -339,6 +375,19 @@ impl GlobalState {
         self.flush_account_state(account_id);
     }
 
+    // free the slot of an order in the tree: the leaf is reset to the empty order,
+    // and the slot can be taken by any later order
+    pub fn clear_account_order(&mut self, account_id: u32, order_pos: u32) {
+        let old_order = self.get_account_order_by_pos(account_id, order_pos);
+        self.order_id_to_pos.remove(&(account_id, old_order.order_id));
+        let empty_order = Order {
+            is_active: false,
+            ..Order::default()
+        };
+        self.order_states.get_mut(&account_id).unwrap().insert(order_pos, empty_order);
+        self.set_order_leaf_hash(account_id, order_pos, self.default_order_leaf);
+    }
+
     pub fn update_order_state(&mut self, account_id: u32, order_pos: u32, order: Order) {
         self.order_states.get_mut(&account_id).unwrap().insert(order_pos, order);
     }
@@ -380,6 +429,21 @@ impl GlobalState {
         }
         self.balance_trees.get(&account_id).unwrap().lock().unwrap().get_leaf(token_id)
     }
+    // non-zero balances of the account, by token id. Also of an account without a key yet,
+    // e.g. one created by `set_token_balance`
+    pub fn get_token_balances(&self, account_id: u32) -> Vec<(u32, Fr)> {
+        let tree = match self.balance_trees.get(&account_id) {
+            Some(tree) => tree.lock().unwrap(),
+            None => return Vec::new(),
+        };
+        let mut balances: Vec<(u32, Fr)> = tree
+            .iter()
+            .filter(|(_, balance)| !balance.is_zero())
+            .map(|(token_id, balance)| (token_id, *balance))
+            .collect();
+        balances.sort_by_key(|(token_id, _)| *token_id);
+        balances
+    }
     pub fn set_token_balance(&mut self, account_id: u32, token_id: u32, balance: Fr) {
         if !self.account_states.contains_key(&account_id) {
             self.init_account(account_id, self.default_next_order_id).unwrap();
@@ -445,6 +509,27 @@ impl GlobalState {
             }
         }
     }
+    pub fn apply_update(&mut self, update: &StateUpdate) {
+        match update {
+            StateUpdate::InitAccount { account_id, next_order_id } => {
+                self.init_account(*account_id, *next_order_id).unwrap();
+            }
+            StateUpdate::Balance {
+                account_id,
+                token_id,
+                balance,
+            } => self.set_token_balance(*account_id, *token_id, *balance),
+            StateUpdate::L2Addr { account_id, sign, ay } => self.set_account_l2_addr(*account_id, *sign, *ay),
+            StateUpdate::Nonce { account_id, nonce } => self.set_account_nonce(*account_id, *nonce),
+            StateUpdate::Order {
+                account_id,
+                order_pos,
+                order,
+            } => self.set_account_order(*account_id, *order_pos, *order),
+            StateUpdate::ClearOrder { account_id, order_pos } => self.clear_account_order(*account_id, *order_pos),
+            StateUpdate::Batch(updates) => self.batch_update(updates.clone(), true),
+        }
+    }
     pub fn set_token_balance_raw(&mut self, account_id: u32, token_id: u32, balance: Fr) {
         assert!(self.balance_trees.contains_key(&account_id), "set_token_balance");
         let tree = self.balance_trees.get_mut(&account_id).unwrap().clone();
@@ -696,3 +781,52 @@ impl GlobalState {
         Self::save_serializable_map(db, &self.next_order_positions)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fluidex_common::types::FrExt;
+
+    #[test]
+    fn test_token_balances() {
+        let mut state = GlobalState::new(3, 2, 2, false);
+        state.set_token_balance(1, 5, Fr::from_u32(7));
+        state.set_token_balance(1, 2, Fr::from_u32(3));
+        state.set_token_balance(1, 0, Fr::from_u32(1));
+        state.set_token_balance(1, 0, Fr::zero());
+        // listed before the account is registered with a key
+        assert_eq!(state.get_token_balances(1), vec![(2, Fr::from_u32(3)), (5, Fr::from_u32(7))]);
+
+        state.set_account_l2_addr(1, Fr::one(), Fr::from_u32(9));
+        assert_eq!(state.get_token_balances(1), vec![(2, Fr::from_u32(3)), (5, Fr::from_u32(7))]);
+        assert!(state.get_token_balances(2).is_empty());
+    }
+
+    #[test]
+    fn test_reuse_cleared_order_slot() {
+        let mut state = GlobalState::new(2, 1, 2, false);
+        state.allow_overwrite_order_leaf = false;
+        state.init_account(1, 0).unwrap();
+        let order = |order_id| Order {
+            account_id: 1,
+            order_id,
+            total_sell: Fr::from_u32(10),
+            total_buy: Fr::from_u32(10),
+            ..Order::default()
+        };
+        for order_id in 1..=2 {
+            let (pos, _) = state.find_or_insert_order(1, &order(order_id));
+            state.set_account_order(1, pos, order(order_id));
+        }
+        state.clear_account_order(1, 0);
+        assert!(!state.has_order(1, 1));
+
+        // the tree is full and nothing can be overwritten, but the cleared slot is free
+        assert!(state.has_free_order_slot(1, 3));
+        let (pos, old_order) = state.find_or_insert_order(1, &order(3));
+        assert_eq!(pos, 0);
+        assert!(old_order.is_default());
+        state.set_account_order(1, pos, order(3));
+        assert!(!state.has_free_order_slot(1, 4));
+    }
+}
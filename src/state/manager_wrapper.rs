@@ -1,13 +1,17 @@
 #![allow(clippy::field_reassign_with_default)]
 #![allow(clippy::vec_init_then_push)]
 
-use super::global::{AccountUpdates, GlobalState};
+use super::global::{AccountUpdates, GlobalState, StateUpdate};
+use super::witness::{AppliedTx, WitnessBuilder, WitnessPlan};
+use super::AccountState;
+use crate::metrics;
 use crate::types::l2::{
     tx_detail_idx,
     tx_encode::{self, EncodeForScheme},
-    AmountType, DepositTx, FullSpotTradeTx, L2Block, L2BlockDetail, Order, RawTx, TransferTx, TxDataEncoder, TxType, UpdateKeyTx,
-    WithdrawTx, TX_LENGTH,
+    AmountType, DepositTx, FullExitTx, FullSpotTradeTx, L2Block, L2BlockDetail, Order, PubDataAux, RawTx, TransferTx, TxDataEncoder,
+    TxReceipt, TxType, UpdateKeyTx, WithdrawTx, TX_LENGTH,
 };
+use crate::types::matchengine::messages::{MsgOffset, PartitionOffsets};
 use crate::types::merkle_tree::Tree;
 use anyhow::{anyhow, bail};
 use fluidex_common::babyjubjub_rs::{self, Point};
@@ -15,6 +19,7 @@ use fluidex_common::ff::Field;
 use fluidex_common::l2::account::{L2Account, SignatureBJJ};
 use fluidex_common::{num_bigint::BigInt, num_traits::ToPrimitive};
 use fluidex_common::{types::FrExt, Fr};
+use std::collections::BTreeSet;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 cfg_if::cfg_if! {
@@ -30,12 +35,65 @@ pub struct ManagerWrapper {
     state: Arc<RwLock<GlobalState>>,
     n_tx: usize,
     // 0 <= len(buffered_txs) < n_tx
-    buffered_txs: Vec<RawTx>,
+    buffered_txs: Vec<AppliedTx>,
+    // witnesses are built only when blocks are sealed
+    witness_builder: WitnessBuilder,
     block_generate_num: usize,
     //buffered_blocks: Vec<L2Block>,
-    tx_data_encoder: TxDataEncoder,
+    // balance, order and account bits of the pubdata
+    tx_bits: (u32, u32, u32),
     verbose: bool,
     verify_sig: bool,
+    // offsets of the last msgs which have been included in popped blocks
+    consumed_offsets: PartitionOffsets,
+    // accounts whose msgs are rejected, see `DeadLetterPolicy::QuarantineAccount`.
+    // Persisted with the offsets, so msgs replayed after a restart are rejected the same way
+    quarantined: BTreeSet<u32>,
+    // the source id of the msg being handled, recorded in the receipts of its txs
+    tx_source: Option<String>,
+}
+
+fn tx_receipts(txs: &[AppliedTx]) -> Vec<TxReceipt> {
+    txs.iter()
+        .enumerate()
+        .filter(|(_, tx)| tx.tx_type != TxType::Nop)
+        .map(|(tx_index, tx)| TxReceipt {
+            tx_index,
+            tx_type: tx.tx_type,
+            offset: tx.offset.clone(),
+            source_id: tx.source_id.clone(),
+            root_before: tx.root_before,
+            root_after: tx.root_after,
+        })
+        .collect()
+}
+
+/// A full block whose txs are applied to the state, but whose witness is not built yet.
+/// [`SealedBlock::build`] does not touch the state, so it can run on another thread.
+pub struct SealedBlock {
+    pub block_id: usize,
+    pub txs: Vec<AppliedTx>,
+    pub kafka_offsets: PartitionOffsets,
+    witness_builder: WitnessBuilder,
+    tx_bits: (u32, u32, u32),
+}
+
+impl SealedBlock {
+    pub fn new_root(&self) -> Fr {
+        self.txs.last().unwrap().root_after
+    }
+
+    pub fn build(self) -> L2Block {
+        let receipts = tx_receipts(&self.txs);
+        let witness_builder = &self.witness_builder;
+        let txs: Vec<RawTx> = self.txs.into_iter().map(|tx| witness_builder.build(tx)).collect();
+        let (balance_bits, order_bits, account_bits) = self.tx_bits;
+        let mut encoder = TxDataEncoder::new(balance_bits, order_bits, account_bits);
+        let mut block = ManagerWrapper::forge_with_txs(self.block_id, &txs, &mut encoder);
+        block.receipts = receipts;
+        block.kafka_offsets = self.kafka_offsets;
+        block
+    }
 }
 
 fn encode_amount_to_compressed_fr(amount: &AmountType) -> anyhow::Result<Fr> {
@@ -127,6 +185,25 @@ fn encode_rawtx_to_pubdata(tx: &RawTx, encoder: &mut TxDataEncoder) -> anyhow::R
             tx_encode::ForCommonTx(tx).encode(encoder)
         }
 
+        // TODO: the circuit has no full exit yet, see `Settings::full_exit_tx`
+        TxType::FullExit => {
+            encoder.encode_heading(3)?; //110
+            tx_encode::ForCommonTx(tx).encode(encoder)
+        }
+
+        // the new order leaf, which is the empty order for a cancel.
+        // TODO: the circuit has no place order yet, see `Settings::place_order_tx`
+        TxType::PlaceOrder => {
+            encoder.encode_heading(5)?; //101
+            encoder.encode_fr(&payload[tx_detail_idx::ACCOUNT_ID1], encoder.account_bits)?;
+            encoder.encode_fr(&payload[tx_detail_idx::ORDER1_POS], encoder.order_bits)?;
+            encoder.encode_fr(&payload[tx_detail_idx::NEW_ORDER1_ID], 32)?;
+            encoder.encode_fr(&payload[tx_detail_idx::NEW_ORDER1_TOKEN_SELL], encoder.token_bits)?;
+            encoder.encode_fr(&payload[tx_detail_idx::NEW_ORDER1_TOKEN_BUY], encoder.token_bits)?;
+            encoder.encode_fr(&payload[tx_detail_idx::NEW_ORDER1_AMOUNT_SELL], 40)?;
+            encoder.encode_fr(&payload[tx_detail_idx::NEW_ORDER1_AMOUNT_BUY], 40)
+        }
+
         TxType::SpotTrade => {
             let mut h = 0;
             let order1_filled = payload[tx_detail_idx::NEW_ORDER1_FILLED_BUY]
@@ -161,20 +238,27 @@ impl ManagerWrapper {
         Tree::print_config();
     }
     pub fn new(state: Arc<RwLock<GlobalState>>, n_tx: usize, block_offset: Option<usize>, verbose: bool) -> Self {
-        let tx_data_encoder = {
+        let (tx_bits, witness_builder) = {
             let st = state.read().unwrap();
-            TxDataEncoder::new(st.balance_bits() as u32, st.order_bits() as u32, st.account_bits() as u32)
+            (
+                (st.balance_bits() as u32, st.order_bits() as u32, st.account_bits() as u32),
+                WitnessBuilder::new(&st),
+            )
         };
 
         Self {
             state,
             n_tx,
             buffered_txs: Vec::new(),
+            witness_builder,
             block_generate_num: block_offset.unwrap_or(0),
             //buffered_blocks: Vec::new(),
-            tx_data_encoder,
+            tx_bits,
             verbose,
             verify_sig: true,
+            consumed_offsets: PartitionOffsets::new(),
+            quarantined: BTreeSet::new(),
+            tx_source: None,
         }
     }
 
@@ -185,6 +269,9 @@ impl ManagerWrapper {
     pub fn has_order(&self, account_id: u32, order_id: u32) -> bool {
         self.state().has_order(account_id, order_id)
     }
+    pub fn has_free_order_slot(&self, account_id: u32, order_id: u32) -> bool {
+        self.state().has_free_order_slot(account_id, order_id)
+    }
     pub fn has_account(&self, account_id: u32) -> bool {
         self.state().has_account(account_id)
     }
@@ -198,25 +285,39 @@ impl ManagerWrapper {
     //    self.state.update_order_state(account_id, order)
     //}
     pub fn create_new_account(&mut self, next_order_id: u32) -> anyhow::Result<u32> {
-        self.mut_state().create_new_account(next_order_id)
+        let account_id = self.state().get_next_account_id()?;
+        self.apply_update(StateUpdate::InitAccount { account_id, next_order_id });
+        Ok(account_id)
     }
     pub fn get_account_order_by_id(&self, account_id: u32, order_id: u32) -> Order {
         self.state().get_account_order_by_id(account_id, order_id)
     }
     pub fn set_account_l2_addr(&mut self, account_id: u32, sign: Fr, ay: Fr) {
-        self.mut_state().set_account_l2_addr(account_id, sign, ay);
+        self.apply_update(StateUpdate::L2Addr { account_id, sign, ay });
     }
     pub fn set_account_nonce(&mut self, account_id: u32, nonce: Fr) {
-        self.mut_state().set_account_nonce(account_id, nonce);
+        self.apply_update(StateUpdate::Nonce { account_id, nonce });
     }
     pub fn get_account_nonce(&self, account_id: u32) -> Fr {
         self.state().get_account_nonce(account_id)
     }
     pub fn set_account_order(&mut self, account_id: u32, order_pos: u32, order: Order) {
-        self.mut_state().set_account_order(account_id, order_pos, order);
+        self.apply_update(StateUpdate::Order {
+            account_id,
+            order_pos,
+            order,
+        });
     }
     pub fn set_token_balance(&mut self, account_id: u32, token_id: u32, balance: Fr) {
-        self.mut_state().set_token_balance(account_id, token_id, balance);
+        self.apply_update(StateUpdate::Balance {
+            account_id,
+            token_id,
+            balance,
+        });
+    }
+    // state updates outside of txs have no witness, e.g. mocking accounts in tests
+    fn apply_update(&mut self, update: StateUpdate) {
+        self.mut_state().apply_update(&update);
     }
 
     pub fn forge_with_txs(block_id: usize, buffered_txs: &[RawTx], encoder: &mut TxDataEncoder) -> L2Block {
@@ -260,28 +361,37 @@ impl ManagerWrapper {
             old_account_roots,
             new_account_roots,
         };
+        let public_data_aux = PubDataAux::from(&detail);
         L2Block {
             block_id,
             detail,
             public_data,
+            public_data_aux,
+            kafka_offsets: PartitionOffsets::new(),
+            receipts: Vec::new(),
         }
     }
     pub fn has_raw_tx(&self) -> bool {
         !self.buffered_txs.is_empty()
     }
-    pub fn add_raw_tx(&mut self, raw_tx: RawTx) {
-        self.buffered_txs.push(raw_tx);
+    pub fn add_applied_tx(&mut self, mut tx: AppliedTx) {
+        metrics::TXS_APPLIED.with_label_values(&[tx.tx_type.as_str()]).inc();
+        tx.source_id = self.tx_source.clone();
+        self.buffered_txs.push(tx);
+    }
+    // txs applied from now on are from the msg of `source_id`
+    pub fn set_tx_source(&mut self, source_id: Option<String>) {
+        self.tx_source = source_id;
     }
     pub fn get_block_generate_num(&self) -> usize {
         self.block_generate_num
     }
-    pub fn key_update(&mut self, tx: UpdateKeyTx, offset: Option<i64>) -> anyhow::Result<()> {
+    pub fn key_update(&mut self, tx: UpdateKeyTx, offset: Option<MsgOffset>) -> anyhow::Result<()> {
         let mut state = self.mut_state();
         if state.has_account(tx.account_id) {
             bail!("current update key can only set key for un-inited account");
         }
         let fake_token_id = 0;
-        let proof = state.balance_full_proof(tx.account_id, fake_token_id);
         let acc = state.get_account(tx.account_id);
         let old_balance = state.get_token_balance(tx.account_id, fake_token_id);
         let nonce = acc.nonce;
@@ -305,34 +415,35 @@ impl ManagerWrapper {
         encoded_tx[tx_detail_idx::ENABLE_BALANCE_CHECK2] = Fr::one();
         encoded_tx[tx_detail_idx::DST_IS_NEW] = Fr::one();
 
-        let mut raw_tx = RawTx {
-            tx_type: TxType::Deposit,
-            payload: encoded_tx.to_vec(),
-            balance_path0: proof.balance_path.clone(),
-            balance_path1: proof.balance_path.clone(),
-            balance_path2: proof.balance_path.clone(),
-            balance_path3: proof.balance_path,
-            order_path0: state.trivial_order_path_elements(),
-            order_path1: state.trivial_order_path_elements(),
-            order_root0: acc.order_root,
-            order_root1: acc.order_root,
-            account_path0: proof.account_path.clone(),
-            account_path1: proof.account_path,
-            root_before: proof.root,
-            root_after: Fr::zero(),
+        let updates = vec![
+            StateUpdate::Balance {
+                account_id: tx.account_id,
+                token_id: fake_token_id,
+                balance: old_balance,
+            },
+            StateUpdate::L2Addr {
+                account_id: tx.account_id,
+                sign: tx.l2key.sign,
+                ay: tx.l2key.ay,
+            },
+        ];
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::Deposit,
+            encoded_tx.to_vec(),
+            WitnessPlan::SingleBalance {
+                account_id: tx.account_id,
+                token_id: fake_token_id,
+            },
+            updates,
             offset,
-        };
-
-        state.set_token_balance(tx.account_id, fake_token_id, old_balance);
-        state.set_account_l2_addr(tx.account_id, tx.l2key.sign, tx.l2key.ay);
-        let new_root = state.root();
+        );
         drop(state);
-        log::debug!("finish update key tx {:?} new root {}", tx, new_root);
-        raw_tx.root_after = new_root;
-        self.add_raw_tx(raw_tx);
+        log::debug!("finish update key tx {:?} new root {}", tx, applied_tx.root_after);
+        self.add_applied_tx(applied_tx);
         Ok(())
     }
-    pub fn deposit(&mut self, tx: DepositTx, offset: Option<i64>) -> anyhow::Result<()> {
+    pub fn deposit(&mut self, tx: DepositTx, offset: Option<MsgOffset>) -> anyhow::Result<()> {
         let mut state = self.mut_state();
         //TODO: deposit to new has been deprecated after key_update is induced
         let deposit_to_new = tx.l2key.is_some();
@@ -343,7 +454,6 @@ impl ManagerWrapper {
             bail!("deposit to old, but account not existed");
         }
         // assert!(state.accounts.get(tx.account_id).eth_addr != 0n, "deposit_to_old");
-        let proof = state.balance_full_proof(tx.account_id, tx.token_id);
         let acc = state.get_account(tx.account_id);
         let old_balance = state.get_token_balance(tx.account_id, tx.token_id);
         let nonce = acc.nonce;
@@ -375,38 +485,36 @@ impl ManagerWrapper {
         encoded_tx[tx_detail_idx::ENABLE_BALANCE_CHECK2] = Fr::one();
         encoded_tx[tx_detail_idx::DST_IS_NEW] = if deposit_to_new { Fr::one() } else { Fr::zero() };
 
-        let mut raw_tx = RawTx {
-            tx_type: TxType::Deposit,
-            payload: encoded_tx.to_vec(),
-            balance_path0: proof.balance_path.clone(),
-            balance_path1: proof.balance_path.clone(),
-            balance_path2: proof.balance_path.clone(),
-            balance_path3: proof.balance_path,
-            order_path0: state.trivial_order_path_elements(),
-            order_path1: state.trivial_order_path_elements(),
-            order_root0: acc.order_root,
-            order_root1: acc.order_root,
-            account_path0: proof.account_path.clone(),
-            account_path1: proof.account_path,
-            root_before: proof.root,
-            root_after: Fr::zero(),
-            offset,
-        };
-
         let mut balance = old_balance;
         balance.add_assign(&Fr::from_bigint(BigInt::from(tx.amount)));
-        state.set_token_balance(tx.account_id, tx.token_id, balance);
+        let mut updates = vec![StateUpdate::Balance {
+            account_id: tx.account_id,
+            token_id: tx.token_id,
+            balance,
+        }];
         if deposit_to_new {
             let l2key = tx.l2key.clone().unwrap();
-            state.set_account_l2_addr(tx.account_id, l2key.sign, l2key.ay);
+            updates.push(StateUpdate::L2Addr {
+                account_id: tx.account_id,
+                sign: l2key.sign,
+                ay: l2key.ay,
+            });
         }
-
-        let new_root = state.root();
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::Deposit,
+            encoded_tx.to_vec(),
+            WitnessPlan::SingleBalance {
+                account_id: tx.account_id,
+                token_id: tx.token_id,
+            },
+            updates,
+            offset,
+        );
         drop(state);
-        log::debug!("finish deposit tx {:?} new root {}", tx, new_root);
-        raw_tx.root_after = new_root;
+        log::debug!("finish deposit tx {:?} new root {}", tx, applied_tx.root_after);
 
-        self.add_raw_tx(raw_tx);
+        self.add_applied_tx(applied_tx);
         Ok(())
     }
     pub fn fill_withdraw_tx(&self, tx: &mut WithdrawTx) {
@@ -414,14 +522,15 @@ impl ManagerWrapper {
         tx.nonce = state.get_account(tx.account_id).nonce;
         tx.old_balance = state.get_token_balance(tx.account_id, tx.token_id);
     }
-    pub fn transfer(&mut self, tx: TransferTx, offset: Option<i64>) {
+    pub fn transfer(&mut self, tx: TransferTx, offset: Option<MsgOffset>) {
+        // the public data of a transfer to self is the same as a deposit, see `TxDataDecoder::decode_tx`
+        assert_ne!(tx.from, tx.to, "transfer to self is not allowed {:?}", tx);
         let mut state = self.mut_state();
         if !state.has_account(tx.from) {
             panic!("invalid account {:?}", tx);
         }
 
         let transfer_to_new = tx.l2key.is_some();
-        let proof_from = state.balance_full_proof(tx.from, tx.token_id);
         let from_account = state.get_account(tx.from);
         // when transfer_to_new, `to_account` will be an empty account
         let to_account = state.get_account(tx.to);
@@ -485,43 +594,37 @@ impl ManagerWrapper {
             balance_updates: vec![(tx.token_id, to_new_balance)],
             ..Default::default()
         };
-        state.batch_update(vec![acc1_updates, acc2_updates], true);
-
-        let proof_to = state.balance_full_proof(tx.to, tx.token_id);
-
+        let mut updates = vec![StateUpdate::Batch(vec![acc1_updates, acc2_updates])];
         if transfer_to_new {
             // transfer_to_new is rarely used
             let l2key = tx.l2key.unwrap();
-            state.set_account_l2_addr(tx.to, l2key.sign, l2key.ay);
+            updates.push(StateUpdate::L2Addr {
+                account_id: tx.to,
+                sign: l2key.sign,
+                ay: l2key.ay,
+            });
         }
-
-        let raw_tx = RawTx {
-            tx_type: TxType::Transfer,
-            payload: encoded_tx.to_vec(),
-            balance_path0: proof_from.balance_path.clone(),
-            balance_path1: proof_to.balance_path.clone(),
-            balance_path2: proof_from.balance_path,
-            balance_path3: proof_to.balance_path,
-            order_path0: state.trivial_order_path_elements(),
-            order_path1: state.trivial_order_path_elements(),
-            order_root0: from_account.order_root,
-            order_root1: to_account.order_root,
-            account_path0: proof_from.account_path,
-            account_path1: proof_to.account_path,
-            root_before: proof_from.root,
-            root_after: state.root(),
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::Transfer,
+            encoded_tx.to_vec(),
+            WitnessPlan::Transfer {
+                from: tx.from,
+                to: tx.to,
+                token_id: tx.token_id,
+            },
+            updates,
             offset,
-        };
+        );
 
         drop(state);
-        self.add_raw_tx(raw_tx);
+        self.add_applied_tx(applied_tx);
     }
-    pub fn withdraw(&mut self, tx: WithdrawTx, offset: Option<i64>) {
+    pub fn withdraw(&mut self, tx: WithdrawTx, offset: Option<MsgOffset>) {
         // assert(this.accounts.get(tx.accountID).ethAddr != 0n, 'Withdraw');
         let account_id = tx.account_id;
         let token_id = tx.token_id;
         let mut state = self.mut_state();
-        let proof = state.balance_full_proof(account_id, token_id);
 
         let acc = state.get_account(account_id);
         let old_balance = state.get_token_balance(account_id, token_id);
@@ -557,30 +660,201 @@ impl ManagerWrapper {
         encoded_tx[tx_detail_idx::R8X1] = tx.sig.r8x;
         encoded_tx[tx_detail_idx::R8Y1] = tx.sig.r8y;
 
-        let mut raw_tx = RawTx {
-            tx_type: TxType::Withdraw,
-            payload: encoded_tx.to_vec(),
-            balance_path0: proof.balance_path.clone(),
-            balance_path1: proof.balance_path.clone(),
-            balance_path2: proof.balance_path.clone(),
-            balance_path3: proof.balance_path,
-            order_path0: state.trivial_order_path_elements(),
-            order_path1: state.trivial_order_path_elements(),
-            order_root0: acc.order_root,
-            order_root1: acc.order_root,
-            account_path0: proof.account_path.clone(),
-            account_path1: proof.account_path,
-            root_before: proof.root,
-            root_after: Fr::zero(),
+        let updates = vec![
+            StateUpdate::Balance {
+                account_id,
+                token_id,
+                balance: new_balance,
+            },
+            StateUpdate::Nonce {
+                account_id,
+                nonce: nonce.add(&Fr::one()),
+            },
+        ];
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::Withdraw,
+            encoded_tx.to_vec(),
+            WitnessPlan::SingleBalance { account_id, token_id },
+            updates,
             offset,
+        );
+        drop(state);
+
+        self.add_applied_tx(applied_tx);
+    }
+
+    // the whole balance is withdrawn, so the tx never fails, even for an empty account,
+    // as the L1 contract expects every full exit request in its priority queue to be executed
+    pub fn full_exit(&mut self, tx: FullExitTx, offset: Option<MsgOffset>) {
+        let account_id = tx.account_id;
+        let token_id = tx.token_id;
+        let mut state = self.mut_state();
+        if account_id >= 2u32.pow(state.account_bits() as u32) || token_id >= 2u32.pow(state.balance_bits() as u32) {
+            drop(state);
+            log::warn!("full exit {:?} is out of the tree range, exit nothing instead", tx);
+            self.empty_full_exit(offset);
+            return;
+        }
+
+        let acc = state.get_account(account_id);
+        let old_balance = state.get_token_balance(account_id, token_id);
+
+        let mut encoded_tx = [Fr::zero(); TX_LENGTH];
+        encoded_tx[tx_detail_idx::AMOUNT] = old_balance;
+
+        encoded_tx[tx_detail_idx::TOKEN_ID1] = Fr::from_u32(token_id);
+        encoded_tx[tx_detail_idx::ACCOUNT_ID1] = Fr::from_u32(account_id);
+        encoded_tx[tx_detail_idx::BALANCE1] = old_balance;
+        encoded_tx[tx_detail_idx::NONCE1] = acc.nonce;
+        encoded_tx[tx_detail_idx::SIGN1] = acc.sign;
+        encoded_tx[tx_detail_idx::AY1] = acc.ay;
+
+        encoded_tx[tx_detail_idx::TOKEN_ID2] = Fr::from_u32(token_id);
+        encoded_tx[tx_detail_idx::ACCOUNT_ID2] = Fr::from_u32(account_id);
+        encoded_tx[tx_detail_idx::BALANCE2] = Fr::zero();
+        // unlike withdraw, the nonce is not increased since the tx is not signed by the account
+        encoded_tx[tx_detail_idx::NONCE2] = acc.nonce;
+        encoded_tx[tx_detail_idx::SIGN2] = acc.sign;
+        encoded_tx[tx_detail_idx::AY2] = acc.ay;
+
+        encoded_tx[tx_detail_idx::ENABLE_BALANCE_CHECK1] = Fr::one();
+        encoded_tx[tx_detail_idx::ENABLE_BALANCE_CHECK2] = Fr::one();
+
+        // nothing to change for an empty balance, and we do not want to init an unknown account here
+        let updates = if old_balance.is_zero() {
+            vec![]
+        } else {
+            vec![StateUpdate::Balance {
+                account_id,
+                token_id,
+                balance: Fr::zero(),
+            }]
         };
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::FullExit,
+            encoded_tx.to_vec(),
+            WitnessPlan::SingleBalance { account_id, token_id },
+            updates,
+            offset,
+        );
+        drop(state);
+        log::debug!("finish full exit tx {:?} amount {}", tx, old_balance.to_decimal_string());
+
+        self.add_applied_tx(applied_tx);
+    }
+
+    // an exit of nothing from the leaf of `trivial_state_proof`, which still takes the position of
+    // the L1 priority op in the block. The ids of the request do not fit in public data so they are not encoded
+    fn empty_full_exit(&mut self, offset: Option<MsgOffset>) {
+        let mut state = self.mut_state();
+        let acc = state.get_account(0);
+        let mut encoded_tx = [Fr::zero(); TX_LENGTH];
+        encoded_tx[tx_detail_idx::NONCE1] = acc.nonce;
+        encoded_tx[tx_detail_idx::SIGN1] = acc.sign;
+        encoded_tx[tx_detail_idx::AY1] = acc.ay;
+        encoded_tx[tx_detail_idx::BALANCE1] = state.get_token_balance(0, 0);
+        encoded_tx[tx_detail_idx::NONCE2] = acc.nonce;
+        encoded_tx[tx_detail_idx::SIGN2] = acc.sign;
+        encoded_tx[tx_detail_idx::AY2] = acc.ay;
+        encoded_tx[tx_detail_idx::BALANCE2] = state.get_token_balance(0, 0);
+        let applied_tx = AppliedTx::apply(&mut state, TxType::FullExit, encoded_tx.to_vec(), WitnessPlan::Nop, vec![], offset);
+        drop(state);
+        self.add_applied_tx(applied_tx);
+    }
+
+    // put a new order into the order tree before it trades
+    pub fn place_order(&mut self, order: Order, offset: Option<MsgOffset>) {
+        let account_id = order.account_id;
+        let mut state = self.mut_state();
+        assert!(state.has_account(account_id), "place order for unknown account {}", account_id);
+        assert!(
+            !state.has_order(account_id, order.order_id),
+            "order {} already placed",
+            order.order_id
+        );
+        assert_eq!(order.filled_sell, Fr::zero());
+        assert_eq!(order.filled_buy, Fr::zero());
 
-        state.set_token_balance(account_id, token_id, new_balance);
-        state.increase_nonce(account_id);
-        raw_tx.root_after = state.root();
+        let (order_pos, old_order) = state.find_or_insert_order(account_id, &order);
+        let acc = state.get_account(account_id);
+        let mut encoded_tx = Self::encode_order_tx(&acc, account_id, order_pos, &old_order, &order);
+        encoded_tx[tx_detail_idx::S1] = order.sig.s;
+        encoded_tx[tx_detail_idx::R8X1] = order.sig.r8x;
+        encoded_tx[tx_detail_idx::R8Y1] = order.sig.r8y;
+        encoded_tx[tx_detail_idx::SIG_L2_HASH1] = order.sig.hash;
+        encoded_tx[tx_detail_idx::ENABLE_SIG_CHECK1] = Fr::one();
+
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::PlaceOrder,
+            encoded_tx.to_vec(),
+            WitnessPlan::Order { account_id, order_pos },
+            vec![StateUpdate::Order {
+                account_id,
+                order_pos,
+                order,
+            }],
+            offset,
+        );
         drop(state);
+        log::debug!("place order {} of account {} at {}", order.order_id, account_id, order_pos);
+        self.add_applied_tx(applied_tx);
+    }
 
-        self.add_raw_tx(raw_tx);
+    // unlike `cancel_order`, which only marks the order inactive in the local state,
+    // this is a PlaceOrder tx of the empty order, so the slot is freed in the proven state too
+    pub fn cancel_order_tx(&mut self, account_id: u32, order_id: u32, offset: Option<MsgOffset>) {
+        let mut state = self.mut_state();
+        let order_pos = state
+            .get_order_pos_by_id(account_id, order_id)
+            .unwrap_or_else(|| panic!("unknown order {} of account {}", order_id, account_id));
+        let old_order = state.get_account_order_by_id(account_id, order_id);
+        let acc = state.get_account(account_id);
+        let encoded_tx = Self::encode_order_tx(&acc, account_id, order_pos, &old_order, &Order::default());
+
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::PlaceOrder,
+            encoded_tx.to_vec(),
+            WitnessPlan::Order { account_id, order_pos },
+            vec![StateUpdate::ClearOrder { account_id, order_pos }],
+            offset,
+        );
+        drop(state);
+        log::debug!("cancel order {} of account {} at {}", order_id, account_id, order_pos);
+        self.add_applied_tx(applied_tx);
+    }
+
+    fn encode_order_tx(acc: &AccountState, account_id: u32, order_pos: u32, old_order: &Order, new_order: &Order) -> [Fr; TX_LENGTH] {
+        let mut encoded_tx = [Fr::zero(); TX_LENGTH];
+        encoded_tx[tx_detail_idx::ACCOUNT_ID1] = Fr::from_u32(account_id);
+        encoded_tx[tx_detail_idx::ACCOUNT_ID2] = Fr::from_u32(account_id);
+        encoded_tx[tx_detail_idx::NONCE1] = acc.nonce;
+        encoded_tx[tx_detail_idx::NONCE2] = acc.nonce;
+        encoded_tx[tx_detail_idx::SIGN1] = acc.sign;
+        encoded_tx[tx_detail_idx::SIGN2] = acc.sign;
+        encoded_tx[tx_detail_idx::AY1] = acc.ay;
+        encoded_tx[tx_detail_idx::AY2] = acc.ay;
+        encoded_tx[tx_detail_idx::ORDER1_POS] = Fr::from_u32(order_pos);
+
+        encoded_tx[tx_detail_idx::OLD_ORDER1_ID] = Fr::from_u32(old_order.order_id);
+        encoded_tx[tx_detail_idx::OLD_ORDER1_TOKEN_SELL] = old_order.token_sell;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_FILLED_SELL] = old_order.filled_sell;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_AMOUNT_SELL] = old_order.total_sell;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_TOKEN_BUY] = old_order.token_buy;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_FILLED_BUY] = old_order.filled_buy;
+        encoded_tx[tx_detail_idx::OLD_ORDER1_AMOUNT_BUY] = old_order.total_buy;
+
+        encoded_tx[tx_detail_idx::NEW_ORDER1_ID] = Fr::from_u32(new_order.order_id);
+        encoded_tx[tx_detail_idx::NEW_ORDER1_TOKEN_SELL] = new_order.token_sell;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_FILLED_SELL] = new_order.filled_sell;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_AMOUNT_SELL] = compress_fr(&new_order.total_sell).unwrap();
+        encoded_tx[tx_detail_idx::NEW_ORDER1_TOKEN_BUY] = new_order.token_buy;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_FILLED_BUY] = new_order.filled_buy;
+        encoded_tx[tx_detail_idx::NEW_ORDER1_AMOUNT_BUY] = compress_fr(&new_order.total_buy).unwrap();
+        encoded_tx
     }
 
     // case1: old order is empty
@@ -588,7 +862,7 @@ impl ManagerWrapper {
     // case3: old order has same order id, we will modify it
     // tx.xxx_order is_none: xxx_order should be already put into the GlobalState tree
     // tx.xxx_order is_some: xxx_order should be new for the GlobalState
-    pub fn full_spot_trade(&mut self, full_tx: FullSpotTradeTx, offset: Option<i64>) {
+    pub fn full_spot_trade(&mut self, full_tx: FullSpotTradeTx, offset: Option<MsgOffset>) {
         // Step1: basic tx check
         // check account ids exist
         let trade = full_tx.trade;
@@ -603,12 +877,7 @@ impl ManagerWrapper {
 
         // Step2: retrive old state first for later use
 
-        let old_root = state.root();
-        let proof_order1_seller = state.balance_full_proof(acc_id1, trade.token_id_1to2);
-        let proof_order2_seller = state.balance_full_proof(acc_id2, trade.token_id_2to1);
-
         let account1 = state.get_account(acc_id1);
-        let order_root0 = account1.order_root;
         let account2 = state.get_account(acc_id2);
 
         // Step3: handle new order
@@ -706,24 +975,6 @@ impl ManagerWrapper {
         encoded_tx[tx_detail_idx::ENABLE_SIG_CHECK1] = Fr::one();
         encoded_tx[tx_detail_idx::ENABLE_SIG_CHECK2] = Fr::one();
 
-        let mut raw_tx = RawTx {
-            tx_type: TxType::SpotTrade,
-            payload: Vec::default(),
-            balance_path0: proof_order1_seller.balance_path,
-            balance_path1: Default::default(),
-            balance_path2: proof_order2_seller.balance_path,
-            balance_path3: Default::default(),
-            order_path0: state.order_proof(acc_id1, order1_pos).path_elements,
-            order_path1: state.order_proof(acc_id2, order2_pos).path_elements,
-            order_root0,
-            order_root1: Default::default(),
-            account_path0: proof_order1_seller.account_path,
-            account_path1: Default::default(),
-            root_before: old_root,
-            root_after: Default::default(),
-            offset,
-        };
-
         order1.trade_with(&trade.amount_1to2, &trade.amount_2to1);
         state.update_order_state(acc_id1, order1_pos, order1);
         order2.trade_with(&trade.amount_2to1, &trade.amount_1to2);
@@ -747,12 +998,6 @@ impl ManagerWrapper {
             order_updates: vec![(order2_pos, order2.hash())],
             ..Default::default()
         };
-        state.batch_update(vec![acc1_updates, acc2_updates], true);
-
-        raw_tx.balance_path3 = state.balance_proof(acc_id1, trade.token_id_2to1).path_elements;
-        raw_tx.balance_path1 = state.balance_proof(acc_id2, trade.token_id_1to2).path_elements;
-        raw_tx.account_path1 = state.account_proof(acc_id2).path_elements;
-        raw_tx.order_root1 = state.get_account(acc_id2).order_root;
 
         encoded_tx[tx_detail_idx::NEW_ORDER1_ID] = Fr::from_u32(order1.order_id);
         encoded_tx[tx_detail_idx::NEW_ORDER1_TOKEN_SELL] = order1.token_sell;
@@ -774,36 +1019,37 @@ impl ManagerWrapper {
         encoded_tx[tx_detail_idx::TOKEN_ID1] = order1.token_sell;
         encoded_tx[tx_detail_idx::TOKEN_ID2] = order2.token_buy;
 
-        raw_tx.payload = encoded_tx.to_vec();
-        raw_tx.root_after = state.root();
+        let applied_tx = AppliedTx::apply(
+            &mut state,
+            TxType::SpotTrade,
+            encoded_tx.to_vec(),
+            WitnessPlan::SpotTrade {
+                account_id1: acc_id1,
+                account_id2: acc_id2,
+                token_id_1to2: trade.token_id_1to2,
+                token_id_2to1: trade.token_id_2to1,
+                order1_pos,
+                order2_pos,
+            },
+            vec![StateUpdate::Batch(vec![acc1_updates, acc2_updates])],
+            offset,
+        );
         drop(state);
-        self.add_raw_tx(raw_tx);
+        self.add_applied_tx(applied_tx);
     }
 
     pub fn nop(&mut self) {
         // assume we already have initialized the account tree and the balance tree
-        let state = self.state();
-        let trivial_proof = state.trivial_state_proof();
         let encoded_tx = [Fr::zero(); TX_LENGTH];
-        let raw_tx = RawTx {
-            tx_type: TxType::Nop,
-            payload: encoded_tx.to_vec(),
-            balance_path0: trivial_proof.balance_path.clone(),
-            balance_path1: trivial_proof.balance_path.clone(),
-            balance_path2: trivial_proof.balance_path.clone(),
-            balance_path3: trivial_proof.balance_path,
-            order_path0: state.trivial_order_path_elements(),
-            order_path1: state.trivial_order_path_elements(),
-            order_root0: Fr::zero(),
-            order_root1: Fr::zero(),
-            account_path0: trivial_proof.account_path.clone(),
-            account_path1: trivial_proof.account_path,
-            root_before: state.root(),
-            root_after: state.root(),
-            offset: None,
-        };
-        drop(state);
-        self.add_raw_tx(raw_tx);
+        let applied_tx = AppliedTx::apply(
+            &mut self.mut_state(),
+            TxType::Nop,
+            encoded_tx.to_vec(),
+            WitnessPlan::Nop,
+            vec![],
+            None,
+        );
+        self.add_applied_tx(applied_tx);
     }
 
     pub fn flush_with_nop(&mut self) {
@@ -815,6 +1061,68 @@ impl ManagerWrapper {
         log::debug!("flush with {} nop", cnt);
     }
 
+    // seal the pending block with nop, a whole nop block is generated if there is no pending tx
+    pub fn seal_block(&mut self) {
+        if self.buffered_txs.len() % self.n_tx == 0 {
+            self.nop();
+        }
+        self.flush_with_nop();
+    }
+
+    // types of the txs buffered for the block which is not full yet
+    pub fn pending_tx_types(&self) -> Vec<TxType> {
+        let len = self.buffered_txs.len();
+        self.buffered_txs[len - len % self.n_tx..].iter().map(|tx| tx.tx_type).collect()
+    }
+
+    // number of blocks which are already full, including those not popped yet
+    pub fn sealed_block_num(&self) -> usize {
+        self.block_generate_num + self.buffered_txs.len() / self.n_tx
+    }
+
+    // restore the offsets persisted with the state
+    pub fn set_consumed_offsets(&mut self, offsets: PartitionOffsets) {
+        self.consumed_offsets = offsets;
+    }
+
+    pub fn consumed_offsets(&self) -> &PartitionOffsets {
+        &self.consumed_offsets
+    }
+
+    // restore the quarantined accounts persisted with the state
+    pub fn set_quarantined(&mut self, accounts: BTreeSet<u32>) {
+        self.quarantined = accounts;
+    }
+
+    pub fn quarantined(&self) -> &BTreeSet<u32> {
+        &self.quarantined
+    }
+
+    pub fn quarantine(&mut self, accounts: &[u32]) {
+        self.quarantined.extend(accounts);
+    }
+
+    // also for a msg which is rejected without any tx, so it is not consumed again after a restart.
+    // Offsets never move back, a rejected msg may be consumed before the txs of earlier msgs are popped
+    pub fn consume_offset(&mut self, offset: MsgOffset) {
+        let consumed = self.consumed_offsets.entry(offset.tp).or_insert(offset.offset);
+        *consumed = (*consumed).max(offset.offset);
+    }
+
+    // number of txs applied since start, a rejected msg must not change it
+    pub fn tx_num(&self) -> usize {
+        self.block_generate_num * self.n_tx + self.buffered_txs.len()
+    }
+
+    // a panic while the state is being written leaves it poisoned and half updated
+    pub fn is_poisoned(&self) -> bool {
+        self.state.is_poisoned()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.n_tx
+    }
+
     pub fn check_sig(&self, account_id: u32, msg: &Fr, sig: &SignatureBJJ) -> anyhow::Result<()> {
         let state = self.state();
         if !state.has_account(account_id) {
@@ -831,51 +1139,74 @@ impl ManagerWrapper {
     }
 
     pub fn pop_all_blocks(&mut self) -> Vec<L2Block> {
+        self.pop_sealed_blocks().into_iter().map(SealedBlock::build).collect()
+    }
+
+    // the full blocks, whose witnesses are left to be built by `SealedBlock::build`
+    pub fn pop_sealed_blocks(&mut self) -> Vec<SealedBlock> {
         let mut blocks = vec![];
-        let mut i = 0;
-        let len = self.buffered_txs.len();
-        while i + self.n_tx <= len {
-            let block = Self::forge_with_txs(
-                self.block_generate_num,
-                &self.buffered_txs[i..i + self.n_tx],
-                &mut self.tx_data_encoder,
-            );
-            blocks.push(block);
+        while self.buffered_txs.len() >= self.n_tx {
+            let txs: Vec<AppliedTx> = self.buffered_txs.drain(0..self.n_tx).collect();
+            for offset in txs.iter().filter_map(|tx| tx.offset.as_ref()) {
+                self.consume_offset(offset.clone());
+            }
+            blocks.push(SealedBlock {
+                block_id: self.block_generate_num,
+                txs,
+                kafka_offsets: self.consumed_offsets.clone(),
+                witness_builder: self.witness_builder.clone(),
+                tx_bits: self.tx_bits,
+            });
 
             self.block_generate_num += 1;
 
             #[cfg(feature = "persist_sled")]
             // TODO: fix unwrap
             if self.block_generate_num % Settings::persist_every_n_block() == 0 {
-                self.persist(i)
+                self.persist(&blocks.last().unwrap().txs)
             }
-
-            i += self.n_tx;
         }
-        self.buffered_txs.drain(0..i);
+        if !blocks.is_empty() {
+            let state = self.state();
+            metrics::ACCOUNTS.set(state.account_num() as i64);
+            metrics::ORDERS.set(state.order_num() as i64);
+            metrics::BLOCKS.set(self.block_generate_num as i64);
+        }
         blocks
     }
 
+    // a snapshot after the last popped block, e.g. on shutdown
+    #[cfg(feature = "persist_sled")]
+    pub fn snapshot(&mut self) {
+        assert!(self.buffered_txs.is_empty(), "can not snapshot with txs not in blocks");
+        if self.block_generate_num == 0 || self.block_generate_num % Settings::persist_every_n_block() == 0 {
+            // nothing to persist, or persisted already by `pop_all_blocks`
+            return;
+        }
+        self.persist(&[]);
+    }
+
     #[cfg(feature = "persist_sled")]
-    fn persist(&mut self, i: usize) {
+    fn persist(&mut self, txs: &[AppliedTx]) {
         log::info!("start to dump #{}", self.block_generate_num);
         let start = Instant::now();
-        let last_offset = self.buffered_txs[i..i + self.n_tx].iter().rev().filter_map(|tx| tx.offset).next();
         if log::log_enabled!(log::Level::Debug) {
-            let offsets: Vec<Option<i64>> = self.buffered_txs[i..i + self.n_tx].iter().map(|tx| tx.offset).collect();
+            let offsets: Vec<Option<&MsgOffset>> = txs.iter().map(|tx| tx.offset.as_ref()).collect();
             log::debug!("block #{}, offsets: {:?}", self.block_generate_num, offsets);
         }
-        if last_offset.is_none() {
+        if self.consumed_offsets.is_empty() {
             log::warn!("kafka offset not exist, is this block belongs to a test_case?")
         }
         let db_path = Settings::persist_dir().join(format!("{}.db", self.block_generate_num));
         let db = sled::open(db_path).unwrap();
         db.insert(BLOCK_OFFSET_KEY, bincode::serialize(&self.block_generate_num).unwrap())
             .unwrap();
-        db.insert(KAFKA_OFFSET_KEY, bincode::serialize(&last_offset.unwrap()).unwrap())
+        db.insert(KAFKA_OFFSETS_KEY, bincode::serialize(&self.consumed_offsets).unwrap())
             .unwrap();
+        db.insert(QUARANTINED_KEY, bincode::serialize(&self.quarantined).unwrap()).unwrap();
         self.dump_to_sled(&db).unwrap();
         let elapsed = Instant::now() - start;
+        metrics::SNAPSHOT_SECONDS.observe(elapsed.as_secs_f64());
         log::info!(
             "dump #{} completed, duration: {:.3}s",
             self.block_generate_num,
@@ -890,10 +1221,12 @@ impl ManagerWrapper {
     }
 
     fn state(&self) -> RwLockReadGuard<'_, GlobalState> {
+        let _timer = metrics::LOCK_WAIT_SECONDS.with_label_values(&["read"]).start_timer();
         self.state.read().unwrap()
     }
 
     fn mut_state(&self) -> RwLockWriteGuard<'_, GlobalState> {
+        let _timer = metrics::LOCK_WAIT_SECONDS.with_label_values(&["write"]).start_timer();
         self.state.write().unwrap()
     }
 }
@@ -903,15 +1236,12 @@ mod test {
 
     //use crate::account::Signature;
     use super::*;
-    use crate::config::Settings;
-    use crate::types::l2::L2Key;
+    use crate::test_utils::init_test_settings;
+    use crate::types::l2::{L2Key, PubDataTx, TxDataDecoder};
 
     #[test]
     fn test_state_pubdata() {
-        let mut s = Settings::new();
-        //don't persist
-        s.persist_every_n_block = 1000;
-        Settings::set(s);
+        init_test_settings();
 
         let gs = GlobalState::new(3, 4, 4, false);
         let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
@@ -1003,5 +1333,272 @@ mod test {
         assert_eq!(blks[0].detail.txdata_hash.low_u128(), 19616728804774751320168438740415224383u128);
         assert_eq!(blks[1].detail.txdata_hash.low_u128(), 229380481089431957009116204147712640854u128);
         assert_eq!(blks[2].detail.txdata_hash.low_u128(), 16562419241364283837688117385709745071u128);
+
+        assert_eq!(blks[0].public_data_aux.key_update_txs_pos, vec![0]);
+        assert_eq!(blks[0].public_data_aux.deposit_txs_pos, vec![1]);
+        assert_eq!(blks[1].public_data_aux.deposit_txs_pos, vec![0]);
+        assert_eq!(blks[1].public_data_aux.key_update_txs_pos, vec![1]);
+        assert_eq!(blks[2].public_data_aux.deposit_count, 2);
+        assert_eq!(blks[2].public_data_aux.key_update_count, 0);
+    }
+
+    #[test]
+    fn test_pubdata_aux() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        let account_id0 = wrapper.create_new_account(1).unwrap();
+        let account_id1 = wrapper.create_new_account(1).unwrap();
+        wrapper.set_account_l2_addr(account_id1, Fr::one(), Fr::from_u32(1));
+
+        //txs are the same as test_tx_pubdata
+        //block empty
+        wrapper.nop();
+        wrapper.nop();
+
+        //block 0
+        wrapper
+            .key_update(
+                UpdateKeyTx {
+                    account_id: account_id0,
+                    l2key: L2Key {
+                        eth_addr: Fr::zero(),
+                        sign: Fr::one(),
+                        ay: Fr::from_str("20929899733237450167431708044227754871358144348193832508253740860573780197290"),
+                    },
+                },
+                None,
+            )
+            .unwrap();
+        wrapper
+            .deposit(
+                DepositTx {
+                    account_id: account_id0,
+                    token_id: 0,
+                    amount: 200u128,
+                    l2key: None,
+                },
+                None,
+            )
+            .unwrap();
+
+        //block 1
+        wrapper
+            .deposit(
+                DepositTx {
+                    account_id: account_id1,
+                    token_id: 0,
+                    amount: 100u128,
+                    l2key: None,
+                },
+                None,
+            )
+            .unwrap();
+        wrapper.transfer(TransferTx::new(account_id1, account_id0, 0, 50u128), None);
+
+        //block 2
+        wrapper.withdraw(WithdrawTx::new(account_id0, 0, 150u128, Fr::zero()), None);
+        wrapper
+            .deposit(
+                DepositTx {
+                    account_id: account_id1,
+                    token_id: 0,
+                    amount: 199u128,
+                    l2key: None,
+                },
+                None,
+            )
+            .unwrap();
+
+        let blks = wrapper.pop_all_blocks();
+        let auxes: Vec<PubDataAux> = blks.iter().map(|b| b.public_data_aux.clone()).collect();
+        assert_eq!(auxes[0], PubDataAux::default());
+        assert_eq!(
+            auxes[1],
+            PubDataAux {
+                deposit_txs_pos: vec![1],
+                deposit_count: 1,
+                key_update_txs_pos: vec![0],
+                key_update_count: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(auxes[2].deposit_txs_pos, vec![0]);
+        assert_eq!(auxes[2].key_update_count, 0);
+        assert_eq!(auxes[3].deposit_txs_pos, vec![1]);
+        assert_eq!(auxes[3].deposit_count, 1);
+    }
+
+    #[test]
+    fn test_full_exit() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        let account_id = wrapper.create_new_account(1).unwrap();
+        wrapper.set_account_l2_addr(account_id, Fr::one(), Fr::from_u32(1));
+        wrapper
+            .deposit(
+                DepositTx {
+                    account_id,
+                    token_id: 1,
+                    amount: 300u128,
+                    l2key: None,
+                },
+                None,
+            )
+            .unwrap();
+        wrapper.set_tx_source(Some("full_exit:0".to_string()));
+        wrapper.full_exit(FullExitTx { account_id, token_id: 1 }, None);
+        // a full exit of an account which does not exist is still executed
+        wrapper.set_tx_source(Some("full_exit:1".to_string()));
+        wrapper.full_exit(
+            FullExitTx {
+                account_id: 3,
+                token_id: 0,
+            },
+            None,
+        );
+        // out of the tree range, which exits nothing
+        wrapper.full_exit(
+            FullExitTx {
+                account_id: 4,
+                token_id: 1,
+            },
+            None,
+        );
+        wrapper.set_tx_source(None);
+
+        assert_eq!(wrapper.get_token_balance(account_id, 1), Fr::zero());
+        assert_eq!(wrapper.get_account_nonce(account_id), Fr::zero());
+        let blks = wrapper.pop_all_blocks();
+        assert_eq!(blks[0].public_data_aux.full_exit_txs_pos, vec![1]);
+        assert_eq!(blks[1].public_data_aux.full_exit_txs_pos, vec![0, 1]);
+        let receipts: Vec<_> = blks.iter().flat_map(|b| b.receipts.iter()).collect();
+        assert_eq!(receipts.len(), 4);
+        assert_eq!(receipts[0].source_id, None);
+        assert_eq!((receipts[1].tx_index, receipts[1].source_id.as_deref()), (1, Some("full_exit:0")));
+        assert_eq!((receipts[2].tx_index, receipts[2].source_id.as_deref()), (0, Some("full_exit:1")));
+        assert_eq!(receipts[2].root_before, blks[1].detail.old_root);
+        assert_eq!(receipts[3].root_before, receipts[3].root_after);
+
+        let decoder = TxDataDecoder::new(2, 2, 2);
+        let txs = decoder.decode(&blks[0].public_data).unwrap();
+        assert_eq!(
+            txs[1],
+            PubDataTx::FullExit {
+                account_id,
+                token_id: 1,
+                amount: 300u128,
+            }
+        );
+        let txs = decoder.decode(&blks[1].public_data).unwrap();
+        assert_eq!(
+            txs[0],
+            PubDataTx::FullExit {
+                account_id: 3,
+                token_id: 0,
+                amount: 0,
+            }
+        );
+        assert_eq!(
+            txs[1],
+            PubDataTx::FullExit {
+                account_id: 0,
+                token_id: 0,
+                amount: 0,
+            }
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "transfer to self")]
+    fn test_transfer_to_self() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        let account_id = wrapper.create_new_account(1).unwrap();
+        wrapper.transfer(TransferTx::new(account_id, account_id, 1, 0), None);
+    }
+
+    #[test]
+    fn test_sealed_block_build() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        let account_id = wrapper.create_new_account(1).unwrap();
+        wrapper.set_account_l2_addr(account_id, Fr::one(), Fr::from_u32(1));
+        wrapper
+            .deposit(
+                DepositTx {
+                    account_id,
+                    token_id: 1,
+                    amount: 300u128,
+                    l2key: None,
+                },
+                None,
+            )
+            .unwrap();
+        // a direct update within a block is not proven, the next tx just starts from the updated root
+        wrapper.set_token_balance(account_id, 2, Fr::from_u32(7));
+        let root = wrapper.root();
+        wrapper.nop();
+
+        let blocks = wrapper.pop_sealed_blocks();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].new_root(), root);
+        let block = std::thread::spawn(move || blocks.into_iter().next().unwrap().build())
+            .join()
+            .unwrap();
+        assert_eq!(block.detail.old_account_roots[1], root);
+        assert_eq!(block.detail.new_root, root);
+        assert_eq!(block.receipts.len(), 1);
+    }
+
+    #[test]
+    fn test_place_order() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        let account_id = wrapper.create_new_account(1).unwrap();
+        wrapper.set_account_l2_addr(account_id, Fr::one(), Fr::from_u32(1));
+        let root_before = wrapper.root();
+
+        let order = Order {
+            account_id,
+            order_id: 1,
+            token_sell: Fr::from_u32(1),
+            token_buy: Fr::zero(),
+            total_sell: Fr::from_u32(1000),
+            total_buy: Fr::from_u32(3000),
+            ..Default::default()
+        };
+        wrapper.place_order(order, None);
+        assert!(wrapper.has_order(account_id, 1));
+        wrapper.cancel_order_tx(account_id, 1, None);
+        assert!(!wrapper.has_order(account_id, 1));
+        // the cleared slot is the same as an empty one
+        assert_eq!(wrapper.root(), root_before);
+
+        let blks = wrapper.pop_all_blocks();
+        assert_eq!(blks.len(), 1);
+        let txs = TxDataDecoder::new(2, 2, 2).decode(&blks[0].public_data).unwrap();
+        assert_eq!(
+            txs[0],
+            PubDataTx::PlaceOrder {
+                account_id,
+                order_pos: 0,
+                order_id: 1,
+                token_sell: 1,
+                token_buy: 0,
+                total_sell: Fr::from_u32(1000),
+                total_buy: Fr::from_u32(3000),
+            }
+        );
+        assert_eq!(txs[1], PubDataTx::CancelOrder { account_id, order_pos: 0 });
     }
 }
@@ -1,6 +1,9 @@
 pub mod account;
 pub mod global;
 pub mod manager_wrapper;
+pub mod recovery;
+pub mod sealing;
+pub mod witness;
 
 pub use account::AccountState;
 pub use global::GlobalState;
@@ -0,0 +1,378 @@
+// Rebuild the global state from the public data posted on L1 only, for the case that both the db and the sled dumps are lost.
+// Public data carries the full amounts of deposits, transfers and withdraws, the l2 keys and the placed orders,
+// so these txs are replayed exactly.
+// A spot trade only publishes its orders (ids, positions, tokens and total amounts) and whether they are filled,
+// but neither the traded amounts nor the filled amounts. So the balances and the order leaves of the traders
+// can not be recovered, and the accounts are reported as unrecoverable from then on.
+use super::global::{GlobalState, StateUpdate};
+use crate::types::l2::{Order, PubDataTx, TxDataDecoder};
+use anyhow::Result;
+use fluidex_common::ff::Field;
+use fluidex_common::num_bigint::BigInt;
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RootCheck {
+    Matched,
+    Mismatched { expected: String },
+    // the state is known to be incomplete, the root is not expected to match
+    Unverifiable,
+    // no known root for this block
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockRecovery {
+    pub block_id: u64,
+    pub tx_num: usize,
+    pub root: String,
+    pub check: RootCheck,
+}
+
+// where public data is not enough to rebuild the state
+#[derive(Debug, Clone, PartialEq)]
+pub enum Insufficiency {
+    // blocks `from..to` are missing
+    MissingBlocks {
+        from: u64,
+        to: u64,
+    },
+    SpotTrade {
+        block_id: u64,
+        tx_idx: usize,
+        // (account_id, order_pos, order_id) of both orders
+        orders: [(u32, u32, u32); 2],
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryReport {
+    pub blocks: Vec<BlockRecovery>,
+    pub insufficiencies: Vec<Insufficiency>,
+    // accounts whose balances or orders are unknown
+    pub unrecoverable_accounts: BTreeSet<u32>,
+}
+
+impl RecoveryReport {
+    // whether the recovered state is exactly the state on L1
+    pub fn is_complete(&self) -> bool {
+        self.insufficiencies.is_empty() && self.blocks.iter().all(|b| !matches!(b.check, RootCheck::Mismatched { .. }))
+    }
+}
+
+/// Replays the public data of blocks, which must be fed in the order of block id starting from 0.
+pub struct StateRecovery {
+    state: GlobalState,
+    decoder: TxDataDecoder,
+    next_block_id: u64,
+    // false once the state diverges from the state on L1
+    exact: bool,
+    report: RecoveryReport,
+}
+
+impl StateRecovery {
+    // `state` must be empty, with the same tree levels as the blocks are generated with
+    pub fn new(state: GlobalState) -> Self {
+        let decoder = TxDataDecoder::new(state.balance_bits() as u32, state.order_bits() as u32, state.account_bits() as u32);
+        Self {
+            state,
+            decoder,
+            next_block_id: 0,
+            exact: true,
+            report: RecoveryReport::default(),
+        }
+    }
+
+    pub fn next_block_id(&self) -> u64 {
+        self.next_block_id
+    }
+
+    pub fn report(&self) -> &RecoveryReport {
+        &self.report
+    }
+
+    // `new_root` is the hex string of the root after the block, as stored in db
+    pub fn apply_block(&mut self, block_id: u64, public_data: &[u8], new_root: Option<&str>) -> Result<&BlockRecovery> {
+        if block_id < self.next_block_id {
+            anyhow::bail!("block {} is already applied, next block is {}", block_id, self.next_block_id);
+        }
+        if block_id > self.next_block_id {
+            log::warn!("blocks {}..{} are missing", self.next_block_id, block_id);
+            self.report.insufficiencies.push(Insufficiency::MissingBlocks {
+                from: self.next_block_id,
+                to: block_id,
+            });
+            self.exact = false;
+        }
+        // decode the whole block first, so a corrupt block is not partially applied
+        let txs = self.decoder.decode(public_data)?;
+        for (tx_idx, tx) in txs.iter().enumerate() {
+            self.apply_tx(block_id, tx_idx, tx);
+        }
+
+        let root = self.state.root().to_hex_string();
+        let check = match new_root {
+            None => RootCheck::Unknown,
+            Some(_) if !self.exact => RootCheck::Unverifiable,
+            Some(expected) if expected == root => RootCheck::Matched,
+            Some(expected) => {
+                log::error!("root mismatch after block {}: expected {} got {}", block_id, expected, root);
+                // every later block would mismatch too
+                self.exact = false;
+                RootCheck::Mismatched {
+                    expected: expected.to_string(),
+                }
+            }
+        };
+        self.next_block_id = block_id + 1;
+        self.report.blocks.push(BlockRecovery {
+            block_id,
+            tx_num: txs.len(),
+            root,
+            check,
+        });
+        Ok(self.report.blocks.last().unwrap())
+    }
+
+    fn apply_tx(&mut self, block_id: u64, tx_idx: usize, tx: &PubDataTx) {
+        let state = &mut self.state;
+        let updates = match *tx {
+            PubDataTx::Nop => vec![],
+            PubDataTx::Deposit {
+                account_id,
+                token_id,
+                amount,
+            } => {
+                let mut balance = state.get_token_balance(account_id, token_id);
+                balance.add_assign(&Fr::from_bigint(BigInt::from(amount)));
+                vec![StateUpdate::Balance {
+                    account_id,
+                    token_id,
+                    balance,
+                }]
+            }
+            PubDataTx::KeyUpdate { account_id, sign, ay } => {
+                // same as ManagerWrapper::key_update, the balance update inits the account
+                let fake_token_id = 0;
+                vec![
+                    StateUpdate::Balance {
+                        account_id,
+                        token_id: fake_token_id,
+                        balance: state.get_token_balance(account_id, fake_token_id),
+                    },
+                    StateUpdate::L2Addr { account_id, sign, ay },
+                ]
+            }
+            PubDataTx::Transfer {
+                from,
+                to,
+                token_id,
+                amount,
+            } => {
+                let amount = Fr::from_bigint(BigInt::from(amount));
+                let mut from_balance = state.get_token_balance(from, token_id);
+                from_balance.sub_assign(&amount);
+                let mut to_balance = state.get_token_balance(to, token_id);
+                to_balance.add_assign(&amount);
+                let mut nonce = state.get_account_nonce(from);
+                nonce.add_assign(&Fr::one());
+                vec![
+                    StateUpdate::Balance {
+                        account_id: from,
+                        token_id,
+                        balance: from_balance,
+                    },
+                    StateUpdate::Nonce { account_id: from, nonce },
+                    StateUpdate::Balance {
+                        account_id: to,
+                        token_id,
+                        balance: to_balance,
+                    },
+                ]
+            }
+            PubDataTx::Withdraw {
+                account_id,
+                token_id,
+                amount,
+            } => {
+                let mut balance = state.get_token_balance(account_id, token_id);
+                balance.sub_assign(&Fr::from_bigint(BigInt::from(amount)));
+                let mut nonce = state.get_account_nonce(account_id);
+                nonce.add_assign(&Fr::one());
+                vec![
+                    StateUpdate::Balance {
+                        account_id,
+                        token_id,
+                        balance,
+                    },
+                    StateUpdate::Nonce { account_id, nonce },
+                ]
+            }
+            PubDataTx::FullExit {
+                account_id,
+                token_id,
+                amount,
+            } => {
+                // the amount is the whole balance, so this zeros it unless the account is unrecoverable
+                let mut balance = state.get_token_balance(account_id, token_id);
+                balance.sub_assign(&Fr::from_bigint(BigInt::from(amount)));
+                vec![StateUpdate::Balance {
+                    account_id,
+                    token_id,
+                    balance,
+                }]
+            }
+            PubDataTx::PlaceOrder {
+                account_id,
+                order_pos,
+                order_id,
+                token_sell,
+                token_buy,
+                total_sell,
+                total_buy,
+            } => {
+                // the side is not in public data, but it is not part of the order leaf either
+                let order = Order {
+                    account_id,
+                    order_id,
+                    token_sell: Fr::from_u32(token_sell),
+                    token_buy: Fr::from_u32(token_buy),
+                    total_sell,
+                    total_buy,
+                    ..Order::default()
+                };
+                vec![
+                    // in case the key update of the account is in a missing block
+                    StateUpdate::InitAccount {
+                        account_id,
+                        next_order_id: 1,
+                    },
+                    StateUpdate::Order {
+                        account_id,
+                        order_pos,
+                        order,
+                    },
+                ]
+            }
+            PubDataTx::CancelOrder { account_id, order_pos } => vec![
+                StateUpdate::InitAccount {
+                    account_id,
+                    next_order_id: 1,
+                },
+                StateUpdate::ClearOrder { account_id, order_pos },
+            ],
+            PubDataTx::SpotTrade {
+                order1_account_id,
+                order2_account_id,
+                order1_pos,
+                order1_id,
+                order2_pos,
+                order2_id,
+                ..
+            } => {
+                log::warn!(
+                    "spot trade at block {} tx {}: balances of accounts {} and {} can not be recovered",
+                    block_id,
+                    tx_idx,
+                    order1_account_id,
+                    order2_account_id
+                );
+                self.report.insufficiencies.push(Insufficiency::SpotTrade {
+                    block_id,
+                    tx_idx,
+                    orders: [
+                        (order1_account_id, order1_pos, order1_id),
+                        (order2_account_id, order2_pos, order2_id),
+                    ],
+                });
+                self.report.unrecoverable_accounts.insert(order1_account_id);
+                self.report.unrecoverable_accounts.insert(order2_account_id);
+                self.exact = false;
+                vec![]
+            }
+        };
+        for update in &updates {
+            self.state.apply_update(update);
+        }
+    }
+
+    pub fn finish(self) -> (GlobalState, RecoveryReport) {
+        (self.state, self.report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ManagerWrapper;
+    use crate::test_utils::init_test_settings;
+    use crate::types::l2::{DepositTx, L2Key, TransferTx, UpdateKeyTx, WithdrawTx};
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn test_recover_from_pubdata() {
+        init_test_settings();
+
+        let gs = GlobalState::new(2, 2, 2, false);
+        let mut wrapper = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 2, None, false);
+        for (account_id, ay) in [(0, 1), (1, 2)] {
+            wrapper
+                .key_update(
+                    UpdateKeyTx {
+                        account_id,
+                        l2key: L2Key {
+                            eth_addr: Fr::zero(),
+                            sign: Fr::one(),
+                            ay: Fr::from_u32(ay),
+                        },
+                    },
+                    None,
+                )
+                .unwrap();
+        }
+        for account_id in [0, 1] {
+            wrapper
+                .deposit(
+                    DepositTx {
+                        account_id,
+                        token_id: 1,
+                        amount: 100u128,
+                        l2key: None,
+                    },
+                    None,
+                )
+                .unwrap();
+        }
+        wrapper.transfer(TransferTx::new(0, 1, 1, 30u128), None);
+        wrapper.withdraw(WithdrawTx::new(1, 1, 50u128, Fr::zero()), None);
+        let blocks = wrapper.pop_all_blocks();
+        assert_eq!(blocks.len(), 3);
+
+        let mut recovery = StateRecovery::new(GlobalState::new(2, 2, 2, false));
+        for block in &blocks {
+            let new_root = block.detail.new_root.to_hex_string();
+            let result = recovery
+                .apply_block(block.block_id as u64, &block.public_data, Some(&new_root))
+                .unwrap();
+            assert_eq!(result.check, RootCheck::Matched);
+        }
+        let (state, report) = recovery.finish();
+        assert!(report.is_complete());
+        assert_eq!(state.get_token_balance(1, 1), Fr::from_u32(80));
+        assert_eq!(state.get_account_nonce(0), Fr::one());
+    }
+
+    #[test]
+    fn test_missing_blocks() {
+        let mut recovery = StateRecovery::new(GlobalState::new(2, 2, 2, false));
+        let empty_root = recovery.state.root().to_hex_string();
+        let result = recovery.apply_block(2, &[], Some(&empty_root)).unwrap();
+        assert_eq!(result.check, RootCheck::Unverifiable);
+        assert!(recovery.apply_block(1, &[], None).is_err());
+        let (_, report) = recovery.finish();
+        assert_eq!(report.insufficiencies, vec![Insufficiency::MissingBlocks { from: 0, to: 2 }]);
+        assert!(!report.is_complete());
+    }
+}
@@ -0,0 +1,310 @@
+// Decides when a partially filled block should be padded with nop and sealed.
+// Full blocks are always sealed by `ManagerWrapper::pop_all_blocks`, the policies here only
+// care about the pending (not full) block.
+use super::ManagerWrapper;
+use crate::config::SealingSettings;
+use crate::metrics;
+use crate::types::l2::TxType;
+use anyhow::bail;
+use std::time::{Duration, Instant};
+
+pub struct SealingContext<'a> {
+    pub now: Instant,
+    pub block_size: usize,
+    pub pending_tx_types: &'a [TxType],
+    // when the first tx of the pending block was buffered
+    pub first_tx_time: Option<Instant>,
+    pub last_seal_time: Instant,
+}
+
+impl SealingContext<'_> {
+    pub fn pending_tx_num(&self) -> usize {
+        self.pending_tx_types.len()
+    }
+}
+
+pub trait SealingPolicy: Send {
+    fn should_seal(&self, ctx: &SealingContext) -> bool;
+    // the time this policy may fire next even if no more tx arrives, so the caller knows how long it can wait
+    fn deadline(&self, _ctx: &SealingContext) -> Option<Instant> {
+        None
+    }
+}
+
+/// Seals once the first buffered tx has waited for `max_wait`.
+pub struct MaxLatency {
+    pub max_wait: Duration,
+}
+
+impl SealingPolicy for MaxLatency {
+    fn should_seal(&self, ctx: &SealingContext) -> bool {
+        match ctx.first_tx_time {
+            Some(t) => ctx.now.duration_since(t) >= self.max_wait,
+            None => false,
+        }
+    }
+    fn deadline(&self, ctx: &SealingContext) -> Option<Instant> {
+        ctx.first_tx_time.map(|t| t + self.max_wait)
+    }
+}
+
+/// Seals once the pending block is filled to `ratio` of the block size.
+pub struct MinFill {
+    pub ratio: f64,
+}
+
+impl SealingPolicy for MinFill {
+    fn should_seal(&self, ctx: &SealingContext) -> bool {
+        ctx.pending_tx_num() > 0 && ctx.pending_tx_num() as f64 >= self.ratio * ctx.block_size as f64
+    }
+}
+
+/// Seals an empty block if no block has been sealed for `interval`.
+pub struct Heartbeat {
+    pub interval: Duration,
+}
+
+impl SealingPolicy for Heartbeat {
+    fn should_seal(&self, ctx: &SealingContext) -> bool {
+        ctx.now.duration_since(ctx.last_seal_time) >= self.interval
+    }
+    fn deadline(&self, ctx: &SealingContext) -> Option<Instant> {
+        Some(ctx.last_seal_time + self.interval)
+    }
+}
+
+/// Seals as soon as one of `tx_types` is buffered, e.g. withdraws for faster exits.
+pub struct SealOnTxTypes {
+    pub tx_types: Vec<TxType>,
+}
+
+impl SealingPolicy for SealOnTxTypes {
+    fn should_seal(&self, ctx: &SealingContext) -> bool {
+        ctx.pending_tx_types.iter().any(|t| self.tx_types.contains(t))
+    }
+}
+
+/// Seals when any of the inner policies fires.
+#[derive(Default)]
+pub struct AnyOf(pub Vec<Box<dyn SealingPolicy>>);
+
+impl SealingPolicy for AnyOf {
+    fn should_seal(&self, ctx: &SealingContext) -> bool {
+        self.0.iter().any(|p| p.should_seal(ctx))
+    }
+    fn deadline(&self, ctx: &SealingContext) -> Option<Instant> {
+        self.0.iter().filter_map(|p| p.deadline(ctx)).min()
+    }
+}
+
+impl AnyOf {
+    pub fn from_settings(settings: &SealingSettings) -> anyhow::Result<Self> {
+        let mut policies: Vec<Box<dyn SealingPolicy>> = vec![];
+        if let Some(secs) = settings.max_wait_secs {
+            policies.push(Box::new(MaxLatency {
+                max_wait: Duration::from_secs(secs),
+            }));
+        }
+        if let Some(ratio) = settings.min_fill_ratio {
+            if !(ratio > 0.0 && ratio <= 1.0) {
+                bail!("invalid min_fill_ratio {}", ratio);
+            }
+            policies.push(Box::new(MinFill { ratio }));
+        }
+        if let Some(secs) = settings.heartbeat_secs {
+            policies.push(Box::new(Heartbeat {
+                interval: Duration::from_secs(secs),
+            }));
+        }
+        if !settings.seal_on_tx_types.is_empty() {
+            let tx_types = settings
+                .seal_on_tx_types
+                .iter()
+                .map(|s| s.parse())
+                .collect::<anyhow::Result<Vec<TxType>>>()?;
+            policies.push(Box::new(SealOnTxTypes { tx_types }));
+        }
+        Ok(Self(policies))
+    }
+}
+
+/// Tracks the timing of the pending block and applies a [`SealingPolicy`] to a [`ManagerWrapper`].
+pub struct BlockSealer<P: SealingPolicy> {
+    policy: P,
+    first_tx_time: Option<Instant>,
+    last_seal_time: Instant,
+    sealed_block_num: usize,
+}
+
+impl<P: SealingPolicy> BlockSealer<P> {
+    pub fn new(policy: P, now: Instant) -> Self {
+        Self {
+            policy,
+            first_tx_time: None,
+            last_seal_time: now,
+            sealed_block_num: 0,
+        }
+    }
+
+    fn observe(&mut self, sealed_block_num: usize, pending_tx_num: usize, now: Instant) {
+        if sealed_block_num != self.sealed_block_num {
+            if let Some(first_tx_time) = self.first_tx_time {
+                metrics::BLOCK_SEAL_SECONDS.observe(now.duration_since(first_tx_time).as_secs_f64());
+            }
+            self.sealed_block_num = sealed_block_num;
+            self.last_seal_time = now;
+            // the txs left over are from the new block
+            self.first_tx_time = None;
+        }
+        if pending_tx_num == 0 {
+            self.first_tx_time = None;
+        } else if self.first_tx_time.is_none() {
+            self.first_tx_time = Some(now);
+        }
+    }
+
+    fn context<'a>(&self, block_size: usize, pending_tx_types: &'a [TxType], now: Instant) -> SealingContext<'a> {
+        SealingContext {
+            now,
+            block_size,
+            pending_tx_types,
+            first_tx_time: self.first_tx_time,
+            last_seal_time: self.last_seal_time,
+        }
+    }
+
+    // returns true if the pending block has been sealed
+    pub fn poll(&mut self, manager: &mut ManagerWrapper, now: Instant) -> bool {
+        let pending_tx_types = manager.pending_tx_types();
+        self.observe(manager.sealed_block_num(), pending_tx_types.len(), now);
+        if !self.policy.should_seal(&self.context(manager.block_size(), &pending_tx_types, now)) {
+            return false;
+        }
+        log::debug!("seal block with {} pending txs", pending_tx_types.len());
+        manager.seal_block();
+        self.observe(manager.sealed_block_num(), 0, now);
+        true
+    }
+
+    // how long we can block on waiting for new msgs, `None` means forever
+    pub fn timeout(&self, manager: &ManagerWrapper, now: Instant) -> Option<Duration> {
+        let pending_tx_types = manager.pending_tx_types();
+        self.policy
+            .deadline(&self.context(manager.block_size(), &pending_tx_types, now))
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::GlobalState;
+    use std::sync::{Arc, RwLock};
+
+    fn ctx(now: Instant, pending_tx_types: &[TxType], first_tx_time: Option<Instant>, last_seal_time: Instant) -> SealingContext {
+        SealingContext {
+            now,
+            block_size: 4,
+            pending_tx_types,
+            first_tx_time,
+            last_seal_time,
+        }
+    }
+
+    #[test]
+    fn test_policies() {
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_secs(10);
+
+        let max_latency = MaxLatency {
+            max_wait: Duration::from_secs(10),
+        };
+        assert!(!max_latency.should_seal(&ctx(t1, &[], None, t0)));
+        assert!(!max_latency.should_seal(&ctx(t1, &[TxType::Deposit], Some(t0 + Duration::from_secs(1)), t0)));
+        assert!(max_latency.should_seal(&ctx(t1, &[TxType::Deposit], Some(t0), t0)));
+        assert_eq!(max_latency.deadline(&ctx(t0, &[TxType::Deposit], Some(t0), t0)), Some(t1));
+
+        let min_fill = MinFill { ratio: 0.5 };
+        assert!(!min_fill.should_seal(&ctx(t0, &[], None, t0)));
+        assert!(!min_fill.should_seal(&ctx(t0, &[TxType::Deposit], Some(t0), t0)));
+        assert!(min_fill.should_seal(&ctx(t0, &[TxType::Deposit, TxType::Transfer], Some(t0), t0)));
+
+        let heartbeat = Heartbeat {
+            interval: Duration::from_secs(10),
+        };
+        assert!(!heartbeat.should_seal(&ctx(t0, &[], None, t0)));
+        assert!(heartbeat.should_seal(&ctx(t1, &[], None, t0)));
+
+        let on_withdraw = SealOnTxTypes {
+            tx_types: vec![TxType::Withdraw],
+        };
+        assert!(!on_withdraw.should_seal(&ctx(t0, &[TxType::Deposit], Some(t0), t0)));
+        assert!(on_withdraw.should_seal(&ctx(t0, &[TxType::Deposit, TxType::Withdraw], Some(t0), t0)));
+
+        let any = AnyOf(vec![Box::new(max_latency), Box::new(heartbeat)]);
+        assert!(!any.should_seal(&ctx(t0, &[TxType::Deposit], Some(t0), t0)));
+        assert!(any.should_seal(&ctx(t1, &[], None, t0)));
+        assert_eq!(
+            any.deadline(&ctx(t0, &[TxType::Deposit], Some(t0 + Duration::from_secs(5)), t0)),
+            Some(t1)
+        );
+        assert_eq!(
+            any.deadline(&ctx(t0, &[], None, t0 + Duration::from_secs(20))),
+            Some(t0 + Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn test_from_settings() {
+        let settings = SealingSettings {
+            max_wait_secs: Some(60),
+            min_fill_ratio: Some(0.8),
+            heartbeat_secs: None,
+            seal_on_tx_types: vec!["withdraw".to_owned()],
+        };
+        assert_eq!(AnyOf::from_settings(&settings).unwrap().0.len(), 3);
+        // a config without `sealing` still seals partial blocks
+        assert_eq!(AnyOf::from_settings(&SealingSettings::default()).unwrap().0.len(), 1);
+
+        let settings = SealingSettings {
+            seal_on_tx_types: vec!["no_such_tx".to_owned()],
+            ..Default::default()
+        };
+        assert!(AnyOf::from_settings(&settings).is_err());
+
+        let settings = SealingSettings {
+            min_fill_ratio: Some(1.5),
+            ..Default::default()
+        };
+        assert!(AnyOf::from_settings(&settings).is_err());
+    }
+
+    #[test]
+    fn test_block_sealer() {
+        let gs = GlobalState::new(3, 4, 4, false);
+        let mut manager = ManagerWrapper::new(Arc::new(RwLock::new(gs)), 4, None, false);
+        let t0 = Instant::now();
+        let mut sealer = BlockSealer::new(
+            Heartbeat {
+                interval: Duration::from_secs(10),
+            },
+            t0,
+        );
+
+        assert!(!sealer.poll(&mut manager, t0));
+        assert_eq!(sealer.timeout(&manager, t0), Some(Duration::from_secs(10)));
+
+        // an empty block is generated for the heartbeat
+        let t1 = t0 + Duration::from_secs(10);
+        assert!(sealer.poll(&mut manager, t1));
+        assert_eq!(manager.sealed_block_num(), 1);
+        assert!(manager.pending_tx_types().is_empty());
+        assert!(!sealer.poll(&mut manager, t1 + Duration::from_secs(1)));
+
+        // a partial block is padded
+        manager.nop();
+        assert_eq!(manager.pending_tx_types(), vec![TxType::Nop]);
+        assert!(sealer.poll(&mut manager, t1 + Duration::from_secs(10)));
+        assert_eq!(manager.sealed_block_num(), 2);
+    }
+}
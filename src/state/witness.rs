@@ -0,0 +1,371 @@
+// Witness (merkle paths) construction, decoupled from the state transition.
+// `ManagerWrapper` applies a tx to the global state and records, as an `AppliedTx`, only the distinct paths
+// the circuit needs, read from the state while it is locked anyway. The `RawTx`, with its 4 balance,
+// 2 order and 2 account paths, is built later by `WitnessBuilder`, which does not touch the state,
+// so the rest of the witness of a block is built off the processor thread once the block is sealed.
+use super::global::{GlobalState, StateUpdate};
+use crate::types::l2::{RawTx, TxType};
+use crate::types::matchengine::messages::MsgOffset;
+use crate::types::merkle_tree::MerklePath;
+use fluidex_common::ff::Field;
+use fluidex_common::Fr;
+
+// which paths a tx needs in its witness
+#[derive(Clone, Debug)]
+pub enum WitnessPlan {
+    Nop,
+    // key_update, deposit, withdraw and full_exit touch a single balance leaf
+    SingleBalance {
+        account_id: u32,
+        token_id: u32,
+    },
+    Transfer {
+        from: u32,
+        to: u32,
+        token_id: u32,
+    },
+    // place_order and cancel_order_tx touch a single order leaf
+    Order {
+        account_id: u32,
+        order_pos: u32,
+    },
+    SpotTrade {
+        account_id1: u32,
+        account_id2: u32,
+        token_id_1to2: u32,
+        token_id_2to1: u32,
+        order1_pos: u32,
+        order2_pos: u32,
+    },
+}
+
+// the distinct paths of a tx, each one kept once however many times it appears in the `RawTx`
+#[derive(Clone, Debug)]
+pub enum TxPaths {
+    // also nop, whose paths are the ones of balance 0 of account 0, with a zero order root
+    SingleBalance {
+        balance_path: MerklePath,
+        account_path: MerklePath,
+        order_root: Fr,
+    },
+    Transfer {
+        from_balance_path: MerklePath,
+        from_account_path: MerklePath,
+        from_order_root: Fr,
+        // after the update of `from`
+        to_balance_path: MerklePath,
+        to_account_path: MerklePath,
+        to_order_root: Fr,
+    },
+    Order {
+        balance_path: MerklePath,
+        account_path: MerklePath,
+        order_path: MerklePath,
+        order_root_before: Fr,
+        order_root_after: Fr,
+    },
+    SpotTrade {
+        balance_paths: [MerklePath; 4],
+        order_paths: [MerklePath; 2],
+        order_roots: [Fr; 2],
+        account_paths: [MerklePath; 2],
+    },
+}
+
+impl TxPaths {
+    // reads the paths before and after `updates` are applied to `state`
+    fn apply(state: &mut GlobalState, plan: &WitnessPlan, updates: &[StateUpdate]) -> Self {
+        let apply_all = |state: &mut GlobalState| {
+            for update in updates {
+                state.apply_update(update);
+            }
+        };
+        match *plan {
+            WitnessPlan::Nop => {
+                apply_all(state);
+                let proof = state.trivial_state_proof();
+                TxPaths::SingleBalance {
+                    balance_path: proof.balance_path,
+                    account_path: proof.account_path,
+                    order_root: Fr::zero(),
+                }
+            }
+            WitnessPlan::SingleBalance { account_id, token_id } => {
+                let proof = state.balance_full_proof(account_id, token_id);
+                let order_root = state.get_account(account_id).order_root;
+                apply_all(state);
+                TxPaths::SingleBalance {
+                    balance_path: proof.balance_path,
+                    account_path: proof.account_path,
+                    order_root,
+                }
+            }
+            WitnessPlan::Transfer { from, to, token_id } => {
+                let proof_from = state.balance_full_proof(from, token_id);
+                let from_order_root = state.get_account(from).order_root;
+                let to_order_root = state.get_account(to).order_root;
+                apply_all(state);
+                // the path of a leaf does not contain the leaf itself,
+                // so it does not matter whether the l2 addr of `to` is set when we take the proof
+                let proof_to = state.balance_full_proof(to, token_id);
+                TxPaths::Transfer {
+                    from_balance_path: proof_from.balance_path,
+                    from_account_path: proof_from.account_path,
+                    from_order_root,
+                    to_balance_path: proof_to.balance_path,
+                    to_account_path: proof_to.account_path,
+                    to_order_root,
+                }
+            }
+            WitnessPlan::Order { account_id, order_pos } => {
+                // the balance path is only used to rebuild the account leaf
+                let proof = state.balance_full_proof(account_id, 0);
+                let order_root_before = state.get_account(account_id).order_root;
+                let order_path = state.order_proof(account_id, order_pos).path_elements;
+                apply_all(state);
+                TxPaths::Order {
+                    balance_path: proof.balance_path,
+                    account_path: proof.account_path,
+                    order_path,
+                    order_root_before,
+                    order_root_after: state.get_account(account_id).order_root,
+                }
+            }
+            WitnessPlan::SpotTrade {
+                account_id1,
+                account_id2,
+                token_id_1to2,
+                token_id_2to1,
+                order1_pos,
+                order2_pos,
+            } => {
+                let proof_order1_seller = state.balance_full_proof(account_id1, token_id_1to2);
+                let proof_order2_seller = state.balance_full_proof(account_id2, token_id_2to1);
+                let order_root0 = state.get_account(account_id1).order_root;
+                let order_path0 = state.order_proof(account_id1, order1_pos).path_elements;
+                let order_path1 = state.order_proof(account_id2, order2_pos).path_elements;
+                apply_all(state);
+                TxPaths::SpotTrade {
+                    balance_paths: [
+                        proof_order1_seller.balance_path,
+                        state.balance_proof(account_id2, token_id_1to2).path_elements,
+                        proof_order2_seller.balance_path,
+                        state.balance_proof(account_id1, token_id_2to1).path_elements,
+                    ],
+                    order_paths: [order_path0, order_path1],
+                    order_roots: [order_root0, state.get_account(account_id2).order_root],
+                    account_paths: [proof_order1_seller.account_path, state.account_proof(account_id2).path_elements],
+                }
+            }
+        }
+    }
+}
+
+// a tx which has been applied to the state, but whose witness is not built yet
+pub struct AppliedTx {
+    pub tx_type: TxType,
+    pub payload: Vec<Fr>,
+    pub paths: TxPaths,
+    pub root_before: Fr,
+    pub root_after: Fr,
+    pub offset: Option<MsgOffset>,
+    pub source_id: Option<String>,
+}
+
+impl AppliedTx {
+    // apply `updates` to `state` and record the paths `plan` asks for
+    pub fn apply(
+        state: &mut GlobalState,
+        tx_type: TxType,
+        payload: Vec<Fr>,
+        plan: WitnessPlan,
+        updates: Vec<StateUpdate>,
+        offset: Option<MsgOffset>,
+    ) -> Self {
+        let root_before = state.root();
+        let paths = TxPaths::apply(state, &plan, &updates);
+        Self {
+            tx_type,
+            payload,
+            paths,
+            root_before,
+            root_after: state.root(),
+            offset,
+            source_id: None,
+        }
+    }
+}
+
+/// Builds [`RawTx`] from [`AppliedTx`]. It only holds the constant paths of the empty order tree,
+/// so it is cheap to clone and can run on any thread, in any order.
+#[derive(Clone)]
+pub struct WitnessBuilder {
+    trivial_order_path: MerklePath,
+}
+
+impl WitnessBuilder {
+    pub fn new(state: &GlobalState) -> Self {
+        Self {
+            trivial_order_path: state.trivial_order_path_elements(),
+        }
+    }
+
+    pub fn build(&self, tx: AppliedTx) -> RawTx {
+        let trivial_order_path = || self.trivial_order_path.clone();
+        let (balance_paths, order_paths, order_roots, account_paths) = match tx.paths {
+            TxPaths::SingleBalance {
+                balance_path,
+                account_path,
+                order_root,
+            } => (
+                [balance_path.clone(), balance_path.clone(), balance_path.clone(), balance_path],
+                [trivial_order_path(), trivial_order_path()],
+                [order_root, order_root],
+                [account_path.clone(), account_path],
+            ),
+            TxPaths::Transfer {
+                from_balance_path,
+                from_account_path,
+                from_order_root,
+                to_balance_path,
+                to_account_path,
+                to_order_root,
+            } => (
+                [
+                    from_balance_path.clone(),
+                    to_balance_path.clone(),
+                    from_balance_path,
+                    to_balance_path,
+                ],
+                [trivial_order_path(), trivial_order_path()],
+                [from_order_root, to_order_root],
+                [from_account_path, to_account_path],
+            ),
+            TxPaths::Order {
+                balance_path,
+                account_path,
+                order_path,
+                order_root_before,
+                order_root_after,
+            } => (
+                [balance_path.clone(), balance_path.clone(), balance_path.clone(), balance_path],
+                [order_path.clone(), order_path],
+                [order_root_before, order_root_after],
+                [account_path.clone(), account_path],
+            ),
+            TxPaths::SpotTrade {
+                balance_paths,
+                order_paths,
+                order_roots,
+                account_paths,
+            } => (balance_paths, order_paths, order_roots, account_paths),
+        };
+        let [balance_path0, balance_path1, balance_path2, balance_path3] = balance_paths;
+        let [order_path0, order_path1] = order_paths;
+        let [account_path0, account_path1] = account_paths;
+        RawTx {
+            tx_type: tx.tx_type,
+            payload: tx.payload,
+            balance_path0,
+            balance_path1,
+            balance_path2,
+            balance_path3,
+            order_path0,
+            order_path1,
+            order_root0: order_roots[0],
+            order_root1: order_roots[1],
+            account_path0,
+            account_path1,
+            root_before: tx.root_before,
+            root_after: tx.root_after,
+            offset: tx.offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::global::AccountUpdates;
+    use fluidex_common::types::FrExt;
+
+    fn deposit(state: &mut GlobalState, account_id: u32, token_id: u32, amount: u32) -> AppliedTx {
+        AppliedTx::apply(
+            state,
+            TxType::Deposit,
+            vec![],
+            WitnessPlan::SingleBalance { account_id, token_id },
+            vec![StateUpdate::Balance {
+                account_id,
+                token_id,
+                balance: Fr::from_u32(amount),
+            }],
+            None,
+        )
+    }
+
+    #[test]
+    fn test_build_after_apply() {
+        let mut state = GlobalState::new(2, 2, 2, false);
+        state.set_token_balance(0, 0, Fr::from_u32(10));
+        let builder = WitnessBuilder::new(&state);
+
+        let proof = state.balance_full_proof(1, 1);
+        let deposit = deposit(&mut state, 1, 1, 5);
+        let nop_proof = state.trivial_state_proof();
+        let nop = AppliedTx::apply(&mut state, TxType::Nop, vec![], WitnessPlan::Nop, vec![], None);
+        // direct updates between txs are allowed, they are just not proven
+        state.set_token_balance(0, 0, Fr::from_u32(20));
+        assert_ne!(deposit.root_before, deposit.root_after);
+
+        // the live state has moved on, but the witness is built against the state before the tx
+        let raw_tx = builder.build(deposit);
+        assert_eq!(raw_tx.balance_path0, proof.balance_path);
+        assert_eq!(raw_tx.balance_path3, proof.balance_path);
+        assert_eq!(raw_tx.account_path1, proof.account_path);
+        assert_eq!(raw_tx.root_before, proof.root);
+        assert_eq!(raw_tx.order_path0, state.trivial_order_path_elements());
+        let raw_tx = builder.build(nop);
+        assert_eq!(raw_tx.balance_path2, nop_proof.balance_path);
+        assert_eq!(raw_tx.account_path0, nop_proof.account_path);
+        assert_eq!((raw_tx.order_root0, raw_tx.root_before), (Fr::zero(), raw_tx.root_after));
+    }
+
+    #[test]
+    fn test_transfer_paths() {
+        let mut state = GlobalState::new(2, 2, 2, false);
+        deposit(&mut state, 1, 2, 50);
+        deposit(&mut state, 2, 2, 5);
+
+        let proof_from = state.balance_full_proof(1, 2);
+        let updates = vec![StateUpdate::Batch(vec![
+            AccountUpdates {
+                account_id: 1,
+                balance_updates: vec![(2, Fr::from_u32(20))],
+                ..Default::default()
+            },
+            AccountUpdates {
+                account_id: 2,
+                balance_updates: vec![(2, Fr::from_u32(35))],
+                ..Default::default()
+            },
+        ])];
+        let plan = WitnessPlan::Transfer {
+            from: 1,
+            to: 2,
+            token_id: 2,
+        };
+        let transfer = AppliedTx::apply(&mut state, TxType::Transfer, vec![], plan, updates, None);
+        // `to` is proven against the state after the update of `from`
+        let proof_to = state.balance_full_proof(2, 2);
+
+        let raw_tx = WitnessBuilder::new(&state).build(transfer);
+        assert_eq!(raw_tx.balance_path0, proof_from.balance_path);
+        assert_eq!(raw_tx.balance_path2, proof_from.balance_path);
+        assert_eq!(raw_tx.account_path0, proof_from.account_path);
+        assert_eq!(raw_tx.balance_path1, proof_to.balance_path);
+        assert_eq!(raw_tx.account_path1, proof_to.account_path);
+        assert_eq!(raw_tx.root_before, proof_from.root);
+        assert_eq!(raw_tx.root_after, proof_to.root);
+    }
+}
@@ -0,0 +1,287 @@
+// The `l2_tx` table, a normalized copy of the txs in block details, so the history of an account
+// can be listed without decoding every block.
+use crate::test_utils::types::prec_token_id;
+use crate::types::l2::{compact, tx_detail_idx, L2BlockDetail, L2BlockSerde, TxType};
+use fluidex_common::db::models::tablenames;
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use sqlx::postgres::PgPool;
+use sqlx::{Postgres, Row, Transaction};
+
+pub const L2_TX: &str = "l2_tx";
+
+pub fn schema() -> Vec<String> {
+    vec![
+        format!(
+            "create table if not exists {} (
+                block_id bigint not null,
+                tx_index integer not null,
+                tx_type varchar(32) not null,
+                from_account integer not null,
+                to_account integer,
+                token_id integer,
+                amount numeric,
+                to_token_id integer,
+                to_amount numeric,
+                from_balance numeric,
+                from_balance_to_token numeric,
+                to_balance numeric,
+                to_balance_to_token numeric,
+                primary key (block_id, tx_index)
+            )",
+            L2_TX
+        ),
+        format!("create index if not exists {0}_from_idx on {0} (from_account, block_id)", L2_TX),
+        format!("create index if not exists {0}_to_idx on {0} (to_account, block_id)", L2_TX),
+        // set once the txs of the block are in `l2_tx`, the blocks saved before it existed are left to `backfill`
+        format!(
+            "alter table {} add column if not exists txs_indexed boolean not null default false",
+            tablenames::L2_BLOCK
+        ),
+    ]
+}
+
+/// A tx as listed in the history of its accounts. By tx type:
+///   - deposit: `amount` of `token_id` into `from_account`
+///   - withdraw, full_exit: `amount` of `token_id` out of `from_account`
+///   - transfer: `amount` of `token_id` from `from_account` to `to_account`
+///   - spot_trade: `amount` of `token_id` from `from_account` to `to_account`, and `to_amount` of `to_token_id` back
+///   - place_order: only `from_account`
+///
+/// Balances are the ones after the tx, `from_balance` of `from_account` in `token_id`,
+/// `from_balance_to_token` of `from_account` in `to_token_id` and so on.
+#[derive(Debug, PartialEq)]
+pub struct L2TxRecord {
+    pub tx_index: usize,
+    pub tx_type: TxType,
+    pub from_account: u32,
+    pub to_account: Option<u32>,
+    pub token_id: Option<u32>,
+    pub amount: Option<Fr>,
+    pub to_token_id: Option<u32>,
+    pub to_amount: Option<Fr>,
+    pub from_balance: Option<Fr>,
+    pub from_balance_to_token: Option<Fr>,
+    pub to_balance: Option<Fr>,
+    pub to_balance_to_token: Option<Fr>,
+}
+
+impl L2TxRecord {
+    // the txs of a block but nop
+    pub fn from_block(detail: &L2BlockDetail) -> Vec<Self> {
+        Self::from_txs(&detail.txs_type, &detail.encoded_txs)
+    }
+
+    fn from_txs(txs_type: &[TxType], encoded_txs: &[Vec<Fr>]) -> Vec<Self> {
+        encoded_txs
+            .iter()
+            .zip(txs_type.iter())
+            .enumerate()
+            .filter(|(_, (_, tx_type))| **tx_type != TxType::Nop)
+            .map(|(tx_index, (tx, tx_type))| Self::from_tx(tx_index, *tx_type, tx))
+            .collect()
+    }
+
+    // see `ManagerWrapper` for how each type of tx is encoded
+    fn from_tx(tx_index: usize, tx_type: TxType, tx: &[Fr]) -> Self {
+        let record = Self {
+            tx_index,
+            tx_type,
+            from_account: tx[tx_detail_idx::ACCOUNT_ID1].to_u32(),
+            to_account: None,
+            token_id: None,
+            amount: None,
+            to_token_id: None,
+            to_amount: None,
+            from_balance: None,
+            from_balance_to_token: None,
+            to_balance: None,
+            to_balance_to_token: None,
+        };
+        match tx_type {
+            // for full_exit, the amount is the whole balance
+            TxType::Deposit | TxType::Withdraw | TxType::FullExit => Self {
+                token_id: Some(tx[tx_detail_idx::TOKEN_ID1].to_u32()),
+                amount: Some(tx[tx_detail_idx::AMOUNT]),
+                from_balance: Some(tx[tx_detail_idx::BALANCE2]),
+                ..record
+            },
+            TxType::Transfer => Self {
+                to_account: Some(tx[tx_detail_idx::ACCOUNT_ID2].to_u32()),
+                token_id: Some(tx[tx_detail_idx::TOKEN_ID1].to_u32()),
+                amount: Some(tx[tx_detail_idx::AMOUNT]),
+                from_balance: Some(tx[tx_detail_idx::BALANCE1].sub(&tx[tx_detail_idx::AMOUNT])),
+                to_balance: Some(tx[tx_detail_idx::BALANCE2]),
+                ..record
+            },
+            TxType::SpotTrade => Self {
+                to_account: Some(tx[tx_detail_idx::ACCOUNT_ID2].to_u32()),
+                token_id: Some(tx[tx_detail_idx::NEW_ORDER1_TOKEN_SELL].to_u32()),
+                amount: Some(tx[tx_detail_idx::AMOUNT1]),
+                to_token_id: Some(tx[tx_detail_idx::NEW_ORDER2_TOKEN_SELL].to_u32()),
+                to_amount: Some(tx[tx_detail_idx::AMOUNT2]),
+                from_balance: Some(tx[tx_detail_idx::BALANCE1].sub(&tx[tx_detail_idx::AMOUNT1])),
+                from_balance_to_token: Some(tx[tx_detail_idx::BALANCE4]),
+                to_balance: Some(tx[tx_detail_idx::BALANCE2]),
+                to_balance_to_token: Some(tx[tx_detail_idx::BALANCE3].sub(&tx[tx_detail_idx::AMOUNT2])),
+                ..record
+            },
+            TxType::PlaceOrder | TxType::Nop => record,
+        }
+    }
+}
+
+// decimal strings with the precision of the token
+fn to_decimal(value: Option<Fr>, token_id: Option<u32>) -> Option<String> {
+    Some(value?.to_decimal(prec_token_id(token_id?)).to_string())
+}
+
+// all the txs of a block in one statement
+pub async fn save(tx: &mut Transaction<'_, Postgres>, block_id: usize, detail: &L2BlockDetail) -> anyhow::Result<()> {
+    save_records(tx, block_id, &L2TxRecord::from_block(detail)).await
+}
+
+async fn save_records(tx: &mut Transaction<'_, Postgres>, block_id: usize, records: &[L2TxRecord]) -> anyhow::Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let ids = |f: fn(&L2TxRecord) -> Option<u32>| -> Vec<Option<i32>> { records.iter().map(|r| f(r).map(|id| id as i32)).collect() };
+    let decimals = |value: fn(&L2TxRecord) -> Option<Fr>, token_id: fn(&L2TxRecord) -> Option<u32>| -> Vec<Option<String>> {
+        records.iter().map(|r| to_decimal(value(r), token_id(r))).collect()
+    };
+    sqlx::query(&format!(
+        "insert into {} (block_id, tx_index, tx_type, from_account, to_account, token_id, amount, to_token_id, to_amount,
+            from_balance, from_balance_to_token, to_balance, to_balance_to_token)
+        select $1, * from unnest($2::integer[], $3::varchar[], $4::integer[], $5::integer[], $6::integer[], $7::text[]::numeric[],
+            $8::integer[], $9::text[]::numeric[], $10::text[]::numeric[], $11::text[]::numeric[], $12::text[]::numeric[],
+            $13::text[]::numeric[])",
+        L2_TX
+    ))
+    .bind(block_id as i64)
+    .bind(records.iter().map(|r| r.tx_index as i32).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.tx_type.as_str()).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.from_account as i32).collect::<Vec<_>>())
+    .bind(ids(|r| r.to_account))
+    .bind(ids(|r| r.token_id))
+    .bind(decimals(|r| r.amount, |r| r.token_id))
+    .bind(ids(|r| r.to_token_id))
+    .bind(decimals(|r| r.to_amount, |r| r.to_token_id))
+    .bind(decimals(|r| r.from_balance, |r| r.token_id))
+    .bind(decimals(|r| r.from_balance_to_token, |r| r.to_token_id))
+    .bind(decimals(|r| r.to_balance, |r| r.token_id))
+    .bind(decimals(|r| r.to_balance_to_token, |r| r.to_token_id))
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+const BACKFILL_BATCH: i64 = 100;
+
+// indexes the txs of the blocks saved before `l2_tx` existed, from `detail_bin` or the older JSON `detail`.
+// a batch of blocks is committed at a time, so an interrupted backfill resumes where it stopped.
+// returns the number of blocks indexed
+pub async fn backfill(pool: &PgPool) -> anyhow::Result<usize> {
+    let mut indexed = 0;
+    loop {
+        let rows = sqlx::query(&format!(
+            "select block_id, detail_bin, detail from {} where not txs_indexed order by block_id limit {}",
+            tablenames::L2_BLOCK,
+            BACKFILL_BATCH
+        ))
+        .fetch_all(pool)
+        .await?;
+        if rows.is_empty() {
+            return Ok(indexed);
+        }
+
+        let mut tx = pool.begin().await?;
+        let mut block_ids = Vec::with_capacity(rows.len());
+        for row in &rows {
+            let block_id: i64 = row.try_get("block_id")?;
+            let records = if let Some(data) = row.try_get::<Option<Vec<u8>>, _>("detail_bin")? {
+                L2TxRecord::from_block(&compact::decode_detail(&data)?)
+            } else if let Some(detail) = row.try_get::<Option<serde_json::Value>, _>("detail")? {
+                let detail: L2BlockSerde = serde_json::from_value(detail)?;
+                let encoded_txs: Vec<Vec<Fr>> = detail
+                    .encoded_txs
+                    .into_iter()
+                    .map(|tx| tx.into_iter().map(|fr| fr.0).collect())
+                    .collect();
+                L2TxRecord::from_txs(&detail.txs_type, &encoded_txs)
+            } else {
+                anyhow::bail!("block {} is saved without detail", block_id);
+            };
+            // the blocks saved before `txs_indexed` existed already have their rows
+            sqlx::query(&format!("delete from {} where block_id = $1", L2_TX))
+                .bind(block_id)
+                .execute(&mut tx)
+                .await?;
+            save_records(&mut tx, block_id as usize, &records).await?;
+            block_ids.push(block_id);
+        }
+        sqlx::query(&format!(
+            "update {} set txs_indexed = true where block_id = any($1)",
+            tablenames::L2_BLOCK
+        ))
+        .bind(&block_ids)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        indexed += block_ids.len();
+        log::info!("indexed the txs of {} blocks up to #{}", indexed, block_ids[block_ids.len() - 1]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::l2::TX_LENGTH;
+    use ethers::core::types::U256;
+    use fluidex_common::ff::Field;
+
+    #[test]
+    fn test_records_from_block() {
+        let mut transfer = vec![Fr::zero(); TX_LENGTH];
+        transfer[tx_detail_idx::ACCOUNT_ID1] = Fr::from_u32(1);
+        transfer[tx_detail_idx::ACCOUNT_ID2] = Fr::from_u32(2);
+        transfer[tx_detail_idx::TOKEN_ID1] = Fr::from_u32(3);
+        transfer[tx_detail_idx::TOKEN_ID2] = Fr::from_u32(3);
+        transfer[tx_detail_idx::AMOUNT] = Fr::from_u32(30);
+        transfer[tx_detail_idx::BALANCE1] = Fr::from_u32(100);
+        transfer[tx_detail_idx::BALANCE2] = Fr::from_u32(50);
+        let detail = L2BlockDetail {
+            old_root: Fr::zero(),
+            new_root: Fr::zero(),
+            txdata_hash: U256::zero(),
+            txs_type: vec![TxType::Nop, TxType::Transfer],
+            encoded_txs: vec![vec![Fr::zero(); TX_LENGTH], transfer],
+            balance_path_elements: vec![],
+            order_path_elements: vec![],
+            account_path_elements: vec![],
+            order_roots: vec![],
+            old_account_roots: vec![],
+            new_account_roots: vec![],
+        };
+
+        let records = L2TxRecord::from_block(&detail);
+        assert_eq!(
+            records,
+            vec![L2TxRecord {
+                tx_index: 1,
+                tx_type: TxType::Transfer,
+                from_account: 1,
+                to_account: Some(2),
+                token_id: Some(3),
+                amount: Some(Fr::from_u32(30)),
+                to_token_id: None,
+                to_amount: None,
+                from_balance: Some(Fr::from_u32(70)),
+                from_balance_to_token: None,
+                to_balance: Some(Fr::from_u32(50)),
+                to_balance_to_token: None,
+            }]
+        );
+        assert_eq!(to_decimal(records[0].amount, records[0].token_id).unwrap(), "0.0030");
+    }
+}
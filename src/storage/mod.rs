@@ -0,0 +1,276 @@
+// Schema changes on top of the tables created by fluidex-common's migrations.
+// The statements must be idempotent since they are executed on every start.
+pub mod l2_tx;
+
+use crate::l1::deposit;
+use crate::metrics;
+use crate::types::l2::compact;
+use crate::types::l2::{L2Block, L2BlockSerde};
+use crate::types::matchengine::messages::{PartitionOffsets, TopicPartition};
+use fluidex_common::db::models::tablenames;
+use fluidex_common::db::models::task::TaskStatus;
+use fluidex_common::types::FrExt;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::collections::BTreeMap;
+
+// the last consumed offset of every topic-partition, as of the latest saved block
+pub const KAFKA_OFFSET: &str = "kafka_offset";
+// where every tx (but nop) comes from and the block it is included in, see `TxReceipt`
+pub const TX_RECEIPT: &str = "tx_receipt";
+// the latest L1 blocks scanned by `l1::block_status`, to resume from and to revert if reorged while stopped
+pub const L1_SCANNED_BLOCK: &str = "l1_scanned_block";
+
+fn schema() -> Vec<String> {
+    let mut schema = vec![
+        format!(
+            "alter table {} add column if not exists public_data_aux jsonb",
+            tablenames::L2_BLOCK
+        ),
+        // the consumed offset of `l1::deposit::L1_DEPOSIT_TOPIC` at the end of the block, to replay it with the same deposits
+        format!(
+            "alter table {} add column if not exists l1_deposit_offset bigint",
+            tablenames::L2_BLOCK
+        ),
+        // the detail of new blocks is saved in `detail_bin` only, see `types::l2::compact`
+        format!("alter table {} add column if not exists detail_bin bytea", tablenames::L2_BLOCK),
+        format!("alter table {} alter column detail drop not null", tablenames::L2_BLOCK),
+        // when the proof of the block is submitted, see `grpc::prover`. `BlockStatus` follows L1 and has no proved state
+        format!(
+            "alter table {} add column if not exists proved_time timestamp with time zone",
+            tablenames::L2_BLOCK
+        ),
+        format!(
+            "create table if not exists {} (
+                topic varchar(255) not null,
+                partition_id integer not null,
+                msg_offset bigint not null,
+                block_id bigint not null,
+                primary key (topic, partition_id)
+            )",
+            KAFKA_OFFSET
+        ),
+        format!(
+            "create table if not exists {} (
+                block_id bigint not null,
+                tx_index integer not null,
+                tx_type varchar(32) not null,
+                topic varchar(255),
+                partition_id integer,
+                msg_offset bigint,
+                source_id varchar(255),
+                root_before varchar(66) not null,
+                root_after varchar(66) not null,
+                primary key (block_id, tx_index)
+            )",
+            TX_RECEIPT
+        ),
+        format!(
+            "create index if not exists {0}_msg_idx on {0} (topic, partition_id, msg_offset)",
+            TX_RECEIPT
+        ),
+        format!("create index if not exists {0}_source_idx on {0} (source_id)", TX_RECEIPT),
+        format!(
+            "create table if not exists {} (
+                block_number bigint primary key,
+                block_hash varchar(66) not null,
+                events jsonb not null
+            )",
+            L1_SCANNED_BLOCK
+        ),
+        // task leases of the prover coordinator, see `grpc::prover`
+        format!(
+            "alter table {} add column if not exists lease_deadline timestamp with time zone",
+            tablenames::TASK
+        ),
+        format!(
+            "alter table {} add column if not exists attempts integer not null default 0",
+            tablenames::TASK
+        ),
+        format!("alter table {} add column if not exists last_error text", tablenames::TASK),
+    ];
+    schema.extend(l2_tx::schema());
+    schema
+}
+
+pub async fn migrate(pool: &PgPool) -> anyhow::Result<()> {
+    for stmt in schema() {
+        sqlx::query(&stmt).execute(pool).await?;
+    }
+    Ok(())
+}
+
+// where the saved output ends, the msg processor resumes from here
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub next_block_id: usize,
+    pub kafka_offsets: PartitionOffsets,
+}
+
+pub async fn load_progress(pool: &PgPool) -> anyhow::Result<Progress> {
+    let max_block_id: Option<i64> = sqlx::query_scalar(&format!("select max(block_id) from {}", tablenames::L2_BLOCK))
+        .fetch_one(pool)
+        .await?;
+    let rows = sqlx::query(&format!("select topic, partition_id, msg_offset from {}", KAFKA_OFFSET))
+        .fetch_all(pool)
+        .await?;
+    let kafka_offsets = rows
+        .iter()
+        .map(|row| {
+            let tp = TopicPartition {
+                topic: row.try_get("topic")?,
+                partition: row.try_get("partition_id")?,
+            };
+            Ok((tp, row.try_get("msg_offset")?))
+        })
+        .collect::<Result<_, sqlx::Error>>()?;
+    Ok(Progress {
+        next_block_id: max_block_id.map_or(0, |id| id as usize + 1),
+        kafka_offsets,
+    })
+}
+
+// the L1 deposit offsets of the saved blocks since `from_block`, see `l1::deposit::DepositScheduler`
+pub async fn load_l1_deposit_cursors(pool: &PgPool, from_block: usize) -> anyhow::Result<BTreeMap<usize, i64>> {
+    let rows = sqlx::query(&format!(
+        "select block_id, l1_deposit_offset from {} where block_id >= $1 and l1_deposit_offset is not null",
+        tablenames::L2_BLOCK
+    ))
+    .bind(from_block as i64)
+    .fetch_all(pool)
+    .await?;
+    let cursors = rows
+        .iter()
+        .map(|row| {
+            let block_id: i64 = row.try_get("block_id")?;
+            Ok((block_id as usize, row.try_get("l1_deposit_offset")?))
+        })
+        .collect::<Result<_, sqlx::Error>>()?;
+    Ok(cursors)
+}
+
+// the task of a block is created exactly once, so its id can be derived from the block id
+pub fn task_id(block_id: usize) -> String {
+    format!("task_block_{}", block_id)
+}
+
+// the input of a proving task refers to its block, instead of holding another copy of the detail
+#[derive(Serialize, Deserialize)]
+pub struct TaskInput {
+    pub l2_block: i64,
+}
+
+// the JSON view of a block detail, decoded from `detail_bin`, or read from `detail` for blocks saved before it.
+// returns None if the block does not exist
+pub async fn load_block_detail(pool: &PgPool, block_id: i64) -> anyhow::Result<Option<L2BlockSerde>> {
+    let row = sqlx::query(&format!(
+        "select detail_bin, detail from {} where block_id = $1",
+        tablenames::L2_BLOCK
+    ))
+    .bind(block_id)
+    .fetch_optional(pool)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    if let Some(data) = row.try_get::<Option<Vec<u8>>, _>("detail_bin")? {
+        return Ok(Some(L2BlockSerde::from(compact::decode_detail(&data)?)));
+    }
+    match row.try_get::<Option<serde_json::Value>, _>("detail")? {
+        Some(detail) => Ok(Some(serde_json::from_value(detail)?)),
+        None => anyhow::bail!("block {} is saved without detail", block_id),
+    }
+}
+
+// the JSON input handed to provers: a block reference is replaced by the block detail,
+// and an input saved in full before is returned as is
+pub async fn materialize_task_input(pool: &PgPool, input: String) -> anyhow::Result<String> {
+    let block_id = match serde_json::from_str::<TaskInput>(&input) {
+        Ok(input) => input.l2_block,
+        Err(_) => return Ok(input),
+    };
+    let detail = load_block_detail(pool, block_id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("block {} of task input not found", block_id))?;
+    Ok(serde_json::to_string(&detail)?)
+}
+
+// saves the block, its proving task, the tx receipts and history and the kafka offsets it has consumed in one transaction,
+// so the saved blocks and offsets never diverge
+pub async fn save_block(pool: &PgPool, block: &L2Block) -> anyhow::Result<()> {
+    let _timer = metrics::DB_WRITE_SECONDS.start_timer();
+    let detail = compact::encode_detail(&block.detail)?;
+    let input = TaskInput {
+        l2_block: block.block_id as i64,
+    };
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(&format!(
+        "insert into {} (block_id, new_root, detail_bin, raw_public_data, public_data_aux, l1_deposit_offset, txs_indexed)
+        values ($1, $2, $3, $4, $5, $6, true)",
+        tablenames::L2_BLOCK
+    ))
+    .bind(block.block_id as u32)
+    .bind(block.detail.new_root.to_hex_string())
+    .bind(detail)
+    .bind(&block.public_data)
+    .bind(sqlx::types::Json(&block.public_data_aux))
+    .bind(block.kafka_offsets.get(&deposit::topic_partition()))
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query(&format!(
+        "insert into {} (task_id, circuit, block_id, input, status) values ($1, $2, $3, $4, $5)",
+        tablenames::TASK
+    ))
+    .bind(task_id(block.block_id))
+    .bind(format!("block_{}", block.detail.encoded_txs.len()))
+    .bind(block.block_id as i64)
+    .bind(sqlx::types::Json(&input))
+    .bind(TaskStatus::Inited)
+    .execute(&mut tx)
+    .await?;
+
+    // one statement for all the receipts of the block
+    if !block.receipts.is_empty() {
+        let offsets = || block.receipts.iter().map(|r| r.offset.as_ref());
+        sqlx::query(&format!(
+            "insert into {} (block_id, tx_index, tx_type, topic, partition_id, msg_offset, source_id, root_before, root_after)
+            select $1, * from unnest($2::integer[], $3::varchar[], $4::varchar[], $5::integer[], $6::bigint[], $7::varchar[],
+                $8::varchar[], $9::varchar[])",
+            TX_RECEIPT
+        ))
+        .bind(block.block_id as i64)
+        .bind(block.receipts.iter().map(|r| r.tx_index as i32).collect::<Vec<_>>())
+        .bind(block.receipts.iter().map(|r| r.tx_type.as_str()).collect::<Vec<_>>())
+        .bind(offsets().map(|o| o.map(|o| o.tp.topic.clone())).collect::<Vec<_>>())
+        .bind(offsets().map(|o| o.map(|o| o.tp.partition)).collect::<Vec<_>>())
+        .bind(offsets().map(|o| o.map(|o| o.offset)).collect::<Vec<_>>())
+        .bind(block.receipts.iter().map(|r| r.source_id.clone()).collect::<Vec<_>>())
+        .bind(block.receipts.iter().map(|r| r.root_before.to_hex_string()).collect::<Vec<_>>())
+        .bind(block.receipts.iter().map(|r| r.root_after.to_hex_string()).collect::<Vec<_>>())
+        .execute(&mut tx)
+        .await?;
+    }
+
+    l2_tx::save(&mut tx, block.block_id, &block.detail).await?;
+
+    for (tp, offset) in &block.kafka_offsets {
+        sqlx::query(&format!(
+            "insert into {} (topic, partition_id, msg_offset, block_id) values ($1, $2, $3, $4)
+            on conflict (topic, partition_id) do update set msg_offset = excluded.msg_offset, block_id = excluded.block_id",
+            KAFKA_OFFSET
+        ))
+        .bind(&tp.topic)
+        .bind(tp.partition)
+        .bind(offset)
+        .bind(block.block_id as i64)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
@@ -1,10 +1,11 @@
+use crate::l1::deposit::DepositEvent;
 use crate::types::matchengine::messages::{
-    DepositMessage, Message, OrderMessage, TradeMessage, TransferMessage, UserMessage, WithdrawMessage,
+    DepositMessage, FullExitMessage, Message, MsgOffset, OrderMessage, TradeMessage, TransferMessage, UserMessage, WithdrawMessage,
 };
 use anyhow::{anyhow, Result};
 use serde_json::Value;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum WrappedMessage {
     DEPOSIT(Message<DepositMessage>),
     ORDER(Message<OrderMessage>),
@@ -12,6 +13,28 @@ pub enum WrappedMessage {
     TRANSFER(Message<TransferMessage>),
     USER(Message<UserMessage>),
     WITHDRAW(Message<WithdrawMessage>),
+    FULLEXIT(Message<FullExitMessage>),
+    // confirmed deposit read from L1, see `crate::l1::deposit`
+    L1DEPOSIT(DepositEvent),
+    // a kafka msg which could not be parsed and is dead lettered, only its offset is consumed
+    SKIPPED(MsgOffset),
+}
+
+impl WrappedMessage {
+    // L1 deposits are not from kafka, their offset is their L1 position
+    pub fn with_offset(self, offset: MsgOffset) -> Self {
+        match self {
+            WrappedMessage::DEPOSIT(msg) => WrappedMessage::DEPOSIT(msg.with_offset(offset)),
+            WrappedMessage::ORDER(msg) => WrappedMessage::ORDER(msg.with_offset(offset)),
+            WrappedMessage::TRADE(msg) => WrappedMessage::TRADE(msg.with_offset(offset)),
+            WrappedMessage::TRANSFER(msg) => WrappedMessage::TRANSFER(msg.with_offset(offset)),
+            WrappedMessage::USER(msg) => WrappedMessage::USER(msg.with_offset(offset)),
+            WrappedMessage::WITHDRAW(msg) => WrappedMessage::WITHDRAW(msg.with_offset(offset)),
+            WrappedMessage::FULLEXIT(msg) => WrappedMessage::FULLEXIT(msg.with_offset(offset)),
+            WrappedMessage::L1DEPOSIT(event) => WrappedMessage::L1DEPOSIT(event),
+            WrappedMessage::SKIPPED(_) => WrappedMessage::SKIPPED(offset),
+        }
+    }
 }
 
 pub fn parse_msg(line: String) -> Result<WrappedMessage> {
@@ -44,6 +67,10 @@ pub fn parse_msg(line: String) -> Result<WrappedMessage> {
                 let data: WithdrawMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong withdraw: {}", e))?;
                 Ok(WrappedMessage::WITHDRAW(data.into()))
             }
+            "FullExitMessage" => {
+                let data: FullExitMessage = serde_json::from_value(val).map_err(|e| anyhow!("wrong full exit: {}", e))?;
+                Ok(WrappedMessage::FULLEXIT(data.into()))
+            }
             other => Err(anyhow!("unrecognized type field {}", other)),
         }
     } else {
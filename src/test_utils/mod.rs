@@ -1,3 +1,17 @@
 pub mod circuit;
 pub mod messages;
 pub mod types;
+
+use crate::config::Settings;
+use std::sync::Once;
+
+// `Settings` can only be set once, while unit tests in the same binary may all need it
+pub fn init_test_settings() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let mut s = Settings::new();
+        //don't persist
+        s.persist_every_n_block = 1000;
+        Settings::set(s);
+    });
+}
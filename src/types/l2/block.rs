@@ -1,4 +1,6 @@
+use super::serialize::PubDataAux;
 use super::tx::TxType;
+use crate::types::matchengine::messages::{MsgOffset, PartitionOffsets};
 use crate::types::merkle_tree::MerklePath;
 
 use ethers::core::types::U256;
@@ -24,4 +26,21 @@ pub struct L2Block {
     pub block_id: usize,
     pub detail: L2BlockDetail,
     pub public_data: Vec<u8>,
+    pub public_data_aux: PubDataAux,
+    // the last consumed kafka offsets once the block is applied, saved with the block
+    pub kafka_offsets: PartitionOffsets,
+    // of the txs other than nop
+    pub receipts: Vec<TxReceipt>,
+}
+
+// where a tx comes from and where it is included
+#[derive(Clone, Debug)]
+pub struct TxReceipt {
+    pub tx_index: usize,
+    pub tx_type: TxType,
+    pub offset: Option<MsgOffset>,
+    // the id of the exchange event, e.g. `trade:<trade id>`, see `msg_processor::source_id`
+    pub source_id: Option<String>,
+    pub root_before: Fr,
+    pub root_after: Fr,
 }
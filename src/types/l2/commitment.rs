@@ -0,0 +1,87 @@
+use super::L2BlockDetail;
+use ethers::core::types::U256;
+use fluidex_common::num_bigint::{BigInt, Sign};
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use sha2::Digest;
+
+// the commitment is a single public input, so it must be less than the BN254 scalar field modulus (~2^253.6)
+const COMMITMENT_BITS: usize = 253;
+
+/// The digest of a block passed to `commitBlock`/`verifyBlock` by `l1::calldata`:
+/// `sha256(block_id || old_root || new_root || txdata_hash)` truncated to its low 253 bits,
+/// where every item is encoded as a 32 bytes big endian word.
+///
+/// It is NOT known to match the public input of the block circuit, which is not in this tree,
+/// so it is neither stored nor served. Recompute it from the block detail when needed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockCommitment {
+    pub block_id: u64,
+    pub old_root: Fr,
+    pub new_root: Fr,
+    pub txdata_hash: U256,
+}
+
+pub(crate) fn fr_to_u256(fr: &Fr) -> U256 {
+    U256::from_big_endian(&fr.to_bigint().to_bytes_be().1)
+}
+
+impl BlockCommitment {
+    pub fn new(block_id: usize, detail: &L2BlockDetail) -> Self {
+        Self {
+            block_id: block_id as u64,
+            old_root: detail.old_root,
+            new_root: detail.new_root,
+            txdata_hash: detail.txdata_hash,
+        }
+    }
+
+    pub fn hash(&self) -> U256 {
+        let words = [
+            U256::from(self.block_id),
+            fr_to_u256(&self.old_root),
+            fr_to_u256(&self.new_root),
+            self.txdata_hash,
+        ];
+        let mut buf = [0u8; 32 * 4];
+        for (word, chunk) in words.iter().zip(buf.chunks_mut(32)) {
+            word.to_big_endian(chunk);
+        }
+        let hash = U256::from_big_endian(&sha2::Sha256::digest(&buf));
+        hash & ((U256::one() << COMMITMENT_BITS) - 1)
+    }
+
+    // the hash as the circuit sees it, no reduction happens since it has 253 bits only
+    pub fn to_fr(&self) -> Fr {
+        let mut bytes = [0u8; 32];
+        self.hash().to_big_endian(&mut bytes);
+        Fr::from_bigint(BigInt::from_bytes_be(Sign::Plus, &bytes))
+    }
+
+    pub fn to_hex_string(&self) -> String {
+        let mut bytes = [0u8; 32];
+        self.hash().to_big_endian(&mut bytes);
+        format!("0x{}", hex::encode(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // regression vectors of this implementation, computed with python's hashlib, not shared with the circuits repo
+    #[test]
+    fn test_block_commitment() {
+        let commitment = BlockCommitment {
+            block_id: 1,
+            old_root: Fr::from_u32(1),
+            new_root: Fr::from_u32(2),
+            txdata_hash: U256::from(3),
+        };
+        assert_eq!(
+            commitment.to_hex_string(),
+            "0x0a78081881c0c85f02668b8cb5d2b677ced520d7f86426d24a4991a39c2a11af"
+        );
+        assert_eq!(fr_to_u256(&commitment.to_fr()), commitment.hash());
+    }
+}
@@ -0,0 +1,245 @@
+// Compact binary encoding of `L2BlockDetail` for storage. The JSON of `L2BlockSerde` takes ~78 bytes
+// for every Fr, while most of the Fr in a block are the same path elements (hashes of empty subtrees,
+// siblings shared by txs of the same accounts). So every distinct Fr is kept once as 32 raw bytes,
+// referenced by its index, and the whole is compressed by zstd.
+//
+// layout before compression, integers in little endian:
+//   dict_len: u32, dict_len * [u8; 32]
+//   old_root, new_root: u32 refs, txdata_hash: [u8; 32] (big endian)
+//   tx_num: u32, then per tx:
+//     tx_type: u8, encoded_tx, 4 balance paths, 2 order paths, 2 account paths, 2 order roots,
+//     old account root, new account root
+//   where a list (encoded_tx or path) is len: u32 followed by refs
+use super::block::L2BlockDetail;
+use super::tx::TxType;
+use crate::types::merkle_tree::MerklePath;
+use anyhow::{anyhow, bail, Result};
+use ethers::core::types::U256;
+use fluidex_common::num_bigint::{BigInt, Sign};
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const MAGIC: &[u8; 4] = b"L2BD";
+const VERSION: u8 = 1;
+const ZSTD_LEVEL: i32 = 3;
+
+fn fr_to_bytes(fr: &Fr) -> [u8; 32] {
+    let (_, bytes) = fr.to_bigint().to_bytes_be();
+    let mut out = [0u8; 32];
+    out[32 - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+fn fr_from_bytes(bytes: &[u8; 32]) -> Fr {
+    Fr::from_bigint(BigInt::from_bytes_be(Sign::Plus, bytes))
+}
+
+#[derive(Default)]
+struct Writer {
+    dict: HashMap<[u8; 32], u32>,
+    values: Vec<[u8; 32]>,
+    body: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, v: u32) {
+        self.body.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn fr(&mut self, fr: &Fr) {
+        let bytes = fr_to_bytes(fr);
+        let next = self.values.len() as u32;
+        let idx = *self.dict.entry(bytes).or_insert(next);
+        if idx == next {
+            self.values.push(bytes);
+        }
+        self.u32(idx);
+    }
+
+    fn frs<'a>(&mut self, frs: impl ExactSizeIterator<Item = &'a Fr>) {
+        self.u32(frs.len() as u32);
+        for fr in frs {
+            self.fr(fr);
+        }
+    }
+
+    fn path(&mut self, path: &MerklePath) {
+        self.frs(path.iter().map(|leaf| &leaf[0]));
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(4 + self.values.len() * 32 + self.body.len());
+        raw.extend_from_slice(&(self.values.len() as u32).to_le_bytes());
+        for value in &self.values {
+            raw.extend_from_slice(value);
+        }
+        raw.extend_from_slice(&self.body);
+        raw
+    }
+}
+
+struct Reader<'a> {
+    dict: Vec<Fr>,
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            bail!("truncated block detail");
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn fr(&mut self) -> Result<Fr> {
+        let idx = self.u32()? as usize;
+        self.dict
+            .get(idx)
+            .copied()
+            .ok_or_else(|| anyhow!("invalid ref {} in block detail", idx))
+    }
+
+    fn frs(&mut self) -> Result<Vec<Fr>> {
+        let len = self.u32()?;
+        (0..len).map(|_| self.fr()).collect()
+    }
+
+    fn path(&mut self) -> Result<MerklePath> {
+        Ok(self.frs()?.into_iter().map(|fr| [fr]).collect())
+    }
+
+    fn paths<const N: usize>(&mut self) -> Result<[MerklePath; N]> {
+        let paths = (0..N).map(|_| self.path()).collect::<Result<Vec<_>>>()?;
+        Ok(paths.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+}
+
+pub fn encode_detail(detail: &L2BlockDetail) -> Result<Vec<u8>> {
+    let mut w = Writer::default();
+    w.fr(&detail.old_root);
+    w.fr(&detail.new_root);
+    let mut txdata_hash = [0u8; 32];
+    detail.txdata_hash.to_big_endian(&mut txdata_hash);
+    w.body.extend_from_slice(&txdata_hash);
+
+    w.u32(detail.txs_type.len() as u32);
+    for i in 0..detail.txs_type.len() {
+        w.body.push(detail.txs_type[i] as u8);
+        w.frs(detail.encoded_txs[i].iter());
+        for path in detail.balance_path_elements[i]
+            .iter()
+            .chain(&detail.order_path_elements[i])
+            .chain(&detail.account_path_elements[i])
+        {
+            w.path(path);
+        }
+        w.fr(&detail.order_roots[i][0]);
+        w.fr(&detail.order_roots[i][1]);
+        w.fr(&detail.old_account_roots[i]);
+        w.fr(&detail.new_account_roots[i]);
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend(zstd::stream::encode_all(&w.finish()[..], ZSTD_LEVEL)?);
+    Ok(out)
+}
+
+pub fn decode_detail(data: &[u8]) -> Result<L2BlockDetail> {
+    if data.len() < 5 || &data[..4] != MAGIC {
+        bail!("not a compact block detail");
+    }
+    if data[4] != VERSION {
+        bail!("unknown block detail version {}", data[4]);
+    }
+    let raw = zstd::stream::decode_all(&data[5..])?;
+
+    let mut r = Reader { dict: vec![], buf: &raw };
+    let dict_len = r.u32()?;
+    for _ in 0..dict_len {
+        let fr = fr_from_bytes(r.bytes(32)?.try_into().unwrap());
+        r.dict.push(fr);
+    }
+
+    let old_root = r.fr()?;
+    let new_root = r.fr()?;
+    let txdata_hash = U256::from_big_endian(r.bytes(32)?);
+    let tx_num = r.u32()? as usize;
+    let mut detail = L2BlockDetail {
+        old_root,
+        new_root,
+        txdata_hash,
+        txs_type: Vec::with_capacity(tx_num),
+        encoded_txs: Vec::with_capacity(tx_num),
+        balance_path_elements: Vec::with_capacity(tx_num),
+        order_path_elements: Vec::with_capacity(tx_num),
+        account_path_elements: Vec::with_capacity(tx_num),
+        order_roots: Vec::with_capacity(tx_num),
+        old_account_roots: Vec::with_capacity(tx_num),
+        new_account_roots: Vec::with_capacity(tx_num),
+    };
+    for _ in 0..tx_num {
+        let tx_type = r.bytes(1)?[0];
+        detail.txs_type.push(tx_type_from_u8(tx_type)?);
+        detail.encoded_txs.push(r.frs()?);
+        detail.balance_path_elements.push(r.paths()?);
+        detail.order_path_elements.push(r.paths()?);
+        detail.account_path_elements.push(r.paths()?);
+        detail.order_roots.push([r.fr()?, r.fr()?]);
+        detail.old_account_roots.push(r.fr()?);
+        detail.new_account_roots.push(r.fr()?);
+    }
+    if !r.buf.is_empty() {
+        bail!("{} trailing bytes in block detail", r.buf.len());
+    }
+    Ok(detail)
+}
+
+fn tx_type_from_u8(v: u8) -> Result<TxType> {
+    let tx_type = TxType::ALL.iter().find(|t| **t as u8 == v).copied();
+    tx_type.ok_or_else(|| anyhow!("invalid tx type {}", v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::l2::L2BlockSerde;
+    use fluidex_common::ff::Field;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_compact_detail() {
+        let fr = |s: &str| Fr::from_bigint(BigInt::from_str(s).unwrap());
+        let big = fr("21888242871839275222246405745257275088548364400416034343698204186575808495616");
+        let path: MerklePath = vec![[fr("1")], [big], [Fr::zero()]];
+        let tx_num = 2;
+        let detail = L2BlockDetail {
+            old_root: fr("12345"),
+            new_root: big,
+            txdata_hash: U256::from(7u64) << 200,
+            txs_type: vec![TxType::Deposit, TxType::Nop],
+            encoded_txs: vec![vec![fr("1"), fr("2"), Fr::zero()], vec![Fr::zero(); 3]],
+            balance_path_elements: vec![[path.clone(), path.clone(), path.clone(), path.clone()]; tx_num],
+            order_path_elements: vec![[path.clone(), path.clone()]; tx_num],
+            account_path_elements: vec![[path.clone(), path.clone()]; tx_num],
+            order_roots: vec![[fr("3"), fr("4")]; tx_num],
+            old_account_roots: vec![fr("5"); tx_num],
+            new_account_roots: vec![fr("6"); tx_num],
+        };
+        let data = encode_detail(&detail).unwrap();
+        let json = serde_json::to_string(&L2BlockSerde::from(detail.clone())).unwrap();
+        assert!(data.len() < json.len() / 2);
+
+        let decoded = decode_detail(&data).unwrap();
+        assert_eq!(serde_json::to_string(&L2BlockSerde::from(decoded)).unwrap(), json);
+        assert!(decode_detail(&data[..data.len() - 1]).is_err());
+    }
+}
@@ -1,12 +1,17 @@
 pub mod block;
+pub mod commitment;
+pub mod compact;
 pub mod order;
 pub mod serialize;
 pub mod tx;
 pub mod tx_data;
+pub mod tx_decode;
 pub mod tx_encode;
 
 pub use block::*;
+pub use commitment::*;
 pub use order::*;
 pub use serialize::*;
 pub use tx::*;
 pub use tx_data::*;
+pub use tx_decode::*;
@@ -1,5 +1,6 @@
 pub use crate::types::l2;
 pub use crate::types::merkle_tree::MerklePath;
+use fluidex_common::ff::Field;
 use fluidex_common::num_bigint::BigInt;
 use fluidex_common::types::FrExt;
 use fluidex_common::Fr;
@@ -107,6 +108,7 @@ impl Serialize for l2::TxType {
             l2::TxType::Withdraw => 3,
             l2::TxType::PlaceOrder => 4,
             l2::TxType::SpotTrade => 5,
+            l2::TxType::FullExit => 6,
         })
     }
 }
@@ -157,6 +159,7 @@ impl<'de> Deserialize<'de> for l2::TxType {
                     3 => l2::TxType::Withdraw,
                     4 => l2::TxType::PlaceOrder,
                     5 => l2::TxType::SpotTrade,
+                    6 => l2::TxType::FullExit,
                     _ => return Err(de::Error::invalid_type(de::Unexpected::Signed(v), &self)),
                 };
                 Ok(tx_type)
@@ -194,6 +197,7 @@ impl<'de> Deserialize<'de> for l2::TxType {
                     3 => l2::TxType::Withdraw,
                     4 => l2::TxType::PlaceOrder,
                     5 => l2::TxType::SpotTrade,
+                    6 => l2::TxType::FullExit,
                     _ => return Err(de::Error::invalid_type(de::Unexpected::Unsigned(v), &self)),
                 };
                 Ok(tx_type)
@@ -276,22 +280,52 @@ impl From<l2::L2BlockDetail> for L2BlockSerde {
     }
 }
 
-
-#[derive(Serialize)]
+// Positions (tx index in the block, each tx takes `pubdata_len_bits` in `public_data`) of the txs
+// which the L1 contract has to match against its priority queue.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct PubDataAux {
     #[serde(rename = "deposit")]
-    pub deposit_txs_pos: Vec<u32>
+    pub deposit_txs_pos: Vec<u32>,
+    #[serde(rename = "depositCount")]
+    pub deposit_count: u32,
+    #[serde(rename = "keyUpdate")]
+    pub key_update_txs_pos: Vec<u32>,
+    #[serde(rename = "keyUpdateCount")]
+    pub key_update_count: u32,
+    // blocks saved before full exit was introduced do not have these
+    #[serde(rename = "fullExit", default)]
+    pub full_exit_txs_pos: Vec<u32>,
+    #[serde(rename = "fullExitCount", default)]
+    pub full_exit_count: u32,
 }
 
-
-impl<'d> From<&'d l2::L2Block> for PubDataAux {
-    fn from(origin: &'d l2::L2Block) -> Self {
-
-        let deposit : Vec<u32> = 
-            origin.detail.txs_type.
-
+impl<'d> From<&'d l2::L2BlockDetail> for PubDataAux {
+    fn from(origin: &'d l2::L2BlockDetail) -> Self {
+        let mut deposit_txs_pos = Vec::new();
+        let mut key_update_txs_pos = Vec::new();
+        let mut full_exit_txs_pos = Vec::new();
+        for (pos, (tx_type, payload)) in origin.txs_type.iter().zip(origin.encoded_txs.iter()).enumerate() {
+            if *tx_type == l2::TxType::FullExit {
+                full_exit_txs_pos.push(pos as u32);
+                continue;
+            }
+            if *tx_type != l2::TxType::Deposit {
+                continue;
+            }
+            // key update is encoded as a deposit to new account with zero amount
+            if payload[l2::tx_detail_idx::DST_IS_NEW] != Fr::zero() {
+                key_update_txs_pos.push(pos as u32);
+            } else {
+                deposit_txs_pos.push(pos as u32);
+            }
+        }
         PubDataAux {
-            deposit_txs_pos: Vec::default(),
+            deposit_count: deposit_txs_pos.len() as u32,
+            deposit_txs_pos,
+            key_update_count: key_update_txs_pos.len() as u32,
+            key_update_txs_pos,
+            full_exit_count: full_exit_txs_pos.len() as u32,
+            full_exit_txs_pos,
         }
     }
-}
\ No newline at end of file
+}
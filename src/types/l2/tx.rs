@@ -1,4 +1,5 @@
 use super::order;
+use crate::types::matchengine::messages::MsgOffset;
 use crate::types::merkle_tree::MerklePath;
 use anyhow::{anyhow, Result};
 use ethers::core::types::U256;
@@ -19,6 +20,49 @@ pub enum TxType {
     Withdraw,
     PlaceOrder,
     SpotTrade,
+    FullExit,
+}
+
+impl TxType {
+    pub const ALL: [TxType; 7] = [
+        TxType::Nop,
+        TxType::Deposit,
+        TxType::Transfer,
+        TxType::Withdraw,
+        TxType::PlaceOrder,
+        TxType::SpotTrade,
+        TxType::FullExit,
+    ];
+
+    // the inverse of `from_str`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TxType::Nop => "nop",
+            TxType::Deposit => "deposit",
+            TxType::Transfer => "transfer",
+            TxType::Withdraw => "withdraw",
+            TxType::PlaceOrder => "place_order",
+            TxType::SpotTrade => "spot_trade",
+            TxType::FullExit => "full_exit",
+        }
+    }
+}
+
+impl std::str::FromStr for TxType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "nop" => Ok(TxType::Nop),
+            "deposit" => Ok(TxType::Deposit),
+            "transfer" => Ok(TxType::Transfer),
+            "withdraw" => Ok(TxType::Withdraw),
+            "place_order" => Ok(TxType::PlaceOrder),
+            "spot_trade" => Ok(TxType::SpotTrade),
+            "full_exit" => Ok(TxType::FullExit),
+            other => Err(anyhow!("unknown tx type {}", other)),
+        }
+    }
 }
 
 pub struct RawTx {
@@ -36,7 +80,7 @@ pub struct RawTx {
     pub account_path1: MerklePath,
     pub root_before: Fr,
     pub root_after: Fr,
-    pub offset: Option<i64>,
+    pub offset: Option<MsgOffset>,
     // debug info
     // extra: any;
 }
@@ -55,12 +99,13 @@ pub enum L2Tx {
     Transfer(TransferTx),
     FullSpotTrade(FullSpotTradeTx),
     Withdraw(WithdrawTx),
+    FullExit(FullExitTx),
 }
 
 #[derive(Debug)]
 pub struct NopTx {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DepositTx {
     pub account_id: u32,
     pub token_id: u32,
@@ -185,6 +230,15 @@ impl WithdrawTx {
     }
 }
 
+// FullExitTx is requested on L1 through the priority queue, to withdraw the whole balance of a token
+// even if the operator ignores the L2 withdraw requests of the account.
+// It is not signed by the l2 key, and the amount is decided by the state when the tx is executed.
+#[derive(Debug, Clone)]
+pub struct FullExitTx {
+    pub account_id: u32,
+    pub token_id: u32,
+}
+
 // https://github.com/fluidex/circuits/issues/144
 // https://github.com/fluidex/circuits/pull/181
 struct BitEncodeContext {
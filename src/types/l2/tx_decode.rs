@@ -0,0 +1,457 @@
+// Decoder of the bit-packed public data produced by `TxDataEncoder`.
+// Every tx takes a fixed slot of `pubdata_len_bits`, starting with a 3 bits heading.
+// Integers are stored lsb first, and the bits are filled into bytes from the msb.
+use super::{AmountType, EncodingParam, TxDataEncoder};
+use anyhow::{anyhow, bail, Result};
+use fluidex_common::ff::Field;
+use fluidex_common::num_bigint::{BigInt, BigUint};
+use fluidex_common::types::FrExt;
+use fluidex_common::Fr;
+use num::{One, Zero};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PubDataTx {
+    Nop,
+    Deposit {
+        account_id: u32,
+        token_id: u32,
+        amount: u128,
+    },
+    Transfer {
+        from: u32,
+        to: u32,
+        token_id: u32,
+        amount: u128,
+    },
+    KeyUpdate {
+        account_id: u32,
+        sign: Fr,
+        ay: Fr,
+    },
+    Withdraw {
+        account_id: u32,
+        token_id: u32,
+        amount: u128,
+    },
+    FullExit {
+        account_id: u32,
+        token_id: u32,
+        amount: u128,
+    },
+    PlaceOrder {
+        account_id: u32,
+        order_pos: u32,
+        order_id: u32,
+        token_sell: u32,
+        token_buy: u32,
+        total_sell: Fr,
+        total_buy: Fr,
+    },
+    // a PlaceOrder of the empty order
+    CancelOrder {
+        account_id: u32,
+        order_pos: u32,
+    },
+    SpotTrade {
+        order1_account_id: u32,
+        order2_account_id: u32,
+        token_id_1to2: u32,
+        token_id_2to1: u32,
+        // order amounts are decompressed from Float40
+        order1_total_sell: Fr,
+        order1_total_buy: Fr,
+        order1_pos: u32,
+        order1_id: u32,
+        order2_total_sell: Fr,
+        order2_total_buy: Fr,
+        order2_pos: u32,
+        order2_id: u32,
+        order1_filled: bool,
+        order2_filled: bool,
+    },
+}
+
+struct BitDecodeContext<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitDecodeContext<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self
+            .data
+            .get(self.pos / 8)
+            .ok_or_else(|| anyhow!("unexpected end of public data"))?;
+        let bit = byte & (128u8 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn read_u128(&mut self, bits: u32) -> Result<u128> {
+        assert!(bits <= 128);
+        let mut n = 0u128;
+        for i in 0..bits {
+            if self.read_bit()? {
+                n |= 1u128 << i;
+            }
+        }
+        Ok(n)
+    }
+
+    fn read_u32(&mut self, bits: u32) -> Result<u32> {
+        assert!(bits <= 32);
+        Ok(self.read_u128(bits)? as u32)
+    }
+
+    fn read_big(&mut self, bits: u32) -> Result<BigUint> {
+        let mut n = BigUint::zero();
+        for i in 0..bits {
+            if self.read_bit()? {
+                n |= BigUint::one() << i;
+            }
+        }
+        Ok(n)
+    }
+
+    fn read_amount_compressed(&mut self) -> Result<Fr> {
+        let encoded = self.read_u128(super::AMOUNT_LEN * 8)?;
+        let amount = AmountType::from_encoded_bigint(BigInt::from(encoded))?;
+        Ok(Fr::from_bigint(amount.to_bigint()))
+    }
+
+    // the rest of the slot must be zero padding
+    fn check_padding(&mut self) -> Result<()> {
+        while self.pos < self.data.len() * 8 {
+            if self.read_bit()? {
+                bail!("non zero padding at bit {}", self.pos - 1);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The inverse of [`TxDataEncoder`] for the same tree levels.
+pub struct TxDataDecoder {
+    pub account_bits: u32,
+    pub token_bits: u32,
+    pub order_bits: u32,
+    tx_encode_bits: usize,
+}
+
+impl TxDataDecoder {
+    pub fn new(balance_levels: u32, order_levels: u32, account_levels: u32) -> Self {
+        let encoder = TxDataEncoder::new(balance_levels, order_levels, account_levels);
+        Self {
+            account_bits: encoder.account_bits,
+            token_bits: encoder.token_bits,
+            order_bits: encoder.order_bits,
+            tx_encode_bits: encoder.data_bits(),
+        }
+    }
+
+    pub fn decode(&self, public_data: &[u8]) -> Result<Vec<PubDataTx>> {
+        // data_bits() is always aligned to bytes
+        let slot_len = self.tx_encode_bits / 8;
+        if public_data.len() % slot_len != 0 {
+            bail!(
+                "public data length {} is not a multiple of tx length {}",
+                public_data.len(),
+                slot_len
+            );
+        }
+        public_data.chunks(slot_len).map(|slot| self.decode_tx(slot)).collect()
+    }
+
+    pub fn decode_tx(&self, slot: &[u8]) -> Result<PubDataTx> {
+        let mut ctx = BitDecodeContext::new(slot);
+        let heading = ctx.read_u32(3)?;
+        let tx = match heading {
+            // deposit, transfer and nop share the common scheme.
+            // A deposit is encoded with both account ids set to the receiver, which is unambiguous
+            // since transfers to self are rejected by `ManagerWrapper::transfer`
+            0 => {
+                let account_id1 = ctx.read_u32(self.account_bits)?;
+                let account_id2 = ctx.read_u32(self.account_bits)?;
+                let token_id = ctx.read_u32(self.token_bits)?;
+                let amount = ctx.read_u128(128)?;
+                if account_id1 == 0 && account_id2 == 0 && token_id == 0 && amount == 0 {
+                    PubDataTx::Nop
+                } else if account_id1 == account_id2 {
+                    PubDataTx::Deposit {
+                        account_id: account_id1,
+                        token_id,
+                        amount,
+                    }
+                } else {
+                    PubDataTx::Transfer {
+                        from: account_id1,
+                        to: account_id2,
+                        token_id,
+                        amount,
+                    }
+                }
+            }
+            1 => {
+                let account_id = ctx.read_u32(self.account_bits)?;
+                let sign = Fr::from_u32(ctx.read_u32(1)?);
+                let ay = Fr::from_bigint(BigInt::from(ctx.read_big(254)?));
+                PubDataTx::KeyUpdate { account_id, sign, ay }
+            }
+            3 => {
+                let account_id = ctx.read_u32(self.account_bits)?;
+                // the account is encoded twice like withdraw
+                if ctx.read_u32(self.account_bits)? != account_id {
+                    bail!("full exit with different accounts");
+                }
+                let token_id = ctx.read_u32(self.token_bits)?;
+                let amount = ctx.read_u128(128)?;
+                PubDataTx::FullExit {
+                    account_id,
+                    token_id,
+                    amount,
+                }
+            }
+            5 => {
+                let account_id = ctx.read_u32(self.account_bits)?;
+                let order_pos = ctx.read_u32(self.order_bits)?;
+                let order_id = ctx.read_u32(32)?;
+                let token_sell = ctx.read_u32(self.token_bits)?;
+                let token_buy = ctx.read_u32(self.token_bits)?;
+                let total_sell = ctx.read_amount_compressed()?;
+                let total_buy = ctx.read_amount_compressed()?;
+                if total_sell.is_zero() {
+                    PubDataTx::CancelOrder { account_id, order_pos }
+                } else {
+                    PubDataTx::PlaceOrder {
+                        account_id,
+                        order_pos,
+                        order_id,
+                        token_sell,
+                        token_buy,
+                        total_sell,
+                        total_buy,
+                    }
+                }
+            }
+            2 | 4 | 6 => {
+                let account_id1 = ctx.read_u32(self.account_bits)?;
+                let account_id2 = ctx.read_u32(self.account_bits)?;
+                // withdraw shares heading 4 with a spot trade which only fills order2,
+                // but a withdraw always encodes the same account twice while self trade is not allowed
+                if heading == 4 && account_id1 == account_id2 {
+                    let token_id = ctx.read_u32(self.token_bits)?;
+                    let amount = ctx.read_u128(128)?;
+                    PubDataTx::Withdraw {
+                        account_id: account_id1,
+                        token_id,
+                        amount,
+                    }
+                } else {
+                    PubDataTx::SpotTrade {
+                        order1_account_id: account_id1,
+                        order2_account_id: account_id2,
+                        token_id_1to2: ctx.read_u32(self.token_bits)?,
+                        token_id_2to1: ctx.read_u32(self.token_bits)?,
+                        order1_total_sell: ctx.read_amount_compressed()?,
+                        order1_total_buy: ctx.read_amount_compressed()?,
+                        order1_pos: ctx.read_u32(self.order_bits)?,
+                        order1_id: ctx.read_u32(32)?,
+                        order2_total_sell: ctx.read_amount_compressed()?,
+                        order2_total_buy: ctx.read_amount_compressed()?,
+                        order2_pos: ctx.read_u32(self.order_bits)?,
+                        order2_id: ctx.read_u32(32)?,
+                        order1_filled: heading & 2 != 0,
+                        order2_filled: heading & 4 != 0,
+                    }
+                }
+            }
+            _ => bail!("unknown tx heading {}", heading),
+        };
+        ctx.check_padding()?;
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        order, DepositTx, EncodeForPubData, FullSpotTradeTx, L2Key, NopTx, SpotTradeTx, TransferTx, UpdateKeyTx, WithdrawTx,
+    };
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const BALANCE_LEVELS: u32 = 3;
+    const ORDER_LEVELS: u32 = 4;
+    const ACCOUNT_LEVELS: u32 = 5;
+
+    fn random_amount(rng: &mut StdRng) -> Fr {
+        // keep it exactly representable by Float40
+        let significand: u64 = rng.gen_range(1..1_000_000);
+        Fr::from_u64(significand * 10u64.pow(rng.gen_range(0..6)))
+    }
+
+    fn random_order(rng: &mut StdRng, account_id: u32, token_sell: u32, token_buy: u32, filled: bool) -> order::Order {
+        let mut order = order::Order::default();
+        order.account_id = account_id;
+        order.order_id = rng.gen();
+        order.side = order::OrderSide::Buy;
+        order.token_sell = Fr::from_u32(token_sell);
+        order.token_buy = Fr::from_u32(token_buy);
+        order.total_sell = random_amount(rng);
+        order.total_buy = random_amount(rng);
+        if filled {
+            order.filled_buy = order.total_buy;
+        }
+        order
+    }
+
+    // encode a random tx and return what it should be decoded to
+    fn encode_random_tx(rng: &mut StdRng, encoder: &mut TxDataEncoder) -> PubDataTx {
+        let max_account = 1u32 << ACCOUNT_LEVELS;
+        let max_token = 1u32 << BALANCE_LEVELS;
+        let account_id = rng.gen_range(1..max_account);
+        let other_account_id = (account_id + rng.gen_range(1..max_account - 1)) % max_account;
+        let token_id = rng.gen_range(0..max_token);
+        let amount: u128 = rng.gen_range(1..u128::MAX);
+        match rng.gen_range(0..6) {
+            0 => {
+                NopTx {}.encode_pubdata(encoder).unwrap();
+                PubDataTx::Nop
+            }
+            1 => {
+                DepositTx {
+                    account_id,
+                    token_id,
+                    amount,
+                    l2key: None,
+                }
+                .encode_pubdata(encoder)
+                .unwrap();
+                PubDataTx::Deposit {
+                    account_id,
+                    token_id,
+                    amount,
+                }
+            }
+            2 => {
+                TransferTx::new(account_id, other_account_id, token_id, amount)
+                    .encode_pubdata(encoder)
+                    .unwrap();
+                PubDataTx::Transfer {
+                    from: account_id,
+                    to: other_account_id,
+                    token_id,
+                    amount,
+                }
+            }
+            3 => {
+                let sign = Fr::from_u32(rng.gen_range(0..2));
+                let ay = Fr::from_bigint(BigInt::from_bytes_be(
+                    fluidex_common::num_bigint::Sign::Plus,
+                    &rng.gen::<[u8; 31]>(),
+                ));
+                UpdateKeyTx {
+                    account_id,
+                    l2key: L2Key {
+                        eth_addr: Fr::zero(),
+                        sign,
+                        ay,
+                    },
+                }
+                .encode_pubdata(encoder)
+                .unwrap();
+                PubDataTx::KeyUpdate { account_id, sign, ay }
+            }
+            4 => {
+                WithdrawTx::new(account_id, token_id, amount, Fr::zero())
+                    .encode_pubdata(encoder)
+                    .unwrap();
+                PubDataTx::Withdraw {
+                    account_id,
+                    token_id,
+                    amount,
+                }
+            }
+            _ => {
+                let token_id_2to1 = (token_id + 1) % max_token;
+                // at least one of the orders is filled by a trade
+                let (order1_filled, order2_filled) = match rng.gen_range(0..3) {
+                    0 => (true, false),
+                    1 => (false, true),
+                    _ => (true, true),
+                };
+                let order1 = random_order(rng, account_id, token_id, token_id_2to1, order1_filled);
+                let order2 = random_order(rng, other_account_id, token_id_2to1, token_id, order2_filled);
+                let order1_pos = rng.gen_range(0..1u32 << ORDER_LEVELS);
+                let order2_pos = rng.gen_range(0..1u32 << ORDER_LEVELS);
+                let expected = PubDataTx::SpotTrade {
+                    order1_account_id: account_id,
+                    order2_account_id: other_account_id,
+                    token_id_1to2: token_id,
+                    token_id_2to1,
+                    order1_total_sell: order1.total_sell,
+                    order1_total_buy: order1.total_buy,
+                    order1_pos,
+                    order1_id: order1.order_id,
+                    order2_total_sell: order2.total_sell,
+                    order2_total_buy: order2.total_buy,
+                    order2_pos,
+                    order2_id: order2.order_id,
+                    order1_filled,
+                    order2_filled,
+                };
+                let tx = FullSpotTradeTx {
+                    trade: SpotTradeTx {
+                        order1_account_id: account_id,
+                        order2_account_id: other_account_id,
+                        token_id_1to2: token_id,
+                        token_id_2to1,
+                        amount_1to2: Fr::zero(),
+                        amount_2to1: Fr::zero(),
+                        order1_id: order1.order_id,
+                        order2_id: order2.order_id,
+                    },
+                    maker_order: Some(order1),
+                    taker_order: Some(order2),
+                };
+                (tx, (order1_pos, order2_pos)).encode_pubdata(encoder).unwrap();
+                expected
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut encoder = TxDataEncoder::new(BALANCE_LEVELS, ORDER_LEVELS, ACCOUNT_LEVELS);
+        let decoder = TxDataDecoder::new(BALANCE_LEVELS, ORDER_LEVELS, ACCOUNT_LEVELS);
+        for _ in 0..50 {
+            let expected: Vec<PubDataTx> = (0..rng.gen_range(1..10))
+                .map(|_| encode_random_tx(&mut rng, &mut encoder))
+                .collect();
+            let (_, public_data) = encoder.finish_with_raw();
+            assert_eq!(decoder.decode(&public_data).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let mut encoder = TxDataEncoder::new(BALANCE_LEVELS, ORDER_LEVELS, ACCOUNT_LEVELS);
+        let decoder = TxDataDecoder::new(BALANCE_LEVELS, ORDER_LEVELS, ACCOUNT_LEVELS);
+        NopTx {}.encode_pubdata(&mut encoder).unwrap();
+        let (_, mut public_data) = encoder.finish_with_raw();
+        assert!(decoder.decode(&public_data[1..]).is_err());
+        // 0b111 is not a valid heading
+        public_data[0] = 0b1110_0000;
+        assert!(decoder.decode(&public_data).is_err());
+        // padding must be zero
+        public_data[0] = 0;
+        *public_data.last_mut().unwrap() = 1;
+        assert!(decoder.decode(&public_data).is_err());
+    }
+}
@@ -1,15 +1,32 @@
 #![allow(clippy::upper_case_acronyms)]
+use std::collections::BTreeMap;
 use std::ops::{Deref, DerefMut};
 
 use fluidex_common::rust_decimal::Decimal;
 use fluidex_common::serde::HexArray;
 use serde::{Deserialize, Serialize};
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicPartition {
+    pub topic: String,
+    pub partition: i32,
+}
+
+// where a message is in kafka
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MsgOffset {
+    pub tp: TopicPartition,
+    pub offset: i64,
+}
+
+// the last consumed offset of every topic-partition
+pub type PartitionOffsets = BTreeMap<TopicPartition, i64>;
+
 // TODO: reuse related types def in dingir-exchange
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message<T> {
     message: T,
-    offset: Option<i64>,
+    offset: Option<MsgOffset>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -176,9 +193,18 @@ pub struct TransferMessage {
     pub signature: [u8; 64],
 }
 
+// a full exit request popped from the priority queue of the L1 contract
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FullExitMessage {
+    pub serial_id: u64,
+    pub user_id: u32,
+    pub asset: String,
+}
+
 pub trait TxMessage {}
 
 impl TxMessage for DepositMessage {}
+impl TxMessage for FullExitMessage {}
 impl TxMessage for OrderMessage {}
 impl TxMessage for TradeMessage {}
 impl TxMessage for TransferMessage {}
@@ -186,18 +212,23 @@ impl TxMessage for UserMessage {}
 impl TxMessage for WithdrawMessage {}
 
 impl<T: TxMessage> Message<T> {
-    pub fn new(message: T, offset: i64) -> Self {
+    pub fn new(message: T, offset: MsgOffset) -> Self {
         Self {
             message,
             offset: Some(offset),
         }
     }
 
-    pub fn offset(&self) -> Option<i64> {
-        self.offset
+    pub fn offset(&self) -> Option<&MsgOffset> {
+        self.offset.as_ref()
+    }
+
+    pub fn with_offset(mut self, offset: MsgOffset) -> Self {
+        self.offset = Some(offset);
+        self
     }
 
-    pub fn into_parts(self) -> (T, Option<i64>) {
+    pub fn into_parts(self) -> (T, Option<MsgOffset>) {
         (self.message, self.offset)
     }
 }
@@ -208,8 +239,8 @@ impl<T: TxMessage> From<T> for Message<T> {
     }
 }
 
-impl<T: TxMessage> From<(T, i64)> for Message<T> {
-    fn from((message, offset): (T, i64)) -> Self {
+impl<T: TxMessage> From<(T, MsgOffset)> for Message<T> {
+    fn from((message, offset): (T, MsgOffset)) -> Self {
         Self {
             message,
             offset: Some(offset),
@@ -28,6 +28,7 @@ struct HashCacheItemN<const LENGTH: usize> {
 type HashCacheItem = HashCacheItemN<2>;
 
 // TODO: use leaf_index/leaf_type as generics
+#[derive(Clone)]
 pub struct Tree {
     pub height: usize,
     // precalculate mid hashes, so we don't have to store the empty nodes
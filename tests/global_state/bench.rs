@@ -4,15 +4,15 @@ use anyhow::Result;
 use fluidex_common::rust_decimal_macros::dec;
 use fluidex_common::types::{Decimal, DecimalExt};
 use rollup_state_manager::account::Account;
-use rollup_state_manager::msg::msg_processor;
+use rollup_state_manager::metrics;
+use rollup_state_manager::msg::{msg_processor, msg_source};
 use rollup_state_manager::params;
 use rollup_state_manager::state::{GlobalState, ManagerWrapper};
-use rollup_state_manager::test_utils::messages::{parse_msg, WrappedMessage};
+use rollup_state_manager::test_utils::messages::WrappedMessage;
 use rollup_state_manager::test_utils::types::{get_mnemonic_by_account_id, prec_token_id};
 use rollup_state_manager::types::l2::{self, TransferTx};
 use rollup_state_manager::types::matchengine::messages::{DepositMessage, UserMessage};
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
 use std::option::Option::None;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
@@ -90,17 +90,20 @@ fn bench_with_dummy_transfers() -> Result<()> {
     Ok(())
 }
 
+const BALANCE_MSG_TYPES: &[&str] = &["deposit", "withdraw"];
+
+// total time spent in `Processor::handle_msg` for msgs of these types
+fn handle_seconds(msg_types: &[&str]) -> f64 {
+    msg_types
+        .iter()
+        .map(|msg_type| metrics::MSG_HANDLE_SECONDS.with_label_values(&[msg_type]).get_sample_sum())
+        .sum()
+}
+
 //if we use nightly build, we are able to use bench test ...
 fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
     let filepath = "tests/global_state/testdata/data.txt";
-    let file = File::open(filepath)?;
-    let messages: Vec<WrappedMessage> = BufReader::new(file)
-        .lines()
-        .map(Result::unwrap)
-        .map(parse_msg)
-        .map(Result::unwrap)
-        //.filter(|msg| matches!(msg, WrappedMessage::BALANCE(_) | WrappedMessage::ORDER(_)))
-        .collect();
+    let messages: Vec<WrappedMessage> = msg_source::read_all(Box::new(msg_source::JsonLinesSource::file(filepath)?))?;
 
     println!("prepare bench: {} records", messages.len());
 
@@ -127,6 +130,8 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
     let mut manager = ManagerWrapper::new(state, *params::NTXS, None, *params::VERBOSE);
     let timing = Instant::now();
     let mut inner_timing = Instant::now();
+    // msg handling time is taken from the metrics, as sums since start
+    let (mut balance_before, mut trade_before) = (handle_seconds(BALANCE_MSG_TYPES), handle_seconds(&["trade"]));
 
     for i in 0..loop_num {
         let account_offset = i * account_num;
@@ -135,12 +140,12 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
                 WrappedMessage::DEPOSIT(deposit) => {
                     let mut deposit = deposit.clone();
                     deposit.user_id += account_offset;
-                    processor.handle_deposit_msg(&mut manager, deposit);
+                    processor.handle_msg(&mut manager, WrappedMessage::DEPOSIT(deposit));
                 }
                 WrappedMessage::ORDER(order) => {
                     let mut order = order.clone();
                     order.order.user += account_offset;
-                    processor.handle_order_msg(&mut manager, order);
+                    processor.handle_msg(&mut manager, WrappedMessage::ORDER(order));
                 }
                 WrappedMessage::TRADE(trade) => {
                     let mut trade = trade.clone();
@@ -156,31 +161,34 @@ fn bench_with_real_trades(_circuit_repo: &Path) -> Result<Vec<l2::L2Block>> {
                         o.user += account_offset;
                         o
                     });
-                    processor.handle_trade_msg(&mut manager, trade);
+                    processor.handle_msg(&mut manager, WrappedMessage::TRADE(trade));
                 }
                 WrappedMessage::TRANSFER(transfer) => {
                     let mut transfer = transfer.clone();
                     transfer.user_from += account_offset;
                     transfer.user_to += account_offset;
-                    processor.handle_transfer_msg(&mut manager, transfer);
+                    processor.handle_msg(&mut manager, WrappedMessage::TRANSFER(transfer));
                 }
                 WrappedMessage::USER(user) => {
                     let mut user = user.clone();
                     user.user_id += account_offset;
-                    processor.handle_user_msg(&mut manager, user);
+                    processor.handle_msg(&mut manager, WrappedMessage::USER(user));
                 }
                 WrappedMessage::WITHDRAW(withdraw) => {
                     let mut withdraw = withdraw.clone();
                     withdraw.user_id += account_offset;
-                    processor.handle_withdraw_msg(&mut manager, withdraw);
+                    processor.handle_msg(&mut manager, WrappedMessage::WITHDRAW(withdraw));
                 }
                 _ => unreachable!(),
             }
         }
 
         if i % 10 == 0 {
-            let total = inner_timing.elapsed().as_secs_f32();
-            let (balance_t, trade_t) = processor.take_bench();
+            let total = inner_timing.elapsed().as_secs_f64();
+            let (balance_after, trade_after) = (handle_seconds(BALANCE_MSG_TYPES), handle_seconds(&["trade"]));
+            let (balance_t, trade_t) = (balance_after - balance_before, trade_after - trade_before);
+            balance_before = balance_after;
+            trade_before = trade_after;
             println!(
                 "{}th 10 iters in {:.5}s: balance {:.3}%, trade {:.3}%",
                 i / 10,
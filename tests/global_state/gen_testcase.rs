@@ -7,6 +7,7 @@
 use anyhow::Result;
 use normpath::PathExt;
 use rollup_state_manager::params;
+use rollup_state_manager::shutdown::Shutdown;
 use rollup_state_manager::state::{GlobalState, ManagerWrapper};
 use rollup_state_manager::test_utils;
 use rollup_state_manager::test_utils::circuit::{write_test_case, CircuitTestCase, CircuitTestData};
@@ -17,7 +18,7 @@ use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
 use rollup_state_manager::config::Settings;
-use rollup_state_manager::msg::{msg_loader, msg_processor};
+use rollup_state_manager::msg::{msg_processor, msg_source};
 use std::option::Option::None;
 
 fn replay_msgs(
@@ -60,6 +61,9 @@ fn replay_msgs(
                 WrappedMessage::WITHDRAW(withdraw) => {
                     processor.handle_withdraw_msg(&mut manager, withdraw);
                 }
+                WrappedMessage::FULLEXIT(full_exit) => {
+                    processor.handle_full_exit_msg(&mut manager, full_exit);
+                }
                 _ => {
                     //other msg is omitted
                 }
@@ -99,7 +103,8 @@ pub fn run(src: &str) -> Result<()> {
     let (msg_sender, msg_receiver) = crossbeam_channel::unbounded();
     let (blk_sender, blk_receiver) = crossbeam_channel::unbounded();
 
-    let loader_thread = msg_loader::load_msgs_from_file(filepath.to_str().unwrap(), msg_sender);
+    let source = msg_source::JsonLinesSource::file(filepath.to_str().unwrap())?;
+    let loader_thread = Some(msg_source::spawn(Box::new(source), msg_sender, Shutdown::default()));
 
     let replay_thread = replay_msgs(msg_receiver, blk_sender);
 